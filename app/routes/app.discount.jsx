@@ -92,6 +92,18 @@ export const action = async ({ request }) => {
       ownerId: appInstallationId,
     };
 
+    // Shopify Functions cannot read app-installation metafields, so the tag-discounts
+    // function reads a copy of both lists from the shop's $app:discounts namespace.
+    const shopResponse = await admin.graphql(`
+      query {
+        shop {
+          id
+        }
+      }
+    `);
+    const shopData = await shopResponse.json();
+    const shopId = shopData.data.shop.id;
+
     const mutation = `
       mutation CreateAppDataMetafield {
         metafieldsSet(metafields: [
@@ -108,6 +120,20 @@ export const action = async ({ request }) => {
             type: "${metafieldsSetInputScheduleDiscounts.type}",
             value: ${JSON.stringify(metafieldsSetInputScheduleDiscounts.value)},
             ownerId: "${metafieldsSetInputScheduleDiscounts.ownerId}"
+          },
+          {
+            namespace: "$app:discounts",
+            key: "${metafieldsSetInputTagDiscounts.key}",
+            type: "${metafieldsSetInputTagDiscounts.type}",
+            value: ${JSON.stringify(metafieldsSetInputTagDiscounts.value)},
+            ownerId: "${shopId}"
+          },
+          {
+            namespace: "$app:discounts",
+            key: "${metafieldsSetInputScheduleDiscounts.key}",
+            type: "${metafieldsSetInputScheduleDiscounts.type}",
+            value: ${JSON.stringify(metafieldsSetInputScheduleDiscounts.value)},
+            ownerId: "${shopId}"
           }
         ]) {
           metafields {
@@ -87,6 +87,18 @@ export const action = async ({ request }) => {
       ownerId: appInstallationId,
     };
 
+    // Shopify Functions cannot read app-installation metafields, so the free-gifts
+    // function reads a copy of the tiers and gifts from the shop's $app:gifts namespace.
+    const shopResponse = await admin.graphql(`
+      query {
+        shop {
+          id
+        }
+      }
+    `);
+    const shopData = await shopResponse.json();
+    const shopId = shopData.data.shop.id;
+
     const mutation = `
       mutation CreateAppDataMetafield {
         metafieldsSet(metafields: [
@@ -103,6 +115,20 @@ export const action = async ({ request }) => {
             type: "${metafieldsSetInputGifts.type}",
             value: ${JSON.stringify(metafieldsSetInputGifts.value)},
             ownerId: "${metafieldsSetInputGifts.ownerId}"
+          },
+          {
+            namespace: "$app:gifts",
+            key: "${metafieldsSetInputTiers.key}",
+            type: "${metafieldsSetInputTiers.type}",
+            value: ${JSON.stringify(metafieldsSetInputTiers.value)},
+            ownerId: "${shopId}"
+          },
+          {
+            namespace: "$app:gifts",
+            key: "${metafieldsSetInputGifts.key}",
+            type: "${metafieldsSetInputGifts.type}",
+            value: ${JSON.stringify(metafieldsSetInputGifts.value)},
+            ownerId: "${shopId}"
           }
         ]) {
           metafields {
@@ -94,6 +94,134 @@ const shopify = shopifyApp({
           }
         }
       );
+      //start metafield definition for coupon discount function
+      const couponDiscountDefinition = {
+        access: {
+          admin: "MERCHANT_READ_WRITE"
+        },
+        key: "config",
+        name: "Coupon Discount Configuration",
+        namespace: "$app:coupon_discounts",
+        ownerType: "DISCOUNT",
+        type: "json",
+      };
+
+      await admin.graphql(
+        `#graphql
+        mutation CreateCouponMetafieldDefinition($definition: MetafieldDefinitionInput!) {
+          metafieldDefinitionCreate(definition: $definition) {
+            createdDefinition {
+              id
+            }
+            userErrors {
+              field
+              message
+              code
+            }
+          }
+        }`,
+        {
+          variables: {
+            definition: couponDiscountDefinition,
+          }
+        }
+      );
+      //start metafield definition for tag discount function
+      const tagDiscountDefinition = {
+        access: {
+          admin: "MERCHANT_READ_WRITE"
+        },
+        key: "config",
+        name: "Tag Discount Configuration",
+        namespace: "$app:tag_discounts",
+        ownerType: "DISCOUNT",
+        type: "json",
+      };
+
+      await admin.graphql(
+        `#graphql
+        mutation CreateTagDiscountMetafieldDefinition($definition: MetafieldDefinitionInput!) {
+          metafieldDefinitionCreate(definition: $definition) {
+            createdDefinition {
+              id
+            }
+            userErrors {
+              field
+              message
+              code
+            }
+          }
+        }`,
+        {
+          variables: {
+            definition: tagDiscountDefinition,
+          }
+        }
+      );
+      //start metafield definition for free gifts function
+      const freeGiftsDefinition = {
+        access: {
+          admin: "MERCHANT_READ_WRITE"
+        },
+        key: "config",
+        name: "Free Gifts Configuration",
+        namespace: "$app:free_gifts",
+        ownerType: "DISCOUNT",
+        type: "json",
+      };
+
+      await admin.graphql(
+        `#graphql
+        mutation CreateFreeGiftsMetafieldDefinition($definition: MetafieldDefinitionInput!) {
+          metafieldDefinitionCreate(definition: $definition) {
+            createdDefinition {
+              id
+            }
+            userErrors {
+              field
+              message
+              code
+            }
+          }
+        }`,
+        {
+          variables: {
+            definition: freeGiftsDefinition,
+          }
+        }
+      );
+      //start metafield definition for payment customization function
+      const paymentCustomizationDefinition = {
+        access: {
+          admin: "MERCHANT_READ_WRITE"
+        },
+        key: "config",
+        name: "Payment Customization Configuration",
+        namespace: "$app:payment_customization",
+        ownerType: "PAYMENT_CUSTOMIZATION",
+        type: "json",
+      };
+
+      await admin.graphql(
+        `#graphql
+        mutation CreatePaymentCustomizationMetafieldDefinition($definition: MetafieldDefinitionInput!) {
+          metafieldDefinitionCreate(definition: $definition) {
+            createdDefinition {
+              id
+            }
+            userErrors {
+              field
+              message
+              code
+            }
+          }
+        }`,
+        {
+          variables: {
+            definition: paymentCustomizationDefinition,
+          }
+        }
+      );
     },
   },
   future: {
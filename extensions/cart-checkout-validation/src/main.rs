@@ -0,0 +1,22 @@
+//! Cart checkout validation: blocks checkout when a collection or variant is
+//! outside its quantity limits or the subtotal is outside the order value bounds.
+
+pub mod run;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    use discount_common::cli::{self, Usage};
+
+    let usage = Usage {
+        name: "cart-checkout-validation",
+        metafield: "$app:checkout_validation.config",
+        explains: "each limit passed or failed",
+    };
+    cli::main(&usage, run::evaluate, run::validate_config);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    eprintln!("Please invoke a named export.");
+    std::process::exit(1);
+}
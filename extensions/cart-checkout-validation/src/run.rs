@@ -0,0 +1,590 @@
+use std::collections::BTreeMap;
+
+use shopify_function::prelude::*;
+use shopify_function::Result;
+use serde::Deserialize;
+use discount_common::collections::{self, CollectionMembership};
+use discount_common::config::{self, ConfigError};
+use discount_common::explain::Explanation;
+use discount_common::money::{self, Money, Rounding};
+use self::run::{input, output};
+
+/*
+ ----------------------------CART CHECKOUT VALIDATION-----------------------------
+            Read from the validation metafield `$app:checkout_validation.config`,
+            which app.addCollections.jsx writes:
+            collectionIds = every collection of `mapping`. Passed to the input query
+                            as `$collectionIds`.
+            mapping = [{ collection, qty, minQty }]
+                    The cart quantity of a collection is summed over all of its lines.
+                    `qty` is the most units the cart may hold, `minQty` the fewest
+                    once it holds any. Both are whole-number strings, like the
+                    admin's text fields, and an empty or missing one is no limit.
+            variantLimits = [{ variant, qty, minQty }]
+                    The same limits for one variant, summed over its lines.
+            minOrderValue, maxOrderValue = bounds on the cart subtotal, as decimal
+                    strings in the shop currency.
+            shopCurrency = currency the order values are declared in, when its number
+                    of decimals differs from the buyer's currency.
+            messages = { <language>: { <message>: template } }
+                    Replaces the built-in English error texts for the buyer's language,
+                    e.g. "fr" or "pt_br". Messages are collectionMax, collectionMin,
+                    variantMax, variantMin, orderMin and orderMax; templates may use
+                    {limit}, {quantity} and {amount}.
+            Quantity errors target the first cart line of the collection or variant,
+            order value errors target the cart. Without the metafield nothing is
+            checked, and a configuration that does not parse blocks nothing. A
+            malformed limit is skipped and logged while the others are still
+            checked; `validate-config` reports it.
+---------------------------------------------------------------------------------
+*/
+#[derive(Deserialize, Default, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Configuration {
+    #[serde(default)]
+    collection_ids: Vec<String>,
+    #[serde(default)]
+    mapping: Vec<CollectionLimit>,
+    #[serde(default)]
+    variant_limits: Vec<VariantLimit>,
+    #[serde(default)]
+    min_order_value: Option<String>,
+    #[serde(default)]
+    max_order_value: Option<String>,
+    #[serde(default)]
+    shop_currency: Option<String>,
+    #[serde(default)]
+    messages: BTreeMap<String, BTreeMap<Message, String>>,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+struct CollectionLimit {
+    collection: String,
+    #[serde(flatten)]
+    limit: QuantityLimit,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+struct VariantLimit {
+    variant: String,
+    #[serde(flatten)]
+    limit: QuantityLimit,
+}
+
+#[derive(Deserialize, Default, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct QuantityLimit {
+    // The maximum, named after the admin's original `qty` field.
+    #[serde(default)]
+    qty: Option<String>,
+    #[serde(default)]
+    min_qty: Option<String>,
+}
+
+/// The error texts a merchant can translate.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "camelCase")]
+enum Message {
+    CollectionMax,
+    CollectionMin,
+    VariantMax,
+    VariantMin,
+    OrderMin,
+    OrderMax,
+}
+
+impl Message {
+    fn default_template(self) -> &'static str {
+        match self {
+            Message::CollectionMax => "Cannot order more than {limit} items from this collection (you have {quantity})",
+            Message::CollectionMin => "Order at least {limit} items from this collection (you have {quantity})",
+            Message::VariantMax => "Cannot order more than {limit} of the product",
+            Message::VariantMin => "Order at least {limit} of the product",
+            Message::OrderMin => "The order subtotal must be at least {amount}",
+            Message::OrderMax => "The order subtotal cannot be more than {amount}",
+        }
+    }
+}
+
+impl CollectionLimit {
+    // The (minimum, maximum) units. The collection has to be queried to be matched.
+    fn bounds(&self, field: &str, collection_ids: &[String]) -> std::result::Result<(Option<i64>, Option<i64>), ConfigError> {
+        if !collection_ids.contains(&self.collection) {
+            return Err(ConfigError::invalid(
+                format!("{}.collection", field),
+                format!("{} is missing from collectionIds, so its membership is never queried", self.collection),
+            ));
+        }
+        self.limit.bounds(field)
+    }
+}
+
+impl VariantLimit {
+    fn bounds(&self, field: &str) -> std::result::Result<(Option<i64>, Option<i64>), ConfigError> {
+        if self.variant.is_empty() {
+            return Err(ConfigError::invalid(format!("{}.variant", field), "variant id must not be empty"));
+        }
+        self.limit.bounds(field)
+    }
+}
+
+impl QuantityLimit {
+    // The (minimum, maximum) units.
+    fn bounds(&self, field: &str) -> std::result::Result<(Option<i64>, Option<i64>), ConfigError> {
+        let min = parse_quantity(self.min_qty.as_deref(), &format!("{}.minQty", field))?;
+        let max = parse_quantity(self.qty.as_deref(), &format!("{}.qty", field))?;
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                return Err(ConfigError::invalid(format!("{}.minQty", field), format!("{} is more than qty {}", min, max)));
+            }
+        }
+        Ok((min, max))
+    }
+}
+
+fn parse_quantity(value: Option<&str>, field: &str) -> std::result::Result<Option<i64>, ConfigError> {
+    match value.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => match value.parse::<i64>() {
+            Ok(quantity) if quantity >= 0 => Ok(Some(quantity)),
+            _ => Err(ConfigError::invalid(field, format!("{} is not a whole number of units", value))),
+        },
+    }
+}
+
+fn parse_amount(value: Option<&str>, field: &str) -> std::result::Result<Option<f64>, ConfigError> {
+    match value.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => match value.parse::<f64>() {
+            Ok(amount) if amount.is_finite() && amount >= 0.0 => Ok(Some(amount)),
+            _ => Err(ConfigError::invalid(field, format!("{} is not a non-negative amount", value))),
+        },
+    }
+}
+
+impl Configuration {
+    fn from_str(value: &str) -> std::result::Result<Self, ConfigError> {
+        let config: Configuration = config::parse(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Reject values that cannot be checked at all. Each quantity limit is checked on its
+    // own by `limit_errors`.
+    fn validate(&self) -> std::result::Result<(), ConfigError> {
+        let (min, max) = self.order_values()?;
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                return Err(ConfigError::invalid("minOrderValue", format!("{} is more than maxOrderValue {}", min, max)));
+            }
+        }
+        if let Some(currency) = &self.shop_currency {
+            money::validate_currency(currency).map_err(|reason| ConfigError::invalid("shopCurrency", reason))?;
+        }
+        for (language, messages) in &self.messages {
+            if let Some((message, _)) = messages.iter().find(|(_, template)| template.trim().is_empty()) {
+                return Err(ConfigError::invalid(format!("messages.{}.{:?}", language, message), "template must not be empty"));
+            }
+        }
+        Ok(())
+    }
+
+    // The quantity limits the function skips.
+    fn limit_errors(&self) -> Vec<ConfigError> {
+        let collection_limits = self
+            .mapping
+            .iter()
+            .enumerate()
+            .map(|(index, limit)| limit.bounds(&format!("mapping[{}]", index), &self.collection_ids));
+        let variant_limits = self
+            .variant_limits
+            .iter()
+            .enumerate()
+            .map(|(index, limit)| limit.bounds(&format!("variantLimits[{}]", index)));
+        collection_limits.chain(variant_limits).filter_map(|bounds| bounds.err()).collect()
+    }
+
+    fn order_values(&self) -> std::result::Result<(Option<f64>, Option<f64>), ConfigError> {
+        Ok((
+            parse_amount(self.min_order_value.as_deref(), "minOrderValue")?,
+            parse_amount(self.max_order_value.as_deref(), "maxOrderValue")?,
+        ))
+    }
+
+    // The merchant's template for the buyer's language, then for its base language
+    // ("pt" for "PT_BR"), then the built-in English text.
+    fn template(&self, language: &str, message: Message) -> &str {
+        let language = language.to_ascii_lowercase().replace('-', "_");
+        let base = language.split('_').next().unwrap_or_default();
+        [language.as_str(), base]
+            .iter()
+            .find_map(|wanted| {
+                self.messages
+                    .iter()
+                    .find(|(key, _)| key.to_ascii_lowercase().replace('-', "_") == *wanted)
+                    .and_then(|(_, messages)| messages.get(&message))
+            })
+            .map(String::as_str)
+            .unwrap_or(message.default_template())
+    }
+
+    fn error(&self, language: &str, message: Message, values: &[(&str, String)], target: String) -> output::FunctionError {
+        let localized_message = values
+            .iter()
+            .fold(self.template(language, message).to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{}}}", name), value)
+            });
+        output::FunctionError {
+            localized_message,
+            target,
+        }
+    }
+}
+
+impl CollectionMembership for input::InputCartLinesMerchandiseOnProductVariantProductInCollections {
+    fn collection_id(&self) -> &str {
+        &self.collection_id
+    }
+
+    fn is_member(&self) -> bool {
+        self.is_member
+    }
+}
+
+// A quantity limit with the cart lines it applies to.
+struct LimitCheck<'a> {
+    id: &'a str,
+    bounds: std::result::Result<(Option<i64>, Option<i64>), ConfigError>,
+    indices: Vec<usize>,
+    quantity: i64,
+    // The messages for too few and too many units.
+    messages: (Message, Message),
+}
+
+fn line_target(index: usize) -> String {
+    format!("$.cart.lines[{}].quantity", index)
+}
+
+// The indices of the lines matching `matches`, and their summed quantity.
+fn lines_where(
+    lines: &[input::InputCartLines],
+    matches: impl Fn(&input::InputCartLinesMerchandiseOnProductVariant) -> bool,
+) -> (Vec<usize>, i64) {
+    let mut indices = vec![];
+    let mut quantity = 0i64;
+    for (index, line) in lines.iter().enumerate() {
+        if let input::InputCartLinesMerchandise::ProductVariant(variant) = &line.merchandise {
+            if matches(variant) {
+                indices.push(index);
+                quantity = quantity.saturating_add(line.quantity);
+            }
+        }
+    }
+    (indices, quantity)
+}
+
+/// Check a `$app:checkout_validation.config` metafield value, including the limits the
+/// function would skip.
+pub(crate) fn validate_config(value: &str) -> std::result::Result<(), String> {
+    let config = Configuration::from_str(value).map_err(|err| err.to_string())?;
+    match config.limit_errors().into_iter().next() {
+        Some(err) => Err(err.to_string()),
+        None => Ok(()),
+    }
+}
+
+#[shopify_function_target(query_path = "src/run.graphql", schema_path = "schema.graphql")]
+fn run(input: input::ResponseData) -> Result<output::FunctionRunResult> {
+    evaluate(input, &mut Explanation::default())
+}
+
+/// The body of `run`. Each decision is recorded in `explanation` for the `explain` command.
+pub(crate) fn evaluate(input: input::ResponseData, explanation: &mut Explanation) -> Result<output::FunctionRunResult> {
+    let no_errors = output::FunctionRunResult { errors: vec![] };
+
+    // 1. Parse the configuration. Checkout is never blocked by a missing or broken one.
+    let config = match input.validation.metafield {
+        Some(input::InputValidationMetafield { value }) => match Configuration::from_str(&value) {
+            Ok(config) => config,
+            Err(err) => {
+                err.log("no_validation");
+                explanation.note(|| format!("Configuration rejected, nothing checked: {}", err));
+                return Ok(no_errors);
+            }
+        },
+        None => {
+            explanation.note(|| "No configuration metafield, nothing checked.".to_string());
+            return Ok(no_errors);
+        }
+    };
+    let language = input.localization.language.iso_code.as_str();
+    let lines = &input.cart.lines;
+    let mut errors = vec![];
+
+    // 2. Quantity limits, summed over the lines of each collection and each variant.
+    let collection_limits = config.mapping.iter().enumerate().map(|(index, limit)| {
+        let (indices, quantity) = lines_where(lines, |variant| {
+            collections::is_member_of(&variant.product.in_collections, &limit.collection)
+        });
+        LimitCheck {
+            id: &limit.collection,
+            bounds: limit.bounds(&format!("mapping[{}]", index), &config.collection_ids),
+            indices,
+            quantity,
+            messages: (Message::CollectionMin, Message::CollectionMax),
+        }
+    });
+    let variant_limits = config.variant_limits.iter().enumerate().map(|(index, limit)| {
+        let (indices, quantity) = lines_where(lines, |variant| variant.id == limit.variant);
+        LimitCheck {
+            id: &limit.variant,
+            bounds: limit.bounds(&format!("variantLimits[{}]", index)),
+            indices,
+            quantity,
+            messages: (Message::VariantMin, Message::VariantMax),
+        }
+    });
+    for LimitCheck { id, bounds, indices, quantity, messages: (below, above) } in collection_limits.chain(variant_limits) {
+        // One malformed limit never turns off the others
+        let (min, max) = match bounds {
+            Ok(bounds) => bounds,
+            Err(err) => {
+                err.log("skip_entry");
+                explanation.note(|| format!("Entry skipped: {}", err));
+                continue;
+            }
+        };
+        let Some(&first) = indices.first() else {
+            explanation.note(|| format!("{} is not in the cart.", id));
+            continue;
+        };
+        let (message, bound) = match (min, max) {
+            (Some(min), _) if quantity < min => (below, min),
+            (_, Some(max)) if quantity > max => (above, max),
+            _ => {
+                explanation.note(|| format!("{} has {} units, within its limits.", id, quantity));
+                continue;
+            }
+        };
+        explanation.note(|| format!("{} has {} units, outside its limit of {}.", id, quantity, bound));
+        let values = [("limit", bound.to_string()), ("quantity", quantity.to_string())];
+        errors.push(config.error(language, message, &values, line_target(first)));
+    }
+
+    // 3. Order value bounds on the subtotal, converted from the shop currency.
+    let subtotal = &input.cart.cost.subtotal_amount;
+    let currency = subtotal.currency_code.as_str();
+    let shop_currency = config.shop_currency.as_deref().unwrap_or(currency);
+    let rate = input.presentment_currency_rate.0;
+    let (min, max) = config.order_values().unwrap_or_default();
+    let bound = |value: Option<f64>, rounding| {
+        value.and_then(|value| money::to_presentment(value, shop_currency, rate, currency, rounding))
+    };
+    if let Some(subtotal) = Money::from_decimal(subtotal.amount.0, currency, Rounding::HalfUp) {
+        explanation.note(|| format!("Order subtotal: {}.", subtotal));
+        if let Some(min) = bound(min, Rounding::Up).filter(|min| subtotal < *min) {
+            explanation.note(|| format!("The subtotal is below the minimum of {}.", min));
+            errors.push(config.error(language, Message::OrderMin, &[("amount", min.to_string())], "$.cart".to_string()));
+        }
+        if let Some(max) = bound(max, Rounding::Down).filter(|max| subtotal > *max) {
+            explanation.note(|| format!("The subtotal is above the maximum of {}.", max));
+            errors.push(config.error(language, Message::OrderMax, &[("amount", max.to_string())], "$.cart".to_string()));
+        }
+    }
+
+    Ok(output::FunctionRunResult { errors })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shopify_function::{run_function_with_input, Result};
+    use discount_common::cart::test_support;
+    use crate::run::run::output;
+
+    const SHIRTS: &str = "gid://shopify/Collection/1";
+    // The shape app.addCollections.jsx saves
+    const CONFIG: &str = r#"{
+        "collectionIds": ["gid://shopify/Collection/1"],
+        "mapping": [{"collection": "gid://shopify/Collection/1", "qty": "3"}]
+    }"#;
+
+    // A line of `quantity` units of variant `variant`, in the shirts collection or not.
+    fn line(variant: u32, quantity: i64, shirt: bool) -> serde_json::Value {
+        serde_json::json!({
+            "quantity": quantity,
+            "merchandise": {
+                "__typename": "ProductVariant",
+                "id": format!("gid://shopify/ProductVariant/{}", variant),
+                "sku": null,
+                "product": {
+                    "inCollections": test_support::in_collection(SHIRTS, shirt)
+                }
+            }
+        })
+    }
+
+    fn input_with(config: Option<&str>, language: &str, subtotal: &str, lines: Vec<serde_json::Value>) -> String {
+        let fields = serde_json::json!({
+            "localization": { "language": { "isoCode": language } },
+            "cart": {
+                "cost": { "subtotalAmount": test_support::money(subtotal, "USD") },
+                "lines": lines
+            }
+        });
+        test_support::input("validation", config, fields)
+    }
+
+    fn error(message: &str, target: &str) -> output::FunctionError {
+        output::FunctionError {
+            localized_message: message.to_string(),
+            target: target.to_string(),
+        }
+    }
+
+    fn errors(input: &str) -> Result<Vec<output::FunctionError>> {
+        Ok(run_function_with_input(run, input)?.errors)
+    }
+
+    #[test]
+    fn test_collection_quantity_is_summed_across_lines() -> Result<()> {
+        // Two lines of two shirts each: fine line by line, too many together
+        let lines = vec![line(1, 1, false), line(2, 2, true), line(3, 2, true)];
+        assert_eq!(
+            errors(&input_with(Some(CONFIG), "EN", "50.00", lines))?,
+            vec![error("Cannot order more than 3 items from this collection (you have 4)", "$.cart.lines[1].quantity")]
+        );
+        let lines = vec![line(2, 2, true), line(3, 1, true), line(4, 5, false)];
+        assert_eq!(errors(&input_with(Some(CONFIG), "EN", "50.00", lines))?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collection_minimum_applies_once_the_collection_is_in_the_cart() -> Result<()> {
+        let config = r#"{
+            "collectionIds": ["gid://shopify/Collection/1"],
+            "mapping": [{"collection": "gid://shopify/Collection/1", "qty": "", "minQty": "2"}]
+        }"#;
+        assert_eq!(
+            errors(&input_with(Some(config), "EN", "50.00", vec![line(1, 1, true)]))?,
+            vec![error("Order at least 2 items from this collection (you have 1)", "$.cart.lines[0].quantity")]
+        );
+        assert_eq!(errors(&input_with(Some(config), "EN", "50.00", vec![line(1, 1, false)]))?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_variant_limits() -> Result<()> {
+        let config = r#"{"variantLimits": [
+            {"variant": "gid://shopify/ProductVariant/1", "qty": "2"},
+            {"variant": "gid://shopify/ProductVariant/2", "minQty": "3"}
+        ]}"#;
+        let lines = vec![line(2, 1, false), line(1, 2, false), line(1, 1, false)];
+        assert_eq!(
+            errors(&input_with(Some(config), "EN", "50.00", lines))?,
+            vec![
+                error("Cannot order more than 2 of the product", "$.cart.lines[1].quantity"),
+                error("Order at least 3 of the product", "$.cart.lines[0].quantity"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_order_value_bounds() -> Result<()> {
+        let config = r#"{"minOrderValue": "25", "maxOrderValue": "500.00"}"#;
+        let lines = vec![line(1, 1, false)];
+        assert_eq!(
+            errors(&input_with(Some(config), "EN", "24.99", lines.clone()))?,
+            vec![error("The order subtotal must be at least 25.00 USD", "$.cart")]
+        );
+        assert_eq!(
+            errors(&input_with(Some(config), "EN", "500.01", lines.clone()))?,
+            vec![error("The order subtotal cannot be more than 500.00 USD", "$.cart")]
+        );
+        assert_eq!(errors(&input_with(Some(config), "EN", "500.00", lines))?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_messages_follow_the_buyer_language() -> Result<()> {
+        let config = r#"{
+            "collectionIds": ["gid://shopify/Collection/1"],
+            "mapping": [{"collection": "gid://shopify/Collection/1", "qty": "3"}],
+            "messages": {"pt": {"collectionMax": "Máximo de {limit} itens desta coleção"}}
+        }"#;
+        let lines = || vec![line(1, 4, true)];
+        assert_eq!(
+            errors(&input_with(Some(config), "PT_BR", "50.00", lines()))?,
+            vec![error("Máximo de 3 itens desta coleção", "$.cart.lines[0].quantity")]
+        );
+        assert_eq!(
+            errors(&input_with(Some(config), "FR", "50.00", lines()))?,
+            vec![error("Cannot order more than 3 items from this collection (you have 4)", "$.cart.lines[0].quantity")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_or_broken_config_blocks_nothing() -> Result<()> {
+        let lines = || vec![line(1, 99, true)];
+        assert_eq!(errors(&input_with(None, "EN", "50.00", lines()))?, vec![]);
+        assert_eq!(errors(&input_with(Some("not json"), "EN", "50.00", lines()))?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_limit_is_skipped_alone() -> Result<()> {
+        let config = r#"{
+            "collectionIds": ["gid://shopify/Collection/1"],
+            "mapping": [{"collection": "gid://shopify/Collection/1", "qty": "3"}],
+            "variantLimits": [
+                {"variant": "gid://shopify/ProductVariant/1", "qty": "3.5"},
+                {"variant": "gid://shopify/ProductVariant/2", "qty": "abc"},
+                {"variant": "gid://shopify/ProductVariant/3", "qty": "1", "minQty": "2"},
+                {"variant": "gid://shopify/ProductVariant/4", "qty": "1"}
+            ]
+        }"#;
+        let lines = vec![line(1, 9, false), line(2, 9, false), line(3, 9, false), line(4, 2, true), line(5, 2, true)];
+        assert_eq!(
+            errors(&input_with(Some(config), "EN", "50.00", lines.clone()))?,
+            vec![
+                error("Cannot order more than 3 items from this collection (you have 4)", "$.cart.lines[3].quantity"),
+                error("Cannot order more than 1 of the product", "$.cart.lines[3].quantity"),
+            ]
+        );
+
+        let mut explanation = Explanation::enabled();
+        evaluate(serde_json::from_str(&input_with(Some(config), "EN", "50.00", lines)).unwrap(), &mut explanation)?;
+        let skipped = explanation.notes().iter().filter(|note| note.starts_with("Entry skipped")).count();
+        assert_eq!(skipped, 3);
+        assert!(validate_config(config).unwrap_err().contains("variantLimits[0].qty"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert_eq!(validate_config(CONFIG), Ok(()));
+        assert!(validate_config(r#"{"mapping": [{"collection": "gid://shopify/Collection/1", "qty": "3"}]}"#)
+            .unwrap_err()
+            .contains("collectionIds"));
+        assert!(validate_config(r#"{"variantLimits": [{"variant": "gid://shopify/ProductVariant/1", "qty": "two"}]}"#)
+            .unwrap_err()
+            .contains("variantLimits[0].qty"));
+        assert!(validate_config(r#"{"variantLimits": [{"variant": "gid://shopify/ProductVariant/1", "qty": "1", "minQty": "2"}]}"#)
+            .unwrap_err()
+            .contains("minQty"));
+        assert!(validate_config(r#"{"minOrderValue": "100", "maxOrderValue": "50"}"#).is_err());
+        assert!(validate_config(r#"{"messages": {"fr": {"tooMany": "Trop"}}}"#).is_err());
+    }
+
+    #[test]
+    fn test_explain_validation() {
+        let mut explanation = Explanation::enabled();
+        let input = serde_json::from_str(&input_with(Some(CONFIG), "EN", "50.00", vec![line(1, 4, true)])).unwrap();
+        evaluate(input, &mut explanation).unwrap();
+        let notes = explanation.notes();
+        assert!(notes.contains(&"gid://shopify/Collection/1 has 4 units, outside its limit of 3.".to_string()));
+        assert!(notes.contains(&"Order subtotal: 50.00 USD.".to_string()));
+    }
+}
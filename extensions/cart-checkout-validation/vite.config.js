@@ -1 +0,0 @@
-// Prevents inheritance from parent Remix project
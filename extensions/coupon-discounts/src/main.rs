@@ -1,7 +1,22 @@
-use std::process;
+//! Coupon discounts: applies the configured collection rules and buy X get Y offers
+//! once the cart value reaches their thresholds.
+
 pub mod run;
 
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    use discount_common::cli::{self, Usage};
+
+    let usage = Usage {
+        name: "coupon-discounts",
+        metafield: "$app:coupon_discounts.config",
+        explains: "each rule and line was chosen or skipped",
+    };
+    cli::main(&usage, run::evaluate, run::validate_config);
+}
+
+#[cfg(target_arch = "wasm32")]
 fn main() {
     eprintln!("Please invoke a named export.");
-    process::exit(1);
+    std::process::exit(1);
 }
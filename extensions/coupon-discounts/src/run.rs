@@ -1,71 +1,738 @@
 use shopify_function::prelude::*;
 use shopify_function::Result;
+use serde::Deserialize;
+use discount_common::cart::{self, CartLine, ThresholdInclusion};
+use discount_common::codes::DiscountCode;
+use discount_common::collections::{self, CollectionMembership};
+use discount_common::config::{self, ConfigError};
+use discount_common::explain::Explanation;
+use discount_common::money::{self, Money, Rounding};
+use discount_common::schedule::{Schedule, ShopTime};
+use discount_common::strategy::ApplicationStrategy;
+use self::run::{input, output};
 
 /*
  --------------------------CONFIGURATION FOR THE DISCOUNT-------------------------
-            Target collection = collection that the discount will be applied to.
-            Discount percentage = percentage of the discount that will be applied eligible cart items.
-            Cart value threshold = minimum value of the cart for the discount to be applied.
+            Read from the discount node metafield `$app:coupon_discounts.config`:
+            collectionIds = every collection used by the rules below. This list is
+                            passed to the input query as `$collectionIds`.
+            rules = one entry per discount, each with:
+                collectionIds = collections that the rule's discount will be applied to.
+                kind = percentage, fixed_amount_per_unit or fixed_amount (across targets).
+                value = percentage or amount that will be applied to eligible cart items.
+                threshold = minimum value of the cart for the rule to be applied.
+                message = optional message shown to the buyer.
+                maxDiscountAmount = optional cap on what the rule takes off the order. Above
+                                it the rule turns into fixed amounts per cart line that
+                                add up to the cap exactly.
+                startsAt/endsAt, daysOfWeek, hours = optional activation window, evaluated
+                                against the shop's local time (see schedule.rs).
+                discountCodes = reserved for [{ code, percentage }]. Entered codes are not
+                                part of this API version's input (see codes.rs), so a
+                                rule with codes is rejected.
+            shopCurrency = currency the fixed amounts, thresholds and caps are declared in.
+                                They are converted to the cart currency with the
+                                presentment rate. Only needed when the shop currency has
+                                a different number of decimals than the buyer's currency.
+            applicationStrategy = optional first, maximum or all. Defaults to all when
+                                several rules apply. For first and maximum the discounts
+                                are ordered by the money they save, biggest first.
+            buyXGetY = optional offers, each with:
+                buy = { collectionIds, quantity } units that have to be in the cart.
+                get = { collectionIds, quantity } units that are discounted. Without
+                      collectionIds the reward comes from the buy collections.
+                percentage = discount on the reward units, up to 100.
+                repeat = apply once for every complete set instead of once per order.
+                The cheapest eligible units are rewarded, the earlier cart line first on
+                a tie. Reward lines are never shared with the rules above.
+            thresholdInclusion = optional lines left out of the cart value the thresholds
+                                are compared against: excludeGiftCards, excludeSubscriptions,
+                                excludeFreeLines and excludeCustomLines, all false by default.
+            A configuration without `rules` or `buyXGetY` is read as a single rule.
+            Without the metafield the rule that used to be built in applies: 15% off
+            LEGACY_COLLECTION from a cart value of 150, which is also the default of
+            `$collectionIds` in run.graphql.
+
+            Overlapping rules: rules are evaluated in the order they are listed. A cart
+            line that falls into several qualifying rules is only targeted by the first
+            of them, so list rules by priority.
 ---------------------------------------------------------------------------------
 */
-const TARGET_COLLECTION: &str = "gid://shopify/Collection/496241049921";
-const DISCOUNT_PERCENTAGE: f64 = 15.0;
-const CART_VALUE_THRESHOLD: f64 = 150.0;
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Configuration {
+    collection_ids: Vec<String>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+    #[serde(default)]
+    application_strategy: Option<ApplicationStrategy>,
+    #[serde(default, rename = "buyXGetY")]
+    buy_x_get_y: Vec<BuyXGetY>,
+    #[serde(default)]
+    threshold_inclusion: ThresholdInclusion,
+    #[serde(default)]
+    shop_currency: Option<String>,
+}
+
+// Buy `buy.quantity` units from the buy collections, get `get.quantity` units from the
+// get collections (the buy collections when none are given) at `percentage` off.
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct BuyXGetY {
+    buy: UnitSelection,
+    get: UnitSelection,
+    percentage: f64,
+    // Apply once for every complete set of units instead of once per order.
+    #[serde(default)]
+    repeat: bool,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(flatten)]
+    schedule: Schedule,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UnitSelection {
+    #[serde(default)]
+    collection_ids: Vec<String>,
+    quantity: i64,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Rule {
+    collection_ids: Vec<String>,
+    #[serde(default)]
+    kind: DiscountKind,
+    value: f64,
+    threshold: f64,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    max_discount_amount: Option<f64>,
+    #[serde(default)]
+    discount_codes: Vec<DiscountCode>,
+    #[serde(flatten)]
+    schedule: Schedule,
+}
+
+const LEGACY_COLLECTION: &str = "gid://shopify/Collection/496241049921";
+
+impl Configuration {
+    // The rule that was built in before the metafield, applied without one.
+    fn legacy() -> Self {
+        Configuration {
+            collection_ids: vec![LEGACY_COLLECTION.to_string()],
+            rules: vec![Rule {
+                collection_ids: vec![LEGACY_COLLECTION.to_string()],
+                kind: DiscountKind::Percentage,
+                value: 15.0,
+                threshold: 150.0,
+                message: None,
+                max_discount_amount: None,
+                discount_codes: vec![],
+                schedule: Schedule::default(),
+            }],
+            application_strategy: None,
+            buy_x_get_y: vec![],
+            threshold_inclusion: ThresholdInclusion::default(),
+            shop_currency: None,
+        }
+    }
+
+    fn from_str(value: &str) -> std::result::Result<Self, ConfigError> {
+        let parse_error = |err: serde_json::Error| ConfigError::Parse(err.to_string());
+        let json: serde_json::Value = config::parse(value)?;
+        let config = if json.get("rules").is_some() || json.get("buyXGetY").is_some() {
+            serde_json::from_value(json).map_err(parse_error)?
+        } else {
+            // Single-rule configuration, as written before rules were introduced.
+            let threshold_inclusion = match json.get("thresholdInclusion") {
+                Some(value) => serde_json::from_value(value.clone()).map_err(parse_error)?,
+                None => ThresholdInclusion::default(),
+            };
+            let shop_currency = json.get("shopCurrency").and_then(|value| value.as_str()).map(str::to_string);
+            let rule: Rule = serde_json::from_value(json).map_err(parse_error)?;
+            Configuration {
+                collection_ids: rule.collection_ids.clone(),
+                rules: vec![rule],
+                application_strategy: None,
+                buy_x_get_y: vec![],
+                threshold_inclusion,
+                shop_currency,
+            }
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> std::result::Result<(), ConfigError> {
+        if let Some(currency) = &self.shop_currency {
+            money::validate_currency(currency).map_err(|reason| ConfigError::invalid("shopCurrency", reason))?;
+        }
+        // Without shopCurrency amounts are in the cart currency, so check them against
+        // the currencies with the most minor units.
+        let currency = self.shop_currency.as_deref().unwrap_or("KWD");
+        for (index, rule) in self.rules.iter().enumerate() {
+            rule.validate(index, currency)?;
+            // Collections missing from the query variable never show up as members.
+            if let Some(missing) = rule.collection_ids.iter().find(|id| !self.collection_ids.contains(id)) {
+                return Err(ConfigError::InvalidValue {
+                    field: format!("rules[{}].collectionIds", index),
+                    reason: format!("{} is not listed in the top-level collectionIds", missing),
+                });
+            }
+        }
+        for (index, offer) in self.buy_x_get_y.iter().enumerate() {
+            offer.validate(index)?;
+            let listed = offer.buy.collection_ids.iter().chain(offer.get.collection_ids.iter());
+            if let Some(missing) = listed.into_iter().find(|id| !self.collection_ids.contains(id)) {
+                return Err(ConfigError::invalid(
+                    format!("buyXGetY[{}]", index),
+                    format!("{} is not listed in the top-level collectionIds", missing),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BuyXGetY {
+    fn validate(&self, index: usize) -> std::result::Result<(), ConfigError> {
+        if self.buy.collection_ids.is_empty() {
+            return Err(ConfigError::invalid(
+                format!("buyXGetY[{}].buy.collectionIds", index),
+                "at least one collection id is required",
+            ));
+        }
+        for (side, selection) in [("buy", &self.buy), ("get", &self.get)] {
+            if selection.quantity < 1 {
+                return Err(ConfigError::invalid(
+                    format!("buyXGetY[{}].{}.quantity", index, side),
+                    format!("quantity {} must be at least 1", selection.quantity),
+                ));
+            }
+        }
+        if !(self.percentage > 0.0 && self.percentage <= 100.0) {
+            return Err(ConfigError::invalid(
+                format!("buyXGetY[{}].percentage", index),
+                format!("percentage {} must be above 0 and at most 100", self.percentage),
+            ));
+        }
+        self.schedule
+            .validate()
+            .map_err(|reason| ConfigError::invalid(format!("buyXGetY[{}].schedule", index), reason))
+    }
+
+    fn message(&self) -> String {
+        if let Some(message) = &self.message {
+            return message.clone();
+        }
+        if self.percentage >= 100.0 {
+            format!("Buy {}, get {} free.", self.buy.quantity, self.get.quantity)
+        } else {
+            format!("Buy {}, get {} at {}% off.", self.buy.quantity, self.get.quantity, self.percentage)
+        }
+    }
+
+    // Pick the reward units, as (cart line, units) in cart order. Rewards are the cheapest
+    // units of the get collections, the earlier line first on a tie. Each set also needs
+    // `buy.quantity` other units, taken from lines that cannot be rewards first, then from
+    // the most expensive ones. Lines in `claimed` are never rewarded but still count as bought.
+    fn reward_units<'a>(&self, lines: &'a [input::InputCartLines], claimed: &[&str]) -> Vec<(&'a input::InputCartLines, i64)> {
+        let get_ids = if self.get.collection_ids.is_empty() {
+            &self.buy.collection_ids
+        } else {
+            &self.get.collection_ids
+        };
+        // (line, unit price in minor units, can be bought, can be rewarded)
+        let pool: Vec<(&input::InputCartLines, i64, bool, bool)> = lines
+            .iter()
+            .filter_map(|line| {
+                let in_collections = line.in_collections()?;
+                let buys = collections::is_member_of_any(in_collections, &self.buy.collection_ids);
+                let rewards = !claimed.contains(&line.id.as_str()) && collections::is_member_of_any(in_collections, get_ids);
+                let price = cart::unit_price(line)?.minor_units();
+                (buys || rewards).then_some((line, price, buys, rewards))
+            })
+            .collect();
+        let mut reward_order: Vec<usize> = (0..pool.len()).filter(|index| pool[*index].3).collect();
+        reward_order.sort_by_key(|index| pool[*index].1);
+        let mut buy_order: Vec<usize> = (0..pool.len()).filter(|index| pool[*index].2).collect();
+        buy_order.sort_by_key(|index| (pool[*index].3, std::cmp::Reverse(pool[*index].1)));
+
+        let mut remaining: Vec<i64> = pool.iter().map(|(line, _, _, _)| line.quantity).collect();
+        let mut rewarded = vec![0; pool.len()];
+        loop {
+            let mut next = remaining.clone();
+            let rewards = take_units(&reward_order, self.get.quantity, &mut next);
+            let bought = take_units(&buy_order, self.buy.quantity, &mut next);
+            match rewards {
+                Some(rewards) if bought.is_some() => {
+                    rewards.into_iter().for_each(|(index, units)| rewarded[index] += units);
+                    remaining = next;
+                }
+                _ => break,
+            }
+            if !self.repeat {
+                break;
+            }
+        }
+        pool.iter()
+            .zip(rewarded)
+            .filter(|(_, units)| *units > 0)
+            .map(|((line, _, _, _), units)| (*line, units))
+            .collect()
+    }
+}
+
+// Take `wanted` units from the pool entries in `order`, or None when there are not enough.
+fn take_units(order: &[usize], wanted: i64, remaining: &mut [i64]) -> Option<Vec<(usize, i64)>> {
+    let mut taken = vec![];
+    let mut missing = wanted;
+    for index in order {
+        if missing == 0 {
+            break;
+        }
+        let units = remaining[*index].min(missing);
+        if units > 0 {
+            remaining[*index] -= units;
+            missing -= units;
+            taken.push((*index, units));
+        }
+    }
+    (missing == 0).then_some(taken)
+}
+
+impl Rule {
+    fn validate(&self, index: usize, currency: &str) -> std::result::Result<(), ConfigError> {
+        if self.collection_ids.is_empty() || self.collection_ids.iter().any(|id| id.is_empty()) {
+            return Err(ConfigError::InvalidValue {
+                field: format!("rules[{}].collectionIds", index),
+                reason: "at least one non-empty collection id is required".to_string(),
+            });
+        }
+        let valid_value = match self.kind {
+            DiscountKind::Percentage => (0.0..=100.0).contains(&self.value),
+            DiscountKind::FixedAmountPerUnit | DiscountKind::FixedAmount => {
+                self.value.is_finite() && self.value >= 0.0
+            }
+        };
+        if !valid_value {
+            return Err(ConfigError::InvalidValue {
+                field: format!("rules[{}].value", index),
+                reason: format!("{} is not a valid {:?} value", self.value, self.kind),
+            });
+        }
+        if !self.threshold.is_finite() || self.threshold < 0.0 {
+            return Err(ConfigError::InvalidValue {
+                field: format!("rules[{}].threshold", index),
+                reason: format!("threshold {} must be a non-negative number", self.threshold),
+            });
+        }
+        if let Some(cap) = self.max_discount_amount {
+            if !cap.is_finite() || cap < 0.0 {
+                return Err(ConfigError::InvalidValue {
+                    field: format!("rules[{}].maxDiscountAmount", index),
+                    reason: format!("cap {} must be a non-negative number", cap),
+                });
+            }
+        }
+        // An amount that does not fit in minor units could never be converted at run time.
+        let amounts = [("value", Some(self.value)), ("threshold", Some(self.threshold)), ("maxDiscountAmount", self.max_discount_amount)];
+        for (name, amount) in amounts {
+            if let Some(amount) = amount.filter(|amount| Money::from_decimal(*amount, currency, Rounding::HalfUp).is_none()) {
+                return Err(ConfigError::invalid(format!("rules[{}].{}", index, name), format!("{} is too large an amount", amount)));
+            }
+        }
+        if !self.discount_codes.is_empty() {
+            return Err(ConfigError::invalid(
+                format!("rules[{}].discountCodes", index),
+                "entered codes are not part of this API version's input, so the rule would never apply",
+            ));
+        }
+        self.schedule.validate().map_err(|reason| ConfigError::InvalidValue {
+            field: format!("rules[{}].schedule", index),
+            reason,
+        })
+    }
+}
+
+// How the discount value is read.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum DiscountKind {
+    // Percentage off the targeted lines.
+    #[default]
+    Percentage,
+    // Fixed amount off each targeted unit.
+    FixedAmountPerUnit,
+    // Fixed amount spread once across all targets.
+    FixedAmount,
+}
+
+impl DiscountKind {
+    // Value for the `appliesToEachItem` flag of a `FixedAmount` output.
+    fn applies_to_each_item(&self) -> Option<bool> {
+        match self {
+            DiscountKind::Percentage => None,
+            DiscountKind::FixedAmountPerUnit => Some(true),
+            DiscountKind::FixedAmount => Some(false),
+        }
+    }
+
+    // Money taken off each targeted line, used to order discounts for the strategy
+    // and to share out a capped discount. `value` is the percentage, or the fixed
+    // amount in the cart currency.
+    fn reductions<L: CartLine>(&self, value: f64, lines: &[&L], currency: &str) -> Vec<Money> {
+        let unit_prices: Vec<(Money, i64)> = lines
+            .iter()
+            .map(|line| (cart::unit_price(*line).unwrap_or_else(|| Money::zero(currency)), line.quantity()))
+            .collect();
+        let amount = match Money::from_decimal(value, currency, Rounding::Down) {
+            Some(amount) => amount,
+            None => return unit_prices.iter().map(|_| Money::zero(currency)).collect(),
+        };
+        match self {
+            DiscountKind::Percentage => unit_prices
+                .iter()
+                .map(|(price, quantity)| price.times(*quantity).percentage(value, Rounding::HalfUp))
+                .collect(),
+            // A unit never gets more off than its price.
+            DiscountKind::FixedAmountPerUnit => unit_prices
+                .iter()
+                .map(|(price, quantity)| if amount < *price { amount.clone() } else { price.clone() }.times(*quantity))
+                .collect(),
+            // One amount across all targets, shared out by line value.
+            DiscountKind::FixedAmount => {
+                let values: Vec<Money> = unit_prices.iter().map(|(price, quantity)| price.times(*quantity)).collect();
+                let total = values
+                    .iter()
+                    .fold(Money::zero(currency), |total, value| total.checked_add(value).unwrap_or(total));
+                let amount = if amount < total { amount } else { total };
+                amount.allocate(&values.iter().map(Money::minor_units).collect::<Vec<i64>>())
+            }
+        }
+    }
+
+    fn message(&self, value: f64, currency: &str) -> String {
+        match self {
+            DiscountKind::Percentage => format!("{}% discount applied to eligible collection items.", value),
+            DiscountKind::FixedAmountPerUnit => match Money::from_decimal(value, currency, Rounding::Down) {
+                Some(amount) => format!("{} off each eligible collection item.", amount),
+                None => "Discount applied to eligible collection items.".to_string(),
+            },
+            DiscountKind::FixedAmount => match Money::from_decimal(value, currency, Rounding::Down) {
+                Some(amount) => format!("{} off eligible collection items.", amount),
+                None => "Discount applied to eligible collection items.".to_string(),
+            },
+        }
+    }
+}
+
+impl CartLine for input::InputCartLines {
+    type Membership = input::InputCartLinesMerchandiseOnProductVariantProductInCollections;
+
+    fn quantity(&self) -> i64 {
+        self.quantity
+    }
+
+    fn amount_per_quantity(&self) -> f64 {
+        self.cost.amount_per_quantity.amount.0
+    }
+
+    fn currency_code(&self) -> &str {
+        &self.cost.amount_per_quantity.currency_code
+    }
+
+    fn in_collections(&self) -> Option<&[Self::Membership]> {
+        match &self.merchandise {
+            input::InputCartLinesMerchandise::ProductVariant(variant) => Some(&variant.product.in_collections),
+            _ => None,
+        }
+    }
+
+    fn is_gift_card(&self) -> bool {
+        match &self.merchandise {
+            input::InputCartLinesMerchandise::ProductVariant(variant) => variant.product.is_gift_card,
+            input::InputCartLinesMerchandise::CustomProduct(custom) => custom.is_gift_card,
+        }
+    }
+
+    fn is_subscription(&self) -> bool {
+        self.selling_plan_allocation.is_some()
+    }
+}
+
+impl CollectionMembership for input::InputCartLinesMerchandiseOnProductVariantProductInCollections {
+    fn collection_id(&self) -> &str {
+        &self.collection_id
+    }
+
+    fn is_member(&self) -> bool {
+        self.is_member
+    }
+}
+
+/// Check a `$app:coupon_discounts.config` metafield value, as the function would read it.
+pub(crate) fn validate_config(value: &str) -> std::result::Result<(), String> {
+    Configuration::from_str(value).map(|_| ()).map_err(|err| err.to_string())
+}
 
 // The main function that will be executed by the Shopify
 #[shopify_function_target(query_path = "src/run.graphql", schema_path = "schema.graphql")]
 fn run(input: input::ResponseData) -> Result<output::FunctionRunResult> {
+    evaluate(input, &mut Explanation::default())
+}
+
+/// The body of `run`. Each decision is recorded in `explanation` for the `explain` command.
+pub(crate) fn evaluate(input: input::ResponseData, explanation: &mut Explanation) -> Result<output::FunctionRunResult> {
     // Define the "no discount" outcome.
     let no_discount = output::FunctionRunResult {
         discounts: vec![],
         discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
     };
 
-    // 1. Calculate the total cart value using f64 arithmetic.
-    let mut total_cart_value_f64: f64 = 0.0;
-    for line in &input.cart.lines {
-        // Check that the cost is an amount per quantity.
-        let line_amount: f64 = line.cost.amount_per_quantity.amount.0;
-        total_cart_value_f64 += line_amount * (line.quantity as f64);
-    }
-    
-    
-    // 2. Check if the total cart value is at least 150 EUR.
-    if total_cart_value_f64 < CART_VALUE_THRESHOLD {
-        eprintln!("Cart value {} is less than 150.", total_cart_value_f64);
-        return Ok(no_discount);
+    // Parse the configuration from the discountNode metafield.
+    let config = match input.discount_node.metafield {
+        Some(input::InputDiscountNodeMetafield { value }) => match Configuration::from_str(&value) {
+            Ok(config) => config,
+            Err(err) => {
+                err.log("no_discount");
+                explanation.note(|| format!("Configuration rejected, no discount: {}", err));
+                return Ok(no_discount);
+            }
+        },
+        None => {
+            explanation.note(|| "No configuration metafield, applying the legacy rule.".to_string());
+            Configuration::legacy()
+        }
+    };
+
+    // 1. Calculate the total cart value in exact minor units, without the lines
+    //    the inclusion rules leave out.
+    let currency = cart::currency(&input.cart.lines);
+    // Amounts are declared in the shop currency and converted with the presentment rate.
+    let shop_currency = config.shop_currency.as_deref().unwrap_or(&currency);
+    let shop_amount = |value: f64, rounding: Rounding| {
+        money::to_presentment(value, shop_currency, input.presentment_currency_rate.0, &currency, rounding)
+    };
+    let total_cart_value = cart::total(&input.cart.lines, |line| match config.threshold_inclusion.exclusion(line) {
+        Some(reason) => {
+            explanation.note(|| format!("Line {} is {} and does not count toward the cart value.", line.id, reason));
+            false
+        }
+        None => true,
+    });
+    explanation.note(|| format!("Cart value: {}.", total_cart_value));
+
+    // 2. Evaluate the rules that are active right now in priority order. Each cart line can only be claimed once.
+    let local_time = &input.shop.local_time;
+    let hours_after = [
+        local_time.hour01, local_time.hour02, local_time.hour03, local_time.hour04,
+        local_time.hour05, local_time.hour06, local_time.hour07, local_time.hour08,
+        local_time.hour09, local_time.hour10, local_time.hour11, local_time.hour12,
+        local_time.hour13, local_time.hour14, local_time.hour15, local_time.hour16,
+        local_time.hour17, local_time.hour18, local_time.hour19, local_time.hour20,
+        local_time.hour21, local_time.hour22, local_time.hour23,
+    ];
+    let now = ShopTime::from_hour_flags(&local_time.date, &hours_after);
+    let mut claimed_lines: Vec<&str> = vec![];
+    let mut discounts = vec![];
+    for (index, rule) in config.rules.iter().enumerate() {
+        // Without a readable shop date only unscheduled rules can apply.
+        let is_active = match &now {
+            Some(now) => rule.schedule.is_active(now),
+            None => rule.schedule == Schedule::default(),
+        };
+        if !is_active {
+            explanation.note(|| format!("Rule {} is outside its schedule.", index));
+            continue;
+        }
+
+        let Some(threshold) = shop_amount(rule.threshold, Rounding::Up) else {
+            explanation.note(|| format!("Rule {} threshold {} cannot be converted to {}, rule skipped.", index, rule.threshold, currency));
+            continue;
+        };
+        if total_cart_value < threshold {
+            explanation.note(|| format!("Rule {} needs {}: not reached.", index, threshold));
+            continue;
+        }
+
+        // 3. Resolve the rule's discount before claiming any line. Fixed amounts and the cap
+        //    are converted to the cart currency; never round up what we give away. `applied`
+        //    is the percentage or the converted amount.
+        let (value, applied) = match rule.kind.applies_to_each_item() {
+            None => (
+                output::Value::Percentage(output::Percentage {
+                    value: Decimal(rule.value),
+                }),
+                rule.value,
+            ),
+            Some(applies_to_each_item) => {
+                let Some(amount) = shop_amount(rule.value, Rounding::Down) else {
+                    explanation.note(|| format!("Rule {} amount {} cannot be converted to {}, rule skipped.", index, rule.value, currency));
+                    continue;
+                };
+                (
+                    output::Value::FixedAmount(output::FixedAmount {
+                        amount: amount.to_decimal(),
+                        applies_to_each_item: Some(applies_to_each_item),
+                    }),
+                    amount.to_decimal().0,
+                )
+            }
+        };
+        let cap = match rule.max_discount_amount {
+            Some(cap) => match shop_amount(cap, Rounding::Down) {
+                Some(cap) => Some(cap),
+                None => {
+                    explanation.note(|| format!("Rule {} cap {} cannot be converted to {}, rule skipped.", index, cap, currency));
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        // 4. Build discount targets only for unclaimed cart lines in the rule's collections.
+        let mut targets = vec![];
+        let mut target_lines = vec![];
+        for line in &input.cart.lines {
+            if claimed_lines.contains(&line.id.as_str()) {
+                explanation.note(|| format!("Line {} was already claimed by an earlier rule.", line.id));
+                continue;
+            }
+            if let Some(in_collections) = line.in_collections() {
+                // Check if the product variant belongs to one of the rule's collections.
+                if collections::is_member_of_any(in_collections, &rule.collection_ids) {
+                    target_lines.push(line);
+                    targets.push(output::Target::CartLine(output::CartLineTarget {
+                        id: line.id.to_string(),
+                        quantity: None,
+                    }));
+                }
+            }
+        }
+
+        // If no eligible cart lines, then this rule is not applied.
+        if targets.is_empty() {
+            explanation.note(|| format!("Rule {} has no unclaimed lines in its collections.", index));
+            continue;
+        }
+        for line in &target_lines {
+            explanation.note(|| format!("Rule {} claims line {}.", index, line.id));
+            claimed_lines.push(&line.id);
+        }
+
+        // 5. Apply the rule's discount to its targets.
+        let message = rule
+            .message
+            .clone()
+            .unwrap_or_else(|| rule.kind.message(applied, &currency));
+        explanation.note(|| format!("Rule {} applies \"{}\".", index, message));
+        let reductions = rule.kind.reductions(applied, &target_lines, &currency);
+        let saving = reductions
+            .iter()
+            .fold(Money::zero(&currency), |total, reduction| total.checked_add(reduction).unwrap_or(total));
+
+        // 6. Above its cap, the rule gives fixed amounts per line that add up to the cap,
+        // shared out in proportion to what each line would have saved.
+        match cap {
+            Some(cap) if saving > cap => {
+                explanation.note(|| format!("Rule {} discount of {} capped at {}.", index, saving, cap));
+                let weights: Vec<i64> = reductions.iter().map(Money::minor_units).collect();
+                let portions = targets
+                    .into_iter()
+                    .zip(cap.allocate(&weights))
+                    .filter(|(_, portion)| !portion.is_zero())
+                    .map(|(target, portion)| output::Discount {
+                        message: Some(format!("Discount capped at {}.", cap)),
+                        targets: vec![target],
+                        value: output::Value::FixedAmount(output::FixedAmount {
+                            amount: portion.to_decimal(),
+                            applies_to_each_item: Some(false),
+                        }),
+                    })
+                    .collect();
+                discounts.push((portions, cap.minor_units()));
+            }
+            _ => discounts.push((
+                vec![output::Discount {
+                    message: Some(message),
+                    targets,
+                    value,
+                }],
+                saving.minor_units(),
+            )),
+        }
     }
-    
-    // 3. Build discount targets only for cart lines in the specified collection.
-    let mut targets = vec![];
-    for line in &input.cart.lines {
-        if let input::InputCartLinesMerchandise::ProductVariant(variant) = &line.merchandise {
-            // Check if the product variant belongs to the target collection.
-            if variant.product.in_collections.iter().any(|col| col.collection_id == TARGET_COLLECTION && col.is_member) {
-                targets.push(output::Target::CartLine(output::CartLineTarget {
-                    id: line.id.to_string(),
-                    quantity: None,
-                }));
+
+    // 7. Buy X get Y offers discount only their reward units, through the line target quantity.
+    for (index, offer) in config.buy_x_get_y.iter().enumerate() {
+        let is_active = match &now {
+            Some(now) => offer.schedule.is_active(now),
+            None => offer.schedule == Schedule::default(),
+        };
+        if !is_active {
+            explanation.note(|| format!("Buy X get Y offer {} is outside its schedule.", index));
+            continue;
+        }
+        let rewards = offer.reward_units(&input.cart.lines, &claimed_lines);
+        if rewards.is_empty() {
+            explanation.note(|| format!("Buy X get Y offer {} is not reached.", index));
+            continue;
+        }
+        let mut saving = Money::zero(&currency);
+        let mut targets = vec![];
+        for (line, units) in rewards {
+            explanation.note(|| format!("Buy X get Y offer {} rewards {} units of line {}.", index, units, line.id));
+            if let Some(reduction) = cart::unit_price(line).map(|price| price.times(units).percentage(offer.percentage, Rounding::HalfUp)) {
+                saving = saving.checked_add(&reduction).unwrap_or(saving);
             }
+            claimed_lines.push(&line.id);
+            targets.push(output::Target::CartLine(output::CartLineTarget {
+                id: line.id.to_string(),
+                quantity: Some(units),
+            }));
         }
+        discounts.push((
+            vec![output::Discount {
+                message: Some(offer.message()),
+                targets,
+                value: output::Value::Percentage(output::Percentage {
+                    value: Decimal(offer.percentage),
+                }),
+            }],
+            saving.minor_units(),
+        ));
     }
-    
-    // If no eligible cart lines, then no discount is applied.
-    if targets.is_empty() {
-        eprintln!("No cart lines belong to the target collection.");
+
+    if discounts.is_empty() {
         return Ok(no_discount);
     }
 
-    // 4. Apply a specific discount(15% in this case) to the eligible targets.
+    // Rules never share a cart line, so by default every emitted discount can be applied.
+    let mut strategy = config.application_strategy.unwrap_or(if discounts.len() > 1 {
+        ApplicationStrategy::All
+    } else {
+        ApplicationStrategy::First
+    });
+    strategy.order(&mut discounts, |(_, saving)| *saving);
+    // A capped rule only adds up to its cap when all of its portions are applied.
+    if strategy != ApplicationStrategy::All && discounts.first().is_some_and(|(portions, _)| portions.len() > 1) {
+        discounts.truncate(1);
+        strategy = ApplicationStrategy::All;
+    }
+    explanation.note(|| format!("{} discounts, applied with {:?}.", discounts.len(), strategy));
+    let discount_application_strategy = match strategy {
+        ApplicationStrategy::First => output::DiscountApplicationStrategy::FIRST,
+        ApplicationStrategy::Maximum => output::DiscountApplicationStrategy::MAXIMUM,
+        ApplicationStrategy::All => output::DiscountApplicationStrategy::ALL,
+    };
     Ok(output::FunctionRunResult {
-        discounts: vec![output::Discount {
-            message: Some("15% discount applied to eligible collection items.".to_string()),
-            targets,
-            value: output::Value::Percentage(output::Percentage {
-                value: Decimal(DISCOUNT_PERCENTAGE),
-            }),
-        }],
-        discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        discounts: discounts.into_iter().flat_map(|(discounts, _)| discounts).collect(),
+        discount_application_strategy,
     })
 }
 
@@ -87,12 +754,17 @@ mod tests {
     fn test_empty_cart() -> Result<()> {
         let input = r#"
         {
+            "discountNode": {
+                "metafield": {
+                    "value": "{\"collectionIds\":[\"gid://shopify/Collection/496241049921\"],\"value\":15,\"threshold\":150}"
+                }
+            },
             "cart": {
                 "lines": []
             }
         }
         "#;
-        let result = run_function_with_input(run, input)?;
+        let result = run_function_with_input(run, &with_shop_time(input))?;
         let expected = output::FunctionRunResult {
             discounts: vec![],
             discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
@@ -106,6 +778,11 @@ mod tests {
     fn test_cart_below_threshold() -> Result<()> {
         let input = r#"
         {
+            "discountNode": {
+                "metafield": {
+                    "value": "{\"collectionIds\":[\"gid://shopify/Collection/496241049921\"],\"value\":15,\"threshold\":150}"
+                }
+            },
             "cart": {
                 "lines": [
                     {
@@ -122,6 +799,7 @@ mod tests {
                             "id": "gid://shopify/ProductVariant/111",
                             "sku": "SKU111",
                             "product": {
+                                "isGiftCard": false,
                                 "inCollections": [
                                     {
                                         "collectionId": "gid://shopify/Collection/496241049921",
@@ -136,7 +814,7 @@ mod tests {
         }
         "#;
         // Total value = 100 < 150.
-        let result = run_function_with_input(run, input)?;
+        let result = run_function_with_input(run, &with_shop_time(input))?;
         let expected = output::FunctionRunResult {
             discounts: vec![],
             discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
@@ -150,6 +828,11 @@ mod tests {
     fn test_cart_with_eligible_product() -> Result<()> {
         let input = r#"
         {            
+            "discountNode": {
+                "metafield": {
+                    "value": "{\"collectionIds\":[\"gid://shopify/Collection/496241049921\"],\"value\":15,\"threshold\":150}"
+                }
+            },
             "cart": {
                 "lines": [
                     {
@@ -166,6 +849,7 @@ mod tests {
                             "id": "gid://shopify/ProductVariant/111",
                             "sku": "SKU111",
                             "product": {
+                                "isGiftCard": false,
                                 "inCollections": [
                                     {
                                         "collectionId": "gid://shopify/Collection/496241049921",
@@ -189,6 +873,7 @@ mod tests {
                             "id": "gid://shopify/ProductVariant/222",
                             "sku": "SKU222",
                             "product": {
+                                "isGiftCard": false,
                                 "inCollections": [
                                     {
                                         "collectionId": "gid://shopify/Collection/NOTELIGIBLE",
@@ -204,7 +889,7 @@ mod tests {
         "#;
         // Total cart value = (2 * 100) + (1 * 50) = 250, which exceeds the threshold.
         // Only the first cart line qualifies.
-        let result = run_function_with_input(run, input)?;
+        let result = run_function_with_input(run, &with_shop_time(input))?;
         let expected = output::FunctionRunResult {
             discounts: vec![output::Discount {
                 message: Some("15% discount applied to eligible collection items.".to_string()),
@@ -213,7 +898,7 @@ mod tests {
                     quantity: None,
                 })],
                 value: output::Value::Percentage(output::Percentage {
-                    value: Decimal(DISCOUNT_PERCENTAGE),
+                    value: Decimal(15.0),
                 }),
             }],
             discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
@@ -227,6 +912,11 @@ mod tests {
     fn test_cart_with_no_eligible_products() -> Result<()> {
         let input = r#"
         {            
+            "discountNode": {
+                "metafield": {
+                    "value": "{\"collectionIds\":[\"gid://shopify/Collection/496241049921\"],\"value\":15,\"threshold\":150}"
+                }
+            },
             "cart": {
                 "lines": [
                     {
@@ -243,6 +933,7 @@ mod tests {
                             "id": "gid://shopify/ProductVariant/111",
                             "sku": "SKU111",
                             "product": {
+                                "isGiftCard": false,
                                 "inCollections": [
                                     {
                                         "collectionId": "gid://shopify/Collection/NOTELIGIBLE",
@@ -257,7 +948,7 @@ mod tests {
         }
         "#;
         // Total cart value = 200 (above threshold), but no product is in the target collection.
-        let result = run_function_with_input(run, input)?;
+        let result = run_function_with_input(run, &with_shop_time(input))?;
         let expected = output::FunctionRunResult {
             discounts: vec![],
             discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
@@ -265,4 +956,727 @@ mod tests {
         assert_eq!(result, expected);
         Ok(())
     }
+
+    // Test 5: Cart exactly on the threshold, where f64 addition would fall short.
+    #[test]
+    fn test_cart_on_threshold_boundary() -> Result<()> {
+        let input = r#"
+        {
+            "discountNode": {
+                "metafield": {
+                    "value": "{\"collectionIds\":[\"gid://shopify/Collection/496241049921\"],\"value\":15,\"threshold\":150}"
+                }
+            },
+            "cart": {
+                "lines": [
+                    {
+                        "id": "gid://shopify/CartLine/0",
+                        "quantity": 7,
+                        "cost": {
+                            "amountPerQuantity": {
+                                "amount": "17.65",
+                                "currencyCode": "EUR"
+                            }
+                        },
+                        "merchandise": {
+                            "__typename": "ProductVariant",
+                            "id": "gid://shopify/ProductVariant/111",
+                            "sku": "SKU111",
+                            "product": {
+                                "isGiftCard": false,
+                                "inCollections": [
+                                    {
+                                        "collectionId": "gid://shopify/Collection/496241049921",
+                                        "isMember": true
+                                    }
+                                ]
+                            }
+                        }
+                    },
+                    {
+                        "id": "gid://shopify/CartLine/1",
+                        "quantity": 1,
+                        "cost": {
+                            "amountPerQuantity": {
+                                "amount": "26.45",
+                                "currencyCode": "EUR"
+                            }
+                        },
+                        "merchandise": {
+                            "__typename": "ProductVariant",
+                            "id": "gid://shopify/ProductVariant/222",
+                            "sku": "SKU222",
+                            "product": {
+                                "isGiftCard": false,
+                                "inCollections": [
+                                    {
+                                        "collectionId": "gid://shopify/Collection/NOTELIGIBLE",
+                                        "isMember": true
+                                    }
+                                ]
+                            }
+                        }
+                    }
+                ]
+            }
+        }
+        "#;
+        // Total cart value = (7 * 17.65) + 26.45 = 150.00, exactly the threshold.
+        let result = run_function_with_input(run, &with_shop_time(input))?;
+        let expected = output::FunctionRunResult {
+            discounts: vec![output::Discount {
+                message: Some("15% discount applied to eligible collection items.".to_string()),
+                targets: vec![output::Target::CartLine(output::CartLineTarget {
+                    id: "gid://shopify/CartLine/0".to_string(),
+                    quantity: None,
+                })],
+                value: output::Value::Percentage(output::Percentage {
+                    value: Decimal(15.0),
+                }),
+            }],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    // Test 6: Each discount kind maps onto the matching output flag and message.
+    #[test]
+    fn test_discount_kind_mapping() {
+        assert_eq!(DiscountKind::Percentage.applies_to_each_item(), None);
+        assert_eq!(DiscountKind::FixedAmountPerUnit.applies_to_each_item(), Some(true));
+        assert_eq!(DiscountKind::FixedAmount.applies_to_each_item(), Some(false));
+
+        assert_eq!(
+            DiscountKind::Percentage.message(15.0, "EUR"),
+            "15% discount applied to eligible collection items."
+        );
+        assert_eq!(
+            DiscountKind::FixedAmountPerUnit.message(10.0, "EUR"),
+            "10.00 EUR off each eligible collection item."
+        );
+        assert_eq!(
+            DiscountKind::FixedAmount.message(25.0, "EUR"),
+            "25.00 EUR off eligible collection items."
+        );
+    }
+
+    // Builds a cart with one line per (collection, amount) pair and the given configuration.
+    fn input_with(config: Option<&str>, lines: &[(&str, &str)]) -> String {
+        let lines: Vec<serde_json::Value> = lines
+            .iter()
+            .enumerate()
+            .map(|(index, (collection, amount))| {
+                serde_json::json!({
+                    "id": format!("gid://shopify/CartLine/{}", index),
+                    "quantity": 1,
+                    "cost": {
+                        "amountPerQuantity": {
+                            "amount": amount,
+                            "currencyCode": "EUR"
+                        }
+                    },
+                    "merchandise": {
+                        "__typename": "ProductVariant",
+                        "id": format!("gid://shopify/ProductVariant/{}", index),
+                        "product": {
+                            "isGiftCard": false,
+                            "inCollections": [
+                                {
+                                    "collectionId": collection,
+                                    "isMember": true
+                                }
+                            ]
+                        }
+                    }
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "discountNode": {
+                "metafield": config.map(|value| serde_json::json!({ "value": value }))
+            },
+            "presentmentCurrencyRate": "1.0",
+            "shop": {
+                "localTime": local_time("2024-06-03", 12)
+            },
+            "cart": {
+                "lines": lines
+            }
+        })
+        .to_string()
+    }
+
+    // `shop.localTime` as queried: the date plus one `timeAfter` flag per full hour.
+    fn local_time(date: &str, hour: u32) -> serde_json::Value {
+        let mut local_time = serde_json::json!({ "date": date });
+        for h in 1..24 {
+            local_time[format!("hour{:02}", h)] = (hour >= h).into();
+        }
+        local_time
+    }
+
+    // Adds a Monday noon `shop.localTime` and a 1.0 presentment rate to a hand-written input.
+    fn with_shop_time(input: &str) -> String {
+        let mut input: serde_json::Value = serde_json::from_str(input).unwrap();
+        input["presentmentCurrencyRate"] = "1.0".into();
+        input["shop"] = serde_json::json!({ "localTime": local_time("2024-06-03", 12) });
+        input.to_string()
+    }
+
+    fn cart_line_target(index: u32) -> output::Target {
+        output::Target::CartLine(output::CartLineTarget {
+            id: format!("gid://shopify/CartLine/{}", index),
+            quantity: None,
+        })
+    }
+
+    // Test 7: Without a configuration metafield the rule that used to be built in applies.
+    #[test]
+    fn test_missing_configuration_applies_the_legacy_rule() -> Result<()> {
+        let input = input_with(None, &[(LEGACY_COLLECTION, "150.0"), ("gid://shopify/Collection/1", "200.0")]);
+        assert_eq!(
+            run_function_with_input(run, &input)?.discounts,
+            vec![output::Discount {
+                message: Some("15% discount applied to eligible collection items.".to_string()),
+                targets: vec![cart_line_target(0)],
+                value: output::Value::Percentage(output::Percentage { value: Decimal(15.0) }),
+            }]
+        );
+        let input = input_with(None, &[(LEGACY_COLLECTION, "149.99")]);
+        assert_eq!(run_function_with_input(run, &input)?.discounts, vec![]);
+
+        let legacy = r#"{"collectionIds": ["gid://shopify/Collection/496241049921"], "value": 15, "threshold": 150}"#;
+        assert_eq!(Configuration::legacy(), Configuration::from_str(legacy).unwrap());
+        // Without a metafield the query only checks the legacy collection
+        assert!(include_str!("run.graphql").contains(&format!("$collectionIds: [ID!]! = [\"{}\"]", LEGACY_COLLECTION)));
+        Ok(())
+    }
+
+    // Test 8: A malformed or invalid configuration falls back to no discount.
+    #[test]
+    fn test_invalid_configuration() -> Result<()> {
+        for config in [
+            "{not json",
+            r#"{"collectionIds":[],"value":15,"threshold":150}"#,
+            r#"{"collectionIds":["gid://shopify/Collection/1"],"value":120,"threshold":150}"#,
+            r#"{"collectionIds":["gid://shopify/Collection/1"],"value":15,"threshold":-1}"#,
+        ] {
+            let input = input_with(Some(config), &[("gid://shopify/Collection/1", "200.0")]);
+            let result = run_function_with_input(run, &input)?;
+            assert_eq!(result.discounts, vec![], "config {}", config);
+        }
+        Ok(())
+    }
+
+    // Test 9: Every configured collection is targeted and the configured message is used.
+    #[test]
+    fn test_configured_collections_and_message() -> Result<()> {
+        let config = r#"{"collectionIds":["gid://shopify/Collection/1","gid://shopify/Collection/2"],"value":10,"threshold":80,"message":"Spring sale"}"#;
+        let input = input_with(
+            Some(config),
+            &[
+                ("gid://shopify/Collection/1", "30.0"),
+                ("gid://shopify/Collection/3", "30.0"),
+                ("gid://shopify/Collection/2", "30.0"),
+            ],
+        );
+        let result = run_function_with_input(run, &input)?;
+        let expected = output::FunctionRunResult {
+            discounts: vec![output::Discount {
+                message: Some("Spring sale".to_string()),
+                targets: vec![cart_line_target(0), cart_line_target(2)],
+                value: output::Value::Percentage(output::Percentage {
+                    value: Decimal(10.0),
+                }),
+            }],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    // Test 10: A fixed amount configuration emits a `FixedAmount` value.
+    #[test]
+    fn test_configured_fixed_amount() -> Result<()> {
+        let config = r#"{"collectionIds":["gid://shopify/Collection/1"],"kind":"fixed_amount","value":25,"threshold":150}"#;
+        let input = input_with(Some(config), &[("gid://shopify/Collection/1", "200.0")]);
+        let result = run_function_with_input(run, &input)?;
+        let expected = output::FunctionRunResult {
+            discounts: vec![output::Discount {
+                message: Some("25.00 EUR off eligible collection items.".to_string()),
+                targets: vec![cart_line_target(0)],
+                value: output::Value::FixedAmount(output::FixedAmount {
+                    amount: Decimal(25.0),
+                    applies_to_each_item: Some(false),
+                }),
+            }],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    const OUTERWEAR: &str = "gid://shopify/Collection/1";
+    const ACCESSORIES: &str = "gid://shopify/Collection/2";
+    const TWO_RULES: &str = r#"{
+        "collectionIds": ["gid://shopify/Collection/1", "gid://shopify/Collection/2"],
+        "rules": [
+            {"collectionIds": ["gid://shopify/Collection/1"], "value": 15, "threshold": 150},
+            {"collectionIds": ["gid://shopify/Collection/2"], "value": 10, "threshold": 80}
+        ]
+    }"#;
+
+    fn percentage_discount(value: f64, targets: Vec<output::Target>) -> output::Discount {
+        output::Discount {
+            message: Some(format!("{}% discount applied to eligible collection items.", value)),
+            targets,
+            value: output::Value::Percentage(output::Percentage {
+                value: Decimal(value),
+            }),
+        }
+    }
+
+    // Test 11: Only the rules whose threshold is reached are applied.
+    #[test]
+    fn test_rules_with_independent_thresholds() -> Result<()> {
+        let input = input_with(Some(TWO_RULES), &[(OUTERWEAR, "60.0"), (ACCESSORIES, "40.0")]);
+        let result = run_function_with_input(run, &input)?;
+        let expected = output::FunctionRunResult {
+            discounts: vec![percentage_discount(10.0, vec![cart_line_target(1)])],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    // Test 12: Each qualifying rule emits its own discount and all of them apply.
+    #[test]
+    fn test_one_discount_per_rule() -> Result<()> {
+        let input = input_with(Some(TWO_RULES), &[(OUTERWEAR, "120.0"), (ACCESSORIES, "40.0")]);
+        let result = run_function_with_input(run, &input)?;
+        let expected = output::FunctionRunResult {
+            discounts: vec![
+                percentage_discount(15.0, vec![cart_line_target(0)]),
+                percentage_discount(10.0, vec![cart_line_target(1)]),
+            ],
+            discount_application_strategy: output::DiscountApplicationStrategy::ALL,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    // Test 13: A line in two qualifying rules is only targeted by the first one.
+    #[test]
+    fn test_overlapping_rules_resolve_to_first_rule() -> Result<()> {
+        let input = input_with(Some(TWO_RULES), &[(OUTERWEAR, "120.0"), (ACCESSORIES, "40.0")]);
+        let mut input: serde_json::Value = serde_json::from_str(&input).unwrap();
+        input["cart"]["lines"][1]["merchandise"]["product"]["inCollections"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({ "collectionId": OUTERWEAR, "isMember": true }));
+        let result = run_function_with_input(run, &input.to_string())?;
+        let expected = output::FunctionRunResult {
+            discounts: vec![percentage_discount(15.0, vec![cart_line_target(0), cart_line_target(1)])],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    // Test 14: Rule collections have to be part of the query variable.
+    #[test]
+    fn test_rule_collection_missing_from_query_variable() {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1"], "rules": [{"collectionIds": ["gid://shopify/Collection/2"], "value": 10, "threshold": 80}]}"#;
+        assert_eq!(
+            Configuration::from_str(config),
+            Err(ConfigError::InvalidValue {
+                field: "rules[0].collectionIds".to_string(),
+                reason: "gid://shopify/Collection/2 is not listed in the top-level collectionIds".to_string(),
+            })
+        );
+    }
+
+    // Test 15: Scheduled rules only apply inside their window.
+    #[test]
+    fn test_scheduled_rule_windows() -> Result<()> {
+        let config = r#"{
+            "collectionIds": ["gid://shopify/Collection/1"],
+            "rules": [
+                {"collectionIds": ["gid://shopify/Collection/1"], "value": 30, "threshold": 0,
+                 "startsAt": "2024-11-29", "endsAt": "2024-12-02", "hours": {"start": 9, "end": 21}},
+                {"collectionIds": ["gid://shopify/Collection/1"], "value": 10, "threshold": 0,
+                 "daysOfWeek": ["monday"]}
+            ]
+        }"#;
+        let at = |date: &str, hour: u32| -> Result<output::FunctionRunResult> {
+            let mut input: serde_json::Value =
+                serde_json::from_str(&input_with(Some(config), &[(OUTERWEAR, "50.0")])).unwrap();
+            input["shop"]["localTime"] = local_time(date, hour);
+            run_function_with_input(run, &input.to_string())
+        };
+
+        // Black Friday afternoon: the flash sale wins.
+        assert_eq!(at("2024-11-29", 15)?.discounts, vec![percentage_discount(30.0, vec![cart_line_target(0)])]);
+        // Black Friday night: outside the flash sale hours and not a Monday.
+        assert_eq!(at("2024-11-29", 22)?.discounts, vec![]);
+        // Monday after the sale ended: only the weekly rule applies.
+        assert_eq!(at("2024-12-02", 15)?.discounts, vec![percentage_discount(10.0, vec![cart_line_target(0)])]);
+        Ok(())
+    }
+
+    // Test 16: validate-config reports the same errors as the function.
+    #[test]
+    fn test_validate_config() {
+        assert!(validate_config(TWO_RULES).is_ok());
+        assert!(validate_config("not json").unwrap_err().starts_with("unable to parse configuration value"));
+    }
+
+    // Test 17: explain records why each rule applied or was skipped.
+    #[test]
+    fn test_explain_rules() {
+        let input = input_with(Some(TWO_RULES), &[(OUTERWEAR, "100.0")]);
+        let mut explanation = Explanation::enabled();
+        evaluate(serde_json::from_str(&input).unwrap(), &mut explanation).unwrap();
+        let notes = explanation.notes();
+        assert!(notes.iter().any(|note| note.starts_with("Rule 0 needs 150")));
+        assert!(notes.iter().any(|note| note == "Rule 1 has no unclaimed lines in its collections."));
+    }
+
+    fn two_rules_with_strategy(strategy: &str) -> String {
+        TWO_RULES.replacen('{', &format!(r#"{{"applicationStrategy": "{}","#, strategy), 1)
+    }
+
+    // Test 18: FIRST puts the rule that saves the most first (10% of 200.00 beats 15% of 100.00).
+    #[test]
+    fn test_first_strategy_orders_by_saving() -> Result<()> {
+        let input = input_with(Some(&two_rules_with_strategy("first")), &[(OUTERWEAR, "100.0"), (ACCESSORIES, "200.0")]);
+        let result = run_function_with_input(run, &input)?;
+        assert_eq!(
+            result,
+            output::FunctionRunResult {
+                discounts: vec![
+                    percentage_discount(10.0, vec![cart_line_target(1)]),
+                    percentage_discount(15.0, vec![cart_line_target(0)]),
+                ],
+                discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+            }
+        );
+        Ok(())
+    }
+
+    // Test 19: MAXIMUM uses the same order and lets Shopify pick the biggest discount.
+    #[test]
+    fn test_maximum_strategy() -> Result<()> {
+        let input = input_with(Some(&two_rules_with_strategy("maximum")), &[(OUTERWEAR, "100.0"), (ACCESSORIES, "200.0")]);
+        let result = run_function_with_input(run, &input)?;
+        assert_eq!(
+            result.discounts,
+            vec![
+                percentage_discount(10.0, vec![cart_line_target(1)]),
+                percentage_discount(15.0, vec![cart_line_target(0)]),
+            ]
+        );
+        assert_eq!(result.discount_application_strategy, output::DiscountApplicationStrategy::MAXIMUM);
+        Ok(())
+    }
+
+    // Test 20: ALL keeps the rules in priority order, even when only one applies.
+    #[test]
+    fn test_all_strategy() -> Result<()> {
+        let input = input_with(Some(&two_rules_with_strategy("ALL")), &[(OUTERWEAR, "100.0"), (ACCESSORIES, "200.0")]);
+        let result = run_function_with_input(run, &input)?;
+        assert_eq!(
+            result.discounts,
+            vec![
+                percentage_discount(15.0, vec![cart_line_target(0)]),
+                percentage_discount(10.0, vec![cart_line_target(1)]),
+            ]
+        );
+        assert_eq!(result.discount_application_strategy, output::DiscountApplicationStrategy::ALL);
+
+        let input = input_with(Some(&two_rules_with_strategy("all")), &[(ACCESSORIES, "90.0")]);
+        let result = run_function_with_input(run, &input)?;
+        assert_eq!(result.discounts, vec![percentage_discount(10.0, vec![cart_line_target(0)])]);
+        assert_eq!(result.discount_application_strategy, output::DiscountApplicationStrategy::ALL);
+        Ok(())
+    }
+
+    // Test 21: Fixed amounts are compared by what they actually take off.
+    #[test]
+    fn test_saving_caps_fixed_amounts() {
+        let lines = serde_json::from_str::<input::ResponseData>(&input_with(None, &[(OUTERWEAR, "8.0"), (OUTERWEAR, "30.0")])).unwrap();
+        let targets: Vec<&input::InputCartLines> = lines.cart.lines.iter().collect();
+        let eur = |minor_units| Money::from_minor_units(minor_units, "EUR");
+        assert_eq!(DiscountKind::FixedAmountPerUnit.reductions(10.0, &targets, "EUR"), vec![eur(800), eur(1000)]);
+        assert_eq!(DiscountKind::FixedAmount.reductions(50.0, &targets, "EUR"), vec![eur(800), eur(3000)]);
+        assert_eq!(DiscountKind::FixedAmount.reductions(19.0, &targets, "EUR"), vec![eur(400), eur(1500)]);
+        assert_eq!(DiscountKind::Percentage.reductions(50.0, &targets, "EUR"), vec![eur(400), eur(1500)]);
+    }
+
+    // Test 22: A capped rule turns into fixed amounts per line that total the cap exactly.
+    #[test]
+    fn test_capped_rule_spreads_the_cap() -> Result<()> {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1"], "rules": [{"collectionIds": ["gid://shopify/Collection/1"], "value": 20, "threshold": 0, "maxDiscountAmount": 50}]}"#;
+        let input = input_with(Some(config), &[(OUTERWEAR, "100.0"), (OUTERWEAR, "100.0"), (OUTERWEAR, "100.0")]);
+        let result = run_function_with_input(run, &input)?;
+        let portion = |line: u32, amount: f64| output::Discount {
+            message: Some("Discount capped at 50.00 EUR.".to_string()),
+            targets: vec![cart_line_target(line)],
+            value: output::Value::FixedAmount(output::FixedAmount {
+                amount: Decimal(amount),
+                applies_to_each_item: Some(false),
+            }),
+        };
+        assert_eq!(
+            result,
+            output::FunctionRunResult {
+                discounts: vec![portion(0, 16.67), portion(1, 16.67), portion(2, 16.66)],
+                discount_application_strategy: output::DiscountApplicationStrategy::ALL,
+            }
+        );
+
+        // Under the cap the rule is unchanged.
+        let input = input_with(Some(config), &[(OUTERWEAR, "100.0")]);
+        let result = run_function_with_input(run, &input)?;
+        assert_eq!(result.discounts, vec![percentage_discount(20.0, vec![cart_line_target(0)])]);
+        Ok(())
+    }
+
+    // Test 23: With FIRST, a capped rule that wins is applied on its own, with all of its portions.
+    #[test]
+    fn test_capped_rule_with_first_strategy() -> Result<()> {
+        let config = r#"{
+            "collectionIds": ["gid://shopify/Collection/1", "gid://shopify/Collection/2"],
+            "applicationStrategy": "first",
+            "rules": [
+                {"collectionIds": ["gid://shopify/Collection/1"], "value": 50, "threshold": 0, "maxDiscountAmount": 30},
+                {"collectionIds": ["gid://shopify/Collection/2"], "value": 10, "threshold": 0}
+            ]
+        }"#;
+        let input = input_with(Some(config), &[(OUTERWEAR, "40.0"), (OUTERWEAR, "40.0"), (ACCESSORIES, "100.0")]);
+        let result = run_function_with_input(run, &input)?;
+        let targets: Vec<Vec<output::Target>> = result.discounts.into_iter().map(|discount| discount.targets).collect();
+        assert_eq!(targets, vec![vec![cart_line_target(0)], vec![cart_line_target(1)]]);
+        assert_eq!(result.discount_application_strategy, output::DiscountApplicationStrategy::ALL);
+        assert!(Configuration::from_str(&config.replace("30}", "-1}")).is_err());
+        Ok(())
+    }
+
+    // Lines as (collection, unit price, quantity).
+    fn input_with_units(config: &str, lines: &[(&str, &str, i64)]) -> String {
+        let collections: Vec<(&str, &str)> = lines.iter().map(|(collection, amount, _)| (*collection, *amount)).collect();
+        let mut input: serde_json::Value = serde_json::from_str(&input_with(Some(config), &collections)).unwrap();
+        for (index, (_, _, quantity)) in lines.iter().enumerate() {
+            input["cart"]["lines"][index]["quantity"] = (*quantity).into();
+        }
+        input.to_string()
+    }
+
+    fn reward_target(index: u32, quantity: i64) -> output::Target {
+        output::Target::CartLine(output::CartLineTarget {
+            id: format!("gid://shopify/CartLine/{}", index),
+            quantity: Some(quantity),
+        })
+    }
+
+    // Test 24: Buy 2 get 1 free within one collection rewards the cheapest unit.
+    #[test]
+    fn test_buy_x_get_y_rewards_cheapest_unit() -> Result<()> {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1"], "buyXGetY": [
+            {"buy": {"collectionIds": ["gid://shopify/Collection/1"], "quantity": 2}, "get": {"quantity": 1}, "percentage": 100}
+        ]}"#;
+        let input = input_with_units(config, &[(OUTERWEAR, "30.0", 1), (OUTERWEAR, "20.0", 1), (OUTERWEAR, "25.0", 1)]);
+        let result = run_function_with_input(run, &input)?;
+        assert_eq!(
+            result,
+            output::FunctionRunResult {
+                discounts: vec![output::Discount {
+                    message: Some("Buy 2, get 1 free.".to_string()),
+                    targets: vec![reward_target(1, 1)],
+                    value: output::Value::Percentage(output::Percentage {
+                        value: Decimal(100.0),
+                    }),
+                }],
+                discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+            }
+        );
+
+        // Two units are not enough for a set of three.
+        let input = input_with_units(config, &[(OUTERWEAR, "30.0", 1), (OUTERWEAR, "20.0", 1)]);
+        assert_eq!(run_function_with_input(run, &input)?.discounts, vec![]);
+        Ok(())
+    }
+
+    // Test 25: Buying from one collection rewards units from another.
+    #[test]
+    fn test_buy_x_get_y_across_collections() -> Result<()> {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1", "gid://shopify/Collection/2"], "buyXGetY": [
+            {"buy": {"collectionIds": ["gid://shopify/Collection/1"], "quantity": 2},
+             "get": {"collectionIds": ["gid://shopify/Collection/2"], "quantity": 1}, "percentage": 50}
+        ]}"#;
+        let input = input_with_units(config, &[(OUTERWEAR, "40.0", 2), (ACCESSORIES, "15.0", 3), (ACCESSORIES, "10.0", 1)]);
+        let result = run_function_with_input(run, &input)?;
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].targets, vec![reward_target(2, 1)]);
+        assert_eq!(result.discounts[0].message, Some("Buy 2, get 1 at 50% off.".to_string()));
+
+        let input = input_with_units(config, &[(OUTERWEAR, "40.0", 1), (ACCESSORIES, "10.0", 1)]);
+        assert_eq!(run_function_with_input(run, &input)?.discounts, vec![]);
+        Ok(())
+    }
+
+    // Test 26: Repeating offers reward one unit per complete set, the earlier line first on a price tie.
+    #[test]
+    fn test_buy_x_get_y_repeat_is_deterministic() -> Result<()> {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1"], "buyXGetY": [
+            {"buy": {"collectionIds": ["gid://shopify/Collection/1"], "quantity": 1}, "get": {"quantity": 1}, "percentage": 100, "repeat": true}
+        ]}"#;
+        let input = input_with_units(config, &[(OUTERWEAR, "10.0", 3), (OUTERWEAR, "10.0", 2)]);
+        let result = run_function_with_input(run, &input)?;
+        assert_eq!(result.discounts[0].targets, vec![reward_target(0, 2)]);
+
+        let input = input_with_units(config, &[(OUTERWEAR, "12.0", 3), (OUTERWEAR, "10.0", 2)]);
+        let result = run_function_with_input(run, &input)?;
+        assert_eq!(result.discounts[0].targets, vec![reward_target(1, 2)]);
+        Ok(())
+    }
+
+    // Test 27: Offers have to name listed collections, positive quantities and a percentage up to 100.
+    #[test]
+    fn test_buy_x_get_y_validation() {
+        let offer = |buy: &str, get: &str, percentage: f64| {
+            format!(
+                r#"{{"collectionIds": ["gid://shopify/Collection/1"], "buyXGetY": [{{"buy": {}, "get": {}, "percentage": {}}}]}}"#,
+                buy, get, percentage
+            )
+        };
+        let buy = r#"{"collectionIds": ["gid://shopify/Collection/1"], "quantity": 2}"#;
+        assert!(Configuration::from_str(&offer(buy, r#"{"quantity": 1}"#, 100.0)).is_ok());
+        assert_eq!(
+            Configuration::from_str(&offer(buy, r#"{"quantity": 0}"#, 100.0)),
+            Err(ConfigError::invalid("buyXGetY[0].get.quantity", "quantity 0 must be at least 1"))
+        );
+        assert!(Configuration::from_str(&offer(buy, r#"{"quantity": 1}"#, 120.0)).is_err());
+        assert!(Configuration::from_str(&offer(
+            buy,
+            r#"{"collectionIds": ["gid://shopify/Collection/3"], "quantity": 1}"#,
+            100.0
+        ))
+        .is_err());
+    }
+
+    // Test 28: The inclusion rules leave gift cards, subscriptions, free and custom lines out of the cart value.
+    #[test]
+    fn test_threshold_inclusion_rules() -> Result<()> {
+        let lines = [(OUTERWEAR, "60.0"), (ACCESSORIES, "50.0"), (ACCESSORIES, "30.0"), (ACCESSORIES, "0.0"), (ACCESSORIES, "40.0")];
+        let input_for = |config: &str| {
+            let mut input: serde_json::Value = serde_json::from_str(&input_with(Some(config), &lines)).unwrap();
+            input["cart"]["lines"][1]["merchandise"]["product"]["isGiftCard"] = true.into();
+            input["cart"]["lines"][2]["sellingPlanAllocation"] = serde_json::json!({ "sellingPlan": { "id": "gid://shopify/SellingPlan/1" } });
+            input["cart"]["lines"][4]["merchandise"] = serde_json::json!({ "__typename": "CustomProduct", "isGiftCard": false });
+            input.to_string()
+        };
+        let legacy = r#"{"collectionIds": ["gid://shopify/Collection/1"], "value": 10, "threshold": 100}"#;
+        let result = run_function_with_input(run, &input_for(legacy))?;
+        assert_eq!(result.discounts.len(), 1);
+
+        let strict = r#"{"collectionIds": ["gid://shopify/Collection/1"], "value": 10, "threshold": 100,
+            "thresholdInclusion": {"excludeGiftCards": true, "excludeSubscriptions": true, "excludeFreeLines": true, "excludeCustomLines": true}}"#;
+        assert_eq!(run_function_with_input(run, &input_for(strict))?.discounts, vec![]);
+
+        // Without the gift card rule the cart reaches 110.00 again.
+        let rules = r#"{"collectionIds": ["gid://shopify/Collection/1"], "rules": [{"collectionIds": ["gid://shopify/Collection/1"], "value": 10, "threshold": 100}],
+            "thresholdInclusion": {"excludeSubscriptions": true, "excludeCustomLines": true}}"#;
+        assert_eq!(run_function_with_input(run, &input_for(rules))?.discounts.len(), 1);
+        Ok(())
+    }
+
+    // Test 29: A rule with discount codes is rejected, as entered codes are not part of the input.
+    #[test]
+    fn test_rule_with_discount_codes_is_rejected() {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1"], "value": 5, "threshold": 0,
+            "discountCodes": [{"code": "VIP10", "percentage": 10}]}"#;
+        assert_eq!(
+            Configuration::from_str(config),
+            Err(ConfigError::invalid(
+                "rules[0].discountCodes",
+                "entered codes are not part of this API version's input, so the rule would never apply"
+            ))
+        );
+        assert!(Configuration::from_str(&config.replace(r#"{"code": "VIP10", "percentage": 10}"#, "")).is_ok());
+    }
+
+    // Test 30: Fixed amounts and thresholds are declared in the shop currency and converted
+    // with the presentment rate.
+    #[test]
+    fn test_fixed_amount_is_converted_to_cart_currency() -> Result<()> {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1"], "shopCurrency": "EUR",
+            "kind": "fixed_amount_per_unit", "value": 10, "threshold": 50}"#;
+        let input_for = |amount: &str| {
+            let mut input: serde_json::Value = serde_json::from_str(&input_with(Some(config), &[(OUTERWEAR, amount)])).unwrap();
+            input["presentmentCurrencyRate"] = "160.0".into();
+            input["cart"]["lines"][0]["cost"]["amountPerQuantity"]["currencyCode"] = "JPY".into();
+            input.to_string()
+        };
+        assert_eq!(
+            run_function_with_input(run, &input_for("10000"))?.discounts,
+            vec![output::Discount {
+                message: Some("1600 JPY off each eligible collection item.".to_string()),
+                targets: vec![cart_line_target(0)],
+                value: output::Value::FixedAmount(output::FixedAmount {
+                    amount: Decimal(1600.0),
+                    applies_to_each_item: Some(true),
+                }),
+            }]
+        );
+        // 50 EUR is 8000 JPY
+        assert_eq!(run_function_with_input(run, &input_for("7999"))?.discounts, vec![]);
+        Ok(())
+    }
+
+    // Test 31: The shop currency has to be an ISO 4217 code.
+    #[test]
+    fn test_shop_currency_must_be_a_currency_code() {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1"], "shopCurrency": "Euro", "rules": [{"collectionIds": ["gid://shopify/Collection/1"], "value": 10, "threshold": 80}]}"#;
+        assert_eq!(
+            Configuration::from_str(config),
+            Err(ConfigError::invalid("shopCurrency", "Euro is not an ISO 4217 currency code"))
+        );
+    }
+
+    fn usd_input(config: &str, rate: &str) -> String {
+        let mut input: serde_json::Value = serde_json::from_str(&input_with(Some(config), &[(OUTERWEAR, "100.0")])).unwrap();
+        input["presentmentCurrencyRate"] = rate.into();
+        input["cart"]["lines"][0]["cost"]["amountPerQuantity"]["currencyCode"] = "USD".into();
+        input.to_string()
+    }
+
+    // Test 32: A threshold that cannot be converted skips its rule instead of applying to every cart.
+    #[test]
+    fn test_unconvertible_threshold_skips_the_rule() -> Result<()> {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1"], "shopCurrency": "EUR",
+            "rules": [{"collectionIds": ["gid://shopify/Collection/1"], "value": 15, "threshold": 1e15}]}"#;
+        assert_eq!(run_function_with_input(run, &usd_input(config, "1000.0"))?.discounts, vec![]);
+
+        let too_large = config.replace("1e15", "1e17");
+        assert_eq!(
+            validate_config(&too_large),
+            Err("invalid value for rules[0].threshold: 100000000000000000 is too large an amount".to_string())
+        );
+        Ok(())
+    }
+
+    // Test 33: A fixed amount that cannot be converted leaves its lines to the later rules.
+    #[test]
+    fn test_unconvertible_amount_does_not_claim_lines() -> Result<()> {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1"], "shopCurrency": "EUR", "rules": [
+            {"collectionIds": ["gid://shopify/Collection/1"], "kind": "fixed_amount", "value": 1e15, "threshold": 0},
+            {"collectionIds": ["gid://shopify/Collection/1"], "value": 10, "threshold": 0}]}"#;
+        assert_eq!(
+            run_function_with_input(run, &usd_input(config, "1000.0"))?.discounts,
+            vec![percentage_discount(10.0, vec![cart_line_target(0)])]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,22 @@
+//! Payment customization: hides, renames or moves payment methods by customer
+//! tags, cart total, shipping country and cart collections.
+
+pub mod run;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    use discount_common::cli::{self, Usage};
+
+    let usage = Usage {
+        name: "customize-payement-tags",
+        metafield: "$app:payment_customization.config",
+        explains: "each rule matched or was skipped",
+    };
+    cli::main(&usage, run::evaluate, run::validate_config);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    eprintln!("Please invoke a named export.");
+    std::process::exit(1);
+}
@@ -0,0 +1,502 @@
+use shopify_function::prelude::*;
+use shopify_function::Result;
+use serde::Deserialize;
+use discount_common::collections::{self, CollectionMembership};
+use discount_common::config::{self, ConfigError};
+use discount_common::explain::Explanation;
+use discount_common::money::{self, Money, Rounding};
+use self::run::{input, output};
+
+/*
+ ------------------------------PAYMENT CUSTOMIZATION------------------------------
+            Read from the payment customization metafield `$app:payment_customization.config`:
+            customerTags = every tag the rules check. Passed to the input query as
+                            `$customerTags`.
+            collectionIds = every collection the rules check. Passed to the input
+                            query as `$collectionIds`.
+            shopCurrency = currency the cart totals are declared in, when its number of
+                            decimals differs from the buyer's currency.
+            rules = [{ paymentMethod, action, name, index, conditions }]
+                    `paymentMethod` matches every method whose name contains it,
+                    ignoring case. `action` is "hide", "rename" (to `name`) or "move"
+                    (to position `index`).
+                    conditions = { customerTags, withoutCustomerTags, minCartTotal,
+                                   maxCartTotal, shippingCountries, collectionIds }
+                            Every listed condition has to hold: the customer has one of
+                            `customerTags` and none of `withoutCustomerTags`, the cart
+                            total is within the bounds (shop currency), a delivery
+                            address is in one of `shippingCountries`, and a cart line
+                            is in one of `collectionIds`.
+            Rules apply in order and a hidden method is left alone by later rules.
+            Without the metafield the rule the JavaScript version hard-coded still
+            applies: cash on delivery is hidden unless the customer has one of its
+            tags, which are the input query's default `$customerTags`. As a
+            metafield value it reads:
+            {"customerTags": ["Testing_Payment_Customization", "restricted", "no_payment"],
+             "rules": [{"paymentMethod": "Cash on Delivery", "action": "hide", "conditions": {
+                "withoutCustomerTags": ["Testing_Payment_Customization", "restricted", "no_payment"]}}]}
+---------------------------------------------------------------------------------
+*/
+#[derive(Deserialize, Default, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Configuration {
+    #[serde(default)]
+    customer_tags: Vec<String>,
+    #[serde(default)]
+    collection_ids: Vec<String>,
+    #[serde(default)]
+    shop_currency: Option<String>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Rule {
+    payment_method: String,
+    #[serde(flatten)]
+    action: Action,
+    #[serde(default)]
+    conditions: Conditions,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Action {
+    Hide,
+    Rename { name: String },
+    Move { index: i64 },
+}
+
+#[derive(Deserialize, Default, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Conditions {
+    #[serde(default)]
+    customer_tags: Vec<String>,
+    #[serde(default)]
+    without_customer_tags: Vec<String>,
+    #[serde(default)]
+    min_cart_total: Option<f64>,
+    #[serde(default)]
+    max_cart_total: Option<f64>,
+    #[serde(default)]
+    shipping_countries: Vec<String>,
+    #[serde(default)]
+    collection_ids: Vec<String>,
+}
+
+// The tags of the legacy rule. Keep them in sync with the `$customerTags` default in
+// run.graphql, which is all the query checks without a metafield.
+const LEGACY_TAGS: [&str; 3] = ["Testing_Payment_Customization", "restricted", "no_payment"];
+
+impl Configuration {
+    // The rule of the JavaScript version, applied without a metafield.
+    fn legacy() -> Self {
+        let tags: Vec<String> = LEGACY_TAGS.iter().map(|tag| tag.to_string()).collect();
+        Configuration {
+            customer_tags: tags.clone(),
+            rules: vec![Rule {
+                payment_method: "Cash on Delivery".to_string(),
+                action: Action::Hide,
+                conditions: Conditions {
+                    without_customer_tags: tags,
+                    ..Conditions::default()
+                },
+            }],
+            ..Configuration::default()
+        }
+    }
+
+    fn from_str(value: &str) -> std::result::Result<Self, ConfigError> {
+        let config: Configuration = config::parse(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> std::result::Result<(), ConfigError> {
+        for (index, rule) in self.rules.iter().enumerate() {
+            let field = format!("rules[{}]", index);
+            if rule.payment_method.trim().is_empty() {
+                return Err(ConfigError::invalid(format!("{}.paymentMethod", field), "payment method name must not be empty"));
+            }
+            match &rule.action {
+                Action::Rename { name } if name.trim().is_empty() => {
+                    return Err(ConfigError::invalid(format!("{}.name", field), "new name must not be empty"));
+                }
+                Action::Move { index } if *index < 0 => {
+                    return Err(ConfigError::invalid(format!("{}.index", field), format!("{} must not be negative", index)));
+                }
+                _ => {}
+            }
+            self.validate_conditions(&rule.conditions, &format!("{}.conditions", field))?;
+        }
+        if let Some(currency) = &self.shop_currency {
+            money::validate_currency(currency).map_err(|reason| ConfigError::invalid("shopCurrency", reason))?;
+        }
+        Ok(())
+    }
+
+    fn validate_conditions(&self, conditions: &Conditions, field: &str) -> std::result::Result<(), ConfigError> {
+        let tags = [("customerTags", &conditions.customer_tags), ("withoutCustomerTags", &conditions.without_customer_tags)];
+        for (key, tags) in tags {
+            if let Some(tag) = tags.iter().find(|tag| !self.customer_tags.contains(tag)) {
+                return Err(ConfigError::invalid(
+                    format!("{}.{}", field, key),
+                    format!("{} is missing from customerTags, so it is never queried", tag),
+                ));
+            }
+        }
+        if let Some(id) = conditions.collection_ids.iter().find(|id| !self.collection_ids.contains(id)) {
+            return Err(ConfigError::invalid(
+                format!("{}.collectionIds", field),
+                format!("{} is missing from collectionIds, so its membership is never queried", id),
+            ));
+        }
+        if let Some(country) = conditions
+            .shipping_countries
+            .iter()
+            .find(|country| country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()))
+        {
+            return Err(ConfigError::invalid(
+                format!("{}.shippingCountries", field),
+                format!("{} is not an ISO 3166 country code", country),
+            ));
+        }
+        for (key, total) in [("minCartTotal", conditions.min_cart_total), ("maxCartTotal", conditions.max_cart_total)] {
+            if total.is_some_and(|total| !total.is_finite() || total < 0.0) {
+                return Err(ConfigError::invalid(format!("{}.{}", field, key), "must be a non-negative amount"));
+            }
+        }
+        if let (Some(min), Some(max)) = (conditions.min_cart_total, conditions.max_cart_total) {
+            if min > max {
+                return Err(ConfigError::invalid(format!("{}.minCartTotal", field), format!("{} is more than maxCartTotal {}", min, max)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CollectionMembership for input::InputCartLinesMerchandiseOnProductVariantProductInCollections {
+    fn collection_id(&self) -> &str {
+        &self.collection_id
+    }
+
+    fn is_member(&self) -> bool {
+        self.is_member
+    }
+}
+
+// What the rule conditions are checked against.
+struct Checkout<'a> {
+    tags: Vec<&'a str>,
+    total: Option<Money>,
+    countries: Vec<&'a str>,
+    lines: &'a [input::InputCartLines],
+    shop_currency: &'a str,
+    rate: f64,
+}
+
+impl Checkout<'_> {
+    fn has_product_in(&self, collection_ids: &[String]) -> bool {
+        self.lines.iter().any(|line| match &line.merchandise {
+            input::InputCartLinesMerchandise::ProductVariant(variant) => {
+                collections::is_member_of_any(&variant.product.in_collections, collection_ids)
+            }
+            _ => false,
+        })
+    }
+
+    // Why the conditions do not hold, or `None` when they all do.
+    fn unmet(&self, conditions: &Conditions) -> Option<String> {
+        let has_tag = |tags: &[String]| tags.iter().any(|tag| self.tags.contains(&tag.as_str()));
+        if !conditions.customer_tags.is_empty() && !has_tag(&conditions.customer_tags) {
+            return Some("the customer has none of its tags".to_string());
+        }
+        if has_tag(&conditions.without_customer_tags) {
+            return Some("the customer has an excluded tag".to_string());
+        }
+        if conditions.min_cart_total.is_some() || conditions.max_cart_total.is_some() {
+            let Some(total) = &self.total else {
+                return Some("the cart total is unknown".to_string());
+            };
+            let bound = |value, rounding| money::to_presentment(value, self.shop_currency, self.rate, total.currency(), rounding);
+            if let Some(min) = conditions.min_cart_total.and_then(|min| bound(min, Rounding::Up)) {
+                if *total < min {
+                    return Some(format!("the cart total is below {}", min));
+                }
+            }
+            if let Some(max) = conditions.max_cart_total.and_then(|max| bound(max, Rounding::Down)) {
+                if *total > max {
+                    return Some(format!("the cart total is above {}", max));
+                }
+            }
+        }
+        if !conditions.shipping_countries.is_empty()
+            && !self.countries.iter().any(|country| conditions.shipping_countries.iter().any(|wanted| wanted == country))
+        {
+            return Some("no delivery address is in its countries".to_string());
+        }
+        if !conditions.collection_ids.is_empty() && !self.has_product_in(&conditions.collection_ids) {
+            return Some("no cart line is in its collections".to_string());
+        }
+        None
+    }
+}
+
+fn operation(action: &Action, payment_method_id: &str) -> output::Operation {
+    let payment_method_id = payment_method_id.to_string();
+    match action {
+        Action::Hide => output::Operation::Hide(output::HideOperation { payment_method_id }),
+        Action::Rename { name } => output::Operation::Rename(output::RenameOperation {
+            name: name.clone(),
+            payment_method_id,
+        }),
+        Action::Move { index } => output::Operation::Move(output::MoveOperation {
+            index: *index,
+            payment_method_id,
+        }),
+    }
+}
+
+/// Check a `$app:payment_customization.config` metafield value, as the function would read it.
+pub(crate) fn validate_config(value: &str) -> std::result::Result<(), String> {
+    Configuration::from_str(value).map(|_| ()).map_err(|err| err.to_string())
+}
+
+#[shopify_function_target(query_path = "src/run.graphql", schema_path = "schema.graphql")]
+fn run(input: input::ResponseData) -> Result<output::FunctionRunResult> {
+    evaluate(input, &mut Explanation::default())
+}
+
+/// The body of `run`. Each decision is recorded in `explanation` for the `explain` command.
+pub(crate) fn evaluate(input: input::ResponseData, explanation: &mut Explanation) -> Result<output::FunctionRunResult> {
+    let no_changes = output::FunctionRunResult { operations: vec![] };
+
+    // 1. Parse the configuration. A broken one leaves the payment methods alone, a
+    //    missing one falls back to the legacy rule.
+    let config = match input.payment_customization.metafield {
+        Some(input::InputPaymentCustomizationMetafield { value }) => match Configuration::from_str(&value) {
+            Ok(config) => config,
+            Err(err) => {
+                err.log("no_changes");
+                explanation.note(|| format!("Configuration rejected, no changes: {}", err));
+                return Ok(no_changes);
+            }
+        },
+        None => {
+            explanation.note(|| "No configuration metafield, applying the legacy rule.".to_string());
+            Configuration::legacy()
+        }
+    };
+
+    // 2. Gather what the conditions check.
+    let cart = &input.cart;
+    let tags = cart
+        .buyer_identity
+        .as_ref()
+        .and_then(|buyer| buyer.customer.as_ref())
+        .map(|customer| {
+            customer
+                .has_tags
+                .iter()
+                .filter(|response| response.has_tag)
+                .map(|response| response.tag.as_str())
+                .collect()
+        })
+        .unwrap_or_default();
+    let total = &cart.cost.total_amount;
+    let checkout = Checkout {
+        tags,
+        total: Money::from_decimal(total.amount.0, &total.currency_code, Rounding::HalfUp),
+        countries: cart
+            .delivery_groups
+            .iter()
+            .filter_map(|group| group.delivery_address.as_ref()?.country_code.as_deref())
+            .collect(),
+        lines: &cart.lines,
+        shop_currency: config.shop_currency.as_deref().unwrap_or(&total.currency_code),
+        rate: input.presentment_currency_rate.0,
+    };
+
+    // 3. Apply the matching rules in order.
+    let mut hidden: Vec<&str> = vec![];
+    let mut operations = vec![];
+    for (index, rule) in config.rules.iter().enumerate() {
+        if let Some(reason) = checkout.unmet(&rule.conditions) {
+            explanation.note(|| format!("Rule {} skipped: {}.", index, reason));
+            continue;
+        }
+        let wanted = rule.payment_method.trim().to_lowercase();
+        let methods: Vec<_> = input
+            .payment_methods
+            .iter()
+            .filter(|method| method.name.to_lowercase().contains(&wanted) && !hidden.contains(&method.id.as_str()))
+            .collect();
+        if methods.is_empty() {
+            explanation.note(|| format!("Rule {} matches no visible payment method named {}.", index, rule.payment_method));
+            continue;
+        }
+        for method in methods {
+            explanation.note(|| format!("Rule {} applies {:?} to {}.", index, rule.action, method.name));
+            if rule.action == Action::Hide {
+                hidden.push(&method.id);
+            }
+            operations.push(operation(&rule.action, &method.id));
+        }
+    }
+
+    Ok(output::FunctionRunResult { operations })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shopify_function::{run_function_with_input, Result};
+    use discount_common::cart::test_support;
+    use crate::run::run::output;
+
+    const COD: &str = "gid://shopify/PaymentCustomizationPaymentMethod/3";
+    const CARD: &str = "gid://shopify/PaymentCustomizationPaymentMethod/1";
+    const LEGACY: &str = r#"{
+        "customerTags": ["Testing_Payment_Customization", "restricted", "no_payment"],
+        "rules": [{"paymentMethod": "Cash on Delivery", "action": "hide", "conditions": {
+            "withoutCustomerTags": ["Testing_Payment_Customization", "restricted", "no_payment"]}}]
+    }"#;
+
+    // A US$`total` cart shipping to `country`, with one line in Collection/1 or not.
+    fn input_with(config: Option<&str>, tags: Option<&[&str]>, total: &str, country: &str, in_collection: bool) -> String {
+        let queried = ["Testing_Payment_Customization", "restricted", "no_payment", "wholesale"];
+        let fields = serde_json::json!({
+            "paymentMethods": [
+                { "id": CARD, "name": "Credit card" },
+                { "id": COD, "name": "Cash on Delivery (COD)" }
+            ],
+            "cart": {
+                "cost": { "totalAmount": test_support::money(total, "USD") },
+                "buyerIdentity": { "customer": test_support::customer(tags, &queried) },
+                "deliveryGroups": [{ "deliveryAddress": { "countryCode": country } }],
+                "lines": [{
+                    "merchandise": {
+                        "__typename": "ProductVariant",
+                        "product": {
+                            "inCollections": test_support::in_collection("gid://shopify/Collection/1", in_collection)
+                        }
+                    }
+                }]
+            }
+        });
+        test_support::input("paymentCustomization", config, fields)
+    }
+
+    fn operations(input: &str) -> Result<Vec<output::Operation>> {
+        Ok(run_function_with_input(run, input)?.operations)
+    }
+
+    fn hide(id: &str) -> output::Operation {
+        output::Operation::Hide(output::HideOperation {
+            payment_method_id: id.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_legacy_rule_hides_cash_on_delivery_unless_tagged() -> Result<()> {
+        assert_eq!(operations(&input_with(Some(LEGACY), None, "50.00", "US", false))?, vec![hide(COD)]);
+        assert_eq!(operations(&input_with(Some(LEGACY), Some(&[]), "50.00", "US", false))?, vec![hide(COD)]);
+        assert_eq!(operations(&input_with(Some(LEGACY), Some(&["restricted"]), "50.00", "US", false))?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_and_move() -> Result<()> {
+        let config = r#"{"customerTags": ["wholesale"], "rules": [
+            {"paymentMethod": "cash on delivery", "action": "rename", "name": "Pay on delivery"},
+            {"paymentMethod": "Cash", "action": "move", "index": 0, "conditions": {"customerTags": ["wholesale"]}}
+        ]}"#;
+        let rename = output::Operation::Rename(output::RenameOperation {
+            name: "Pay on delivery".to_string(),
+            payment_method_id: COD.to_string(),
+        });
+        let first = output::Operation::Move(output::MoveOperation {
+            index: 0,
+            payment_method_id: COD.to_string(),
+        });
+        assert_eq!(operations(&input_with(Some(config), Some(&["wholesale"]), "50.00", "US", false))?, vec![rename.clone(), first]);
+        assert_eq!(operations(&input_with(Some(config), None, "50.00", "US", false))?, vec![rename]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cart_total_bounds() -> Result<()> {
+        let config = r#"{"rules": [{"paymentMethod": "Cash on Delivery", "action": "hide", "conditions": {"minCartTotal": 500}}]}"#;
+        assert_eq!(operations(&input_with(Some(config), None, "499.99", "US", false))?, vec![]);
+        assert_eq!(operations(&input_with(Some(config), None, "500.00", "US", false))?, vec![hide(COD)]);
+        let config = r#"{"rules": [{"paymentMethod": "Credit card", "action": "hide", "conditions": {"maxCartTotal": 1}}]}"#;
+        assert_eq!(operations(&input_with(Some(config), None, "0.50", "US", false))?, vec![hide(CARD)]);
+        assert_eq!(operations(&input_with(Some(config), None, "1.01", "US", false))?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shipping_country_and_collection_conditions() -> Result<()> {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/1"], "rules": [{
+            "paymentMethod": "Cash on Delivery", "action": "hide",
+            "conditions": {"shippingCountries": ["DE", "FR"], "collectionIds": ["gid://shopify/Collection/1"]}
+        }]}"#;
+        assert_eq!(operations(&input_with(Some(config), None, "50.00", "FR", true))?, vec![hide(COD)]);
+        assert_eq!(operations(&input_with(Some(config), None, "50.00", "US", true))?, vec![]);
+        assert_eq!(operations(&input_with(Some(config), None, "50.00", "FR", false))?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hidden_methods_are_left_alone() -> Result<()> {
+        let config = r#"{"rules": [
+            {"paymentMethod": "Cash on Delivery", "action": "hide"},
+            {"paymentMethod": "Cash on Delivery", "action": "hide"},
+            {"paymentMethod": "Cash on Delivery", "action": "rename", "name": "COD"}
+        ]}"#;
+        assert_eq!(operations(&input_with(Some(config), None, "50.00", "US", false))?, vec![hide(COD)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_config_applies_the_legacy_rule() -> Result<()> {
+        assert_eq!(operations(&input_with(None, None, "50.00", "US", false))?, vec![hide(COD)]);
+        assert_eq!(operations(&input_with(None, Some(&["wholesale"]), "50.00", "US", false))?, vec![hide(COD)]);
+        assert_eq!(operations(&input_with(None, Some(&["no_payment"]), "50.00", "US", false))?, vec![]);
+        assert_eq!(Configuration::legacy(), Configuration::from_str(LEGACY).unwrap());
+        // Without a metafield the query only checks its default tags
+        let query = include_str!("run.graphql");
+        assert!(LEGACY_TAGS.iter().all(|tag| query.contains(&format!("\"{}\"", tag))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_broken_config_changes_nothing() -> Result<()> {
+        assert_eq!(operations(&input_with(Some("not json"), None, "50.00", "US", false))?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert_eq!(validate_config(LEGACY), Ok(()));
+        assert!(validate_config(r#"{"rules": [{"paymentMethod": "COD", "action": "hide", "conditions": {"customerTags": ["vip"]}}]}"#)
+            .unwrap_err()
+            .contains("rules[0].conditions.customerTags"));
+        assert!(validate_config(r#"{"rules": [{"paymentMethod": "COD", "action": "rename", "name": " "}]}"#)
+            .unwrap_err()
+            .contains("rules[0].name"));
+        assert!(validate_config(r#"{"rules": [{"paymentMethod": "COD", "action": "move", "index": -1}]}"#).is_err());
+        assert!(validate_config(r#"{"rules": [{"paymentMethod": "COD", "action": "disable"}]}"#).is_err());
+        assert!(validate_config(r#"{"rules": [{"paymentMethod": "COD", "action": "hide", "conditions": {"shippingCountries": ["usa"]}}]}"#)
+            .is_err());
+    }
+
+    #[test]
+    fn test_explain_payment_customization() {
+        let mut explanation = Explanation::enabled();
+        let input = serde_json::from_str(&input_with(Some(LEGACY), Some(&["no_payment"]), "50.00", "US", false)).unwrap();
+        evaluate(input, &mut explanation).unwrap();
+        assert!(explanation.notes().contains(&"Rule 0 skipped: the customer has an excluded tag.".to_string()));
+    }
+}
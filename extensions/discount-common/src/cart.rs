@@ -0,0 +1,240 @@
+use serde::{Deserialize, Serialize};
+use crate::collections::{self, CollectionMembership};
+use crate::money::{Money, Rounding};
+
+/// The parts of a cart line every discount function queries.
+pub trait CartLine {
+    type Membership: CollectionMembership;
+
+    fn quantity(&self) -> i64;
+    fn amount_per_quantity(&self) -> f64;
+    fn currency_code(&self) -> &str;
+    /// The product's `inCollections` response, `None` when the merchandise is not a product variant.
+    fn in_collections(&self) -> Option<&[Self::Membership]>;
+    /// Whether the merchandise is a gift card.
+    fn is_gift_card(&self) -> bool;
+    /// Whether the line is bought with a selling plan, e.g. a subscription.
+    fn is_subscription(&self) -> bool;
+}
+
+/// Lines left out of threshold totals, on top of each function's own exclusions.
+/// Every rule is off by default so existing configurations keep their totals.
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ThresholdInclusion {
+    pub exclude_gift_cards: bool,
+    pub exclude_subscriptions: bool,
+    /// Lines that already cost nothing.
+    pub exclude_free_lines: bool,
+    /// Custom line items, i.e. merchandise that is not a product variant.
+    pub exclude_custom_lines: bool,
+}
+
+impl ThresholdInclusion {
+    /// Why `line` does not count toward a threshold, `None` when it does.
+    pub fn exclusion<L: CartLine>(&self, line: &L) -> Option<&'static str> {
+        if self.exclude_custom_lines && line.in_collections().is_none() {
+            Some("a custom line item")
+        } else if self.exclude_gift_cards && line.is_gift_card() {
+            Some("a gift card")
+        } else if self.exclude_subscriptions && line.is_subscription() {
+            Some("a subscription")
+        } else if self.exclude_free_lines && line.amount_per_quantity() <= 0.0 {
+            Some("free")
+        } else {
+            None
+        }
+    }
+
+    pub fn includes<L: CartLine>(&self, line: &L) -> bool {
+        self.exclusion(line).is_none()
+    }
+}
+
+/// Why `line` does not count toward a threshold that leaves out the `excluded`
+/// collections, `None` when it does. Custom line items are in no collection and never count.
+pub fn threshold_exclusion<L: CartLine>(line: &L, excluded: &[String], inclusion: &ThresholdInclusion) -> Option<&'static str> {
+    let in_collections = match line.in_collections() {
+        Some(in_collections) => in_collections,
+        None => return Some("a custom line item"),
+    };
+    if collections::is_member_of_any(in_collections, excluded) {
+        return Some("in an excluded collection");
+    }
+    inclusion.exclusion(line)
+}
+
+/// The cart currency, taken from the first line. Every line of a cart shares it.
+pub fn currency<L: CartLine>(lines: &[L]) -> String {
+    lines.first().map(|line| line.currency_code().to_string()).unwrap_or_default()
+}
+
+/// Unit price in exact minor units.
+pub fn unit_price<L: CartLine>(line: &L) -> Option<Money> {
+    Money::from_decimal(line.amount_per_quantity(), line.currency_code(), Rounding::HalfEven)
+}
+
+/// Unit price times quantity. The unit price is converted before multiplying so no cents get lost.
+pub fn line_value<L: CartLine>(line: &L) -> Option<Money> {
+    Some(unit_price(line)?.times(line.quantity()))
+}
+
+/// Sum the value of the lines accepted by `include`. Lines with an unreadable price are skipped.
+pub fn total<L: CartLine>(lines: &[L], mut include: impl FnMut(&L) -> bool) -> Money {
+    let mut total = Money::zero(&currency(lines));
+    for line in lines.iter().filter(|line| include(line)) {
+        if let Some(sum) = line_value(line).and_then(|value| total.checked_add(&value)) {
+            total = sum;
+        }
+    }
+    total
+}
+
+/// JSON builders for the function inputs the unit tests run on. Functions enable
+/// them through the `test-support` feature of their dev-dependency.
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support {
+    use serde_json::{json, Value};
+
+    /// A function input with a 1.0 presentment rate, the configuration metafield on
+    /// `owner` (e.g. `discountNode`) and the other top-level `fields`.
+    pub fn input(owner: &str, config: Option<&str>, fields: Value) -> String {
+        let mut input = json!({
+            "presentmentCurrencyRate": "1.0",
+            owner: { "metafield": metafield(config) },
+        });
+        if let (Some(input), Value::Object(fields)) = (input.as_object_mut(), fields) {
+            input.extend(fields);
+        }
+        input.to_string()
+    }
+
+    /// A `metafield { value }` response, `null` without a value.
+    pub fn metafield(value: Option<&str>) -> Value {
+        value.map_or(Value::Null, |value| json!({ "value": value }))
+    }
+
+    /// A `MoneyV2` response.
+    pub fn money(amount: &str, currency: &str) -> Value {
+        json!({ "amount": amount, "currencyCode": currency })
+    }
+
+    /// An `inCollections` response for a single queried collection.
+    pub fn in_collection(collection: &str, is_member: bool) -> Value {
+        json!([{ "collectionId": collection, "isMember": is_member }])
+    }
+
+    /// A `buyerIdentity.customer` answering `hasTags` for each `queried` tag, `null`
+    /// for a guest.
+    pub fn customer(tags: Option<&[&str]>, queried: &[&str]) -> Value {
+        tags.map_or(Value::Null, |tags| {
+            let responses: Vec<Value> = queried
+                .iter()
+                .map(|tag| json!({ "tag": tag, "hasTag": tags.contains(tag) }))
+                .collect();
+            json!({ "hasTags": responses })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Line {
+        quantity: i64,
+        amount: f64,
+        gift_card: bool,
+        subscription: bool,
+        memberships: Option<Vec<(String, bool)>>,
+    }
+
+    impl CartLine for Line {
+        type Membership = (String, bool);
+
+        fn quantity(&self) -> i64 {
+            self.quantity
+        }
+
+        fn amount_per_quantity(&self) -> f64 {
+            self.amount
+        }
+
+        fn currency_code(&self) -> &str {
+            "EUR"
+        }
+
+        fn in_collections(&self) -> Option<&[(String, bool)]> {
+            self.memberships.as_deref()
+        }
+
+        fn is_gift_card(&self) -> bool {
+            self.gift_card
+        }
+
+        fn is_subscription(&self) -> bool {
+            self.subscription
+        }
+    }
+
+    impl CollectionMembership for (String, bool) {
+        fn collection_id(&self) -> &str {
+            &self.0
+        }
+
+        fn is_member(&self) -> bool {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_total_is_exact() {
+        let lines = [
+            Line { quantity: 7, amount: 17.65, ..Line::default() },
+            Line { quantity: 1, amount: 26.45, ..Line::default() },
+        ];
+        assert_eq!(total(&lines, |_| true), Money::from_minor_units(15000, "EUR"));
+        assert_eq!(total(&lines, |line| line.quantity == 1), Money::from_minor_units(2645, "EUR"));
+    }
+
+    #[test]
+    fn test_empty_cart_total_is_zero() {
+        let lines: [Line; 0] = [];
+        assert!(total(&lines, |_| true).is_zero());
+    }
+
+    #[test]
+    fn test_threshold_inclusion_rules() {
+        let variant = |line: Line| Line { memberships: Some(vec![]), ..line };
+        let lines = [
+            variant(Line { quantity: 1, amount: 10.0, ..Line::default() }),
+            variant(Line { quantity: 1, amount: 25.0, gift_card: true, ..Line::default() }),
+            variant(Line { quantity: 2, amount: 5.0, subscription: true, ..Line::default() }),
+            variant(Line { quantity: 3, amount: 0.0, ..Line::default() }),
+            Line { quantity: 1, amount: 4.0, ..Line::default() },
+        ];
+        let everything = ThresholdInclusion::default();
+        assert_eq!(total(&lines, |line| everything.includes(line)), Money::from_minor_units(4900, "EUR"));
+
+        let strict = ThresholdInclusion {
+            exclude_gift_cards: true,
+            exclude_subscriptions: true,
+            exclude_free_lines: true,
+            exclude_custom_lines: true,
+        };
+        assert_eq!(total(&lines, |line| strict.includes(line)), Money::from_minor_units(1000, "EUR"));
+        assert_eq!(strict.exclusion(&lines[1]), Some("a gift card"));
+        assert_eq!(strict.exclusion(&lines[4]), Some("a custom line item"));
+
+        let excluded = ["gid://shopify/Collection/1".to_string()];
+        let in_excluded = Line {
+            memberships: Some(vec![(excluded[0].clone(), true)]),
+            ..Line::default()
+        };
+        assert_eq!(threshold_exclusion(&in_excluded, &excluded, &everything), Some("in an excluded collection"));
+        assert_eq!(threshold_exclusion(&lines[4], &[], &everything), Some("a custom line item"));
+        assert_eq!(threshold_exclusion(&lines[1], &excluded, &everything), None);
+        assert_eq!(threshold_exclusion(&lines[1], &excluded, &strict), Some("a gift card"));
+    }
+}
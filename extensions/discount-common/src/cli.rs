@@ -0,0 +1,147 @@
+use std::io::Read;
+use std::{env, fmt, fs, io, process};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::explain::Explanation;
+
+/*
+ ----------------------------------CLI--------------------------------------
+            The native driver behind every function binary. In checkout a
+            function is invoked through its named export, the binary itself is
+            only used locally to run, lint and explain inputs:
+                <function> run [file]              print the FunctionRunResult
+                <function> validate-config [file]  check a metafield value
+                <function> explain [file]          print the decisions, then the result
+            Each command reads from the given file, or from stdin.
+---------------------------------------------------------------------------
+*/
+
+/// What a function binary prints in its usage text.
+pub struct Usage<'a> {
+    /// The binary name, e.g. `product-discount`.
+    pub name: &'a str,
+    /// The configuration metafield checked by `validate-config`.
+    pub metafield: &'a str,
+    /// What `explain` prints, completing "print why ...".
+    pub explains: &'a str,
+}
+
+impl fmt::Display for Usage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Usage: {} <command> [file]\n\n\
+             Commands:\n  \
+             run               Run the function on an input JSON and print the FunctionRunResult\n  \
+             validate-config   Check a `{}` metafield value\n  \
+             explain           Run the function and print why {}\n\n\
+             Reads from stdin when no file is given.",
+            self.name, self.metafield, self.explains
+        )
+    }
+}
+
+/// Run the command given on the command line with a function's `evaluate` and
+/// `validate_config`, then exit.
+pub fn main<I, O, E>(
+    usage: &Usage,
+    evaluate: fn(I, &mut Explanation) -> Result<O, E>,
+    validate_config: fn(&str) -> Result<(), String>,
+) -> !
+where
+    I: DeserializeOwned,
+    O: Serialize,
+    E: fmt::Display,
+{
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, path) = match args.as_slice() {
+        [command] => (command.as_str(), None),
+        [command, path] => (command.as_str(), Some(path.as_str())),
+        _ => exit_with_usage(usage),
+    };
+
+    if !matches!(command, "run" | "validate-config" | "explain") {
+        exit_with_usage(usage);
+    }
+
+    let contents = match read_input(path) {
+        Ok(contents) => contents,
+        Err(err) => fail(&format!("Could not read input: {}", err)),
+    };
+
+    match command {
+        "run" => print_result(evaluate(parse_input(&contents), &mut Explanation::default())),
+        "validate-config" => match validate_config(contents.trim()) {
+            Ok(()) => println!("Configuration is valid."),
+            Err(err) => fail(&err),
+        },
+        _ => {
+            let mut explanation = Explanation::enabled();
+            let result = evaluate(parse_input(&contents), &mut explanation);
+            for note in explanation.notes() {
+                println!("- {}", note);
+            }
+            print_result(result);
+        }
+    }
+    process::exit(0);
+}
+
+fn read_input(path: Option<&str>) -> io::Result<String> {
+    match path {
+        Some(path) => fs::read_to_string(path),
+        None => {
+            let mut contents = String::new();
+            io::stdin().read_to_string(&mut contents)?;
+            Ok(contents)
+        }
+    }
+}
+
+fn parse_input<I: DeserializeOwned>(contents: &str) -> I {
+    serde_json::from_str(contents).unwrap_or_else(|err| fail(&format!("Invalid input JSON: {}", err)))
+}
+
+fn print_result<O: Serialize, E: fmt::Display>(result: Result<O, E>) {
+    match result {
+        Ok(output) => println!("{}", serde_json::to_string_pretty(&output).unwrap()),
+        Err(err) => fail(&err.to_string()),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn exit_with_usage(usage: &Usage) -> ! {
+    eprintln!("{}", usage);
+    process::exit(2);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_text() {
+        let usage = Usage {
+            name: "tag-discounts",
+            metafield: "$app:tag_discounts.config",
+            explains: "each tag discount was chosen or skipped",
+        };
+        assert_eq!(
+            usage.to_string(),
+            "Usage: tag-discounts <command> [file]
+
+Commands:
+  run               Run the function on an input JSON and print the FunctionRunResult
+  validate-config   Check a `$app:tag_discounts.config` metafield value
+  explain           Run the function and print why each tag discount was chosen or skipped
+
+Reads from stdin when no file is given."
+        );
+    }
+}
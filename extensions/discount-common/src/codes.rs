@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+
+/*
+ ------------------------------DISCOUNT CODES---------------------------------
+            Codes are managed in app.discount.jsx, where the last two characters
+            of a code have to be the digits of its percentage (SUMMER15 -> 15%).
+            The same rule is enforced again when a function parses its config.
+            The discount function input of this API version does not list the
+            codes the buyer entered, and a cart attribute is set by the buyer's
+            own client, so it cannot stand in for them. Until the functions move
+            to an API version with entered codes, a configuration whose bands or
+            rules list codes is rejected.
+---------------------------------------------------------------------------
+*/
+
+/// A code that unlocks a discount at its own percentage.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct DiscountCode {
+    pub code: String,
+    pub percentage: f64,
+}
+
+impl DiscountCode {
+    /// The `validateDiscountCode` rule: at least 3 characters, where `parseInt` of the
+    /// last two gives a whole percentage below 100. Like in the admin, `VIP+5` and
+    /// `VIP5X` both carry 5%. Lengths count UTF-16 units, as JavaScript strings do.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.percentage.fract() != 0.0 || !(0.0..100.0).contains(&self.percentage) {
+            return Err(format!("percentage {} must be a whole number below 100", self.percentage));
+        }
+        let units: Vec<u16> = self.code.encode_utf16().collect();
+        if units.len() < 3 {
+            return Err(format!("code {} must have at least 3 characters", self.code));
+        }
+        let suffix = String::from_utf16_lossy(&units[units.len() - 2..]);
+        match parse_int(&suffix) {
+            Some(digits) if digits as f64 == self.percentage => Ok(()),
+            _ => Err(format!(
+                "code {} must end in the two digits of its percentage {}",
+                self.code, self.percentage
+            )),
+        }
+    }
+}
+
+// JavaScript's `parseInt(text, 10)`: leading whitespace, an optional sign, then the
+// digits up to the first other character. `None` where it gives `NaN`.
+fn parse_int(text: &str) -> Option<i64> {
+    let text = text.trim_start_matches(|c: char| c.is_whitespace() || c == '\u{feff}');
+    let (sign, rest) = match text.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+    digits.parse::<i64>().ok().map(|value| sign * value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(code: &str, percentage: f64) -> DiscountCode {
+        DiscountCode {
+            code: code.to_string(),
+            percentage,
+        }
+    }
+
+    #[test]
+    fn test_validate_matches_the_admin_rule() {
+        assert!(code("SUMMER15", 15.0).validate().is_ok());
+        assert!(code("VIP05", 5.0).validate().is_ok());
+        assert!(code("SUMMER15", 20.0).validate().is_err());
+        assert!(code("15", 15.0).validate().is_err());
+        assert!(code("SALE5", 5.0).validate().is_err());
+        assert!(code("SALE100", 100.0).validate().is_err());
+        assert!(code("SALE12", 12.5).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_reads_the_suffix_like_parse_int() {
+        assert!(code("VIP+5", 5.0).validate().is_ok());
+        assert!(code("VIP 5", 5.0).validate().is_ok());
+        assert!(code("VIP5X", 5.0).validate().is_ok());
+        assert!(code("VIP-5", 5.0).validate().is_err());
+        assert!(code("VIPX5", 5.0).validate().is_err());
+        assert!(code("VIP+5", 15.0).validate().is_err());
+        assert_eq!(parse_int(" 7"), Some(7));
+        assert_eq!(parse_int("-3"), Some(-3));
+        assert_eq!(parse_int("+"), None);
+        assert_eq!(parse_int("x1"), None);
+    }
+}
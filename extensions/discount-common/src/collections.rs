@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+/// One entry of a product's `inCollections(ids: ...)` response.
+pub trait CollectionMembership {
+    fn collection_id(&self) -> &str;
+    fn is_member(&self) -> bool;
+}
+
+/// `inCollections` returns an entry for every queried id, so membership
+/// always has to be read from `isMember`, never from the entry being present.
+pub fn is_member_of<M: CollectionMembership>(memberships: &[M], collection_id: &str) -> bool {
+    memberships
+        .iter()
+        .any(|membership| membership.is_member() && membership.collection_id() == collection_id)
+}
+
+pub fn is_member_of_any<M: CollectionMembership>(memberships: &[M], collection_ids: &[String]) -> bool {
+    collection_ids.iter().any(|id| is_member_of(memberships, id))
+}
+
+/// How a rule with several collections matches a product.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CollectionMatch {
+    /// The product is a member of at least one of the collections.
+    #[default]
+    MatchAny,
+    /// The product is a member of every collection.
+    MatchAll,
+}
+
+impl CollectionMatch {
+    /// An empty list of collections never matches.
+    pub fn matches<M: CollectionMembership>(&self, memberships: &[M], collection_ids: &[String]) -> bool {
+        match self {
+            CollectionMatch::MatchAny => is_member_of_any(memberships, collection_ids),
+            CollectionMatch::MatchAll => {
+                !collection_ids.is_empty() && collection_ids.iter().all(|id| is_member_of(memberships, id))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Membership(&'static str, bool);
+
+    impl CollectionMembership for Membership {
+        fn collection_id(&self) -> &str {
+            self.0
+        }
+
+        fn is_member(&self) -> bool {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_membership_requires_is_member() {
+        let memberships = [Membership("gid://shopify/Collection/1", true), Membership("gid://shopify/Collection/2", false)];
+        assert!(is_member_of(&memberships, "gid://shopify/Collection/1"));
+        assert!(!is_member_of(&memberships, "gid://shopify/Collection/2"));
+        assert!(!is_member_of(&memberships, "gid://shopify/Collection/3"));
+        assert!(is_member_of_any(&memberships, &["gid://shopify/Collection/2".to_string(), "gid://shopify/Collection/1".to_string()]));
+        assert!(!is_member_of_any(&memberships, &["gid://shopify/Collection/2".to_string()]));
+    }
+
+    #[test]
+    fn test_collection_match_modes() {
+        let memberships = [Membership("gid://shopify/Collection/1", true), Membership("gid://shopify/Collection/2", false)];
+        let one = ["gid://shopify/Collection/1".to_string()];
+        let both = ["gid://shopify/Collection/1".to_string(), "gid://shopify/Collection/2".to_string()];
+        assert!(CollectionMatch::MatchAny.matches(&memberships, &both));
+        assert!(!CollectionMatch::MatchAll.matches(&memberships, &both));
+        assert!(CollectionMatch::MatchAll.matches(&memberships, &one));
+        assert!(!CollectionMatch::MatchAll.matches(&memberships, &[]));
+        assert!(!CollectionMatch::MatchAny.matches(&memberships, &[]));
+    }
+}
@@ -0,0 +1,122 @@
+use serde::de::DeserializeOwned;
+use std::fmt;
+
+use crate::explain::Explanation;
+
+/// Why a configuration metafield was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The metafield value is not valid JSON for the configuration shape.
+    Parse(String),
+    /// A field parsed but holds a value the function cannot use.
+    InvalidValue { field: String, reason: String },
+}
+
+impl ConfigError {
+    pub fn invalid(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        ConfigError::InvalidValue {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConfigError::Parse(_) => "config_parse",
+            ConfigError::InvalidValue { .. } => "invalid_value",
+        }
+    }
+
+    pub fn log(&self, fallback: &str) {
+        log_warning(self.kind(), &self.to_string(), fallback);
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Parse(reason) => {
+                write!(f, "unable to parse configuration value from metafield: {}", reason)
+            }
+            ConfigError::InvalidValue { field, reason } => {
+                write!(f, "invalid value for {}: {}", field, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Deserialize a metafield value, mapping serde errors to `ConfigError::Parse`.
+pub fn parse<T: DeserializeOwned>(value: &str) -> Result<T, ConfigError> {
+    serde_json::from_str(value).map_err(|err| ConfigError::Parse(err.to_string()))
+}
+
+/// Read a metafield holding a JSON list, such as the lists the admin keeps for the app,
+/// paired with their `key[index]` field names. Entries that do not parse are logged and
+/// skipped so one bad entry never blocks the others. A missing metafield is an empty list.
+pub fn parse_entries<T: DeserializeOwned>(
+    value: Option<&str>,
+    key: &str,
+    explanation: &mut Explanation,
+) -> Vec<(String, T)> {
+    let values: Vec<serde_json::Value> = match value.map(parse).transpose() {
+        Ok(values) => values.unwrap_or_default(),
+        Err(err) => {
+            err.log("skip_metafield");
+            explanation.note(|| format!("{} skipped: {}", key, err));
+            return vec![];
+        }
+    };
+    let mut entries = vec![];
+    for (index, value) in values.into_iter().enumerate() {
+        let field = format!("{}[{}]", key, index);
+        match serde_json::from_value(value) {
+            Ok(entry) => entries.push((field, entry)),
+            Err(err) => {
+                let err = ConfigError::invalid(field, err.to_string());
+                err.log("skip_entry");
+                explanation.note(|| format!("Entry skipped: {}", err));
+            }
+        }
+    }
+    entries
+}
+
+// Write a single JSON line to stderr so the function run logs stay greppable.
+pub fn log_warning(kind: &str, detail: &str, fallback: &str) {
+    eprintln!(
+        "{}",
+        serde_json::json!({
+            "level": "warn",
+            "error": kind,
+            "detail": detail,
+            "fallback": fallback,
+        })
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(parse::<Vec<u32>>("{"), Err(ConfigError::Parse(_))));
+        assert_eq!(parse::<Vec<u32>>("[1, 2]"), Ok(vec![1, 2]));
+        assert_eq!(
+            ConfigError::invalid("rules[0].value", "must not be negative").to_string(),
+            "invalid value for rules[0].value: must not be negative"
+        );
+    }
+
+    #[test]
+    fn test_parse_entries_skips_bad_entries() {
+        let mut explanation = Explanation::enabled();
+        let entries: Vec<(String, u32)> = parse_entries(Some(r#"[1, "two", 3]"#), "tiers", &mut explanation);
+        assert_eq!(entries, vec![("tiers[0]".to_string(), 1), ("tiers[2]".to_string(), 3)]);
+        assert!(explanation.notes()[0].starts_with("Entry skipped: invalid value for tiers[1]"));
+        assert!(parse_entries::<u32>(Some("{"), "tiers", &mut explanation).is_empty());
+        assert!(parse_entries::<u32>(None, "tiers", &mut explanation).is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+/*
+ --------------------------------EXPLAIN------------------------------------
+            Collects a human-readable trace of the decisions made during a run,
+            for the `explain` command of the local CLI. In checkout the
+            explanation is disabled and notes are never formatted.
+---------------------------------------------------------------------------
+*/
+
+#[derive(Default, Debug)]
+pub struct Explanation {
+    notes: Option<Vec<String>>,
+}
+
+impl Explanation {
+    pub fn enabled() -> Self {
+        Explanation { notes: Some(vec![]) }
+    }
+
+    /// Record a note. The closure only runs when the explanation is enabled.
+    pub fn note(&mut self, note: impl FnOnce() -> String) {
+        if let Some(notes) = &mut self.notes {
+            notes.push(note());
+        }
+    }
+
+    pub fn notes(&self) -> &[String] {
+        self.notes.as_deref().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_explanation_skips_notes() {
+        let mut explanation = Explanation::default();
+        explanation.note(|| unreachable!("notes are not formatted when disabled"));
+        assert!(explanation.notes().is_empty());
+    }
+
+    #[test]
+    fn test_enabled_explanation_keeps_notes_in_order() {
+        let mut explanation = Explanation::enabled();
+        explanation.note(|| "first".to_string());
+        explanation.note(|| "second".to_string());
+        assert_eq!(explanation.notes(), ["first".to_string(), "second".to_string()]);
+    }
+}
@@ -0,0 +1,21 @@
+/*
+ ------------------------------DISCOUNT COMMON--------------------------------
+            Discount-domain helpers shared by the Rust functions in this app.
+            Each function generates its own input types from its query, so
+            cart lines are read through the traits in `cart` and every function
+            implements them for its generated types.
+            Everything here ends up in size-optimized Wasm builds: keep the
+            dependencies to serde, serde_json and shopify_function.
+---------------------------------------------------------------------------
+*/
+
+pub mod cart;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cli;
+pub mod codes;
+pub mod collections;
+pub mod config;
+pub mod explain;
+pub mod money;
+pub mod schedule;
+pub mod strategy;
@@ -0,0 +1,387 @@
+use shopify_function::scalars::Decimal;
+use std::cmp::Ordering;
+use std::fmt;
+
+/*
+ ---------------------------------MONEY-------------------------------------
+            Amounts are stored as integer minor units (cents for EUR/USD) plus
+            the ISO currency code, so totals and threshold comparisons are exact.
+            Converting from the f64 values Shopify hands us always goes through
+            an explicit rounding mode.
+---------------------------------------------------------------------------
+*/
+
+/// How to round a value that falls between two representable minor units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// Round half away from zero (149.995 -> 150.00).
+    HalfUp,
+    /// Round half to the nearest even unit, a.k.a. banker's rounding.
+    HalfEven,
+    /// Truncate toward zero.
+    Down,
+    /// Round away from zero.
+    Up,
+}
+
+impl Rounding {
+    /// Divide `value` by a positive `divisor`, rounding the quotient.
+    fn divide(self, value: i128, divisor: i128) -> i128 {
+        let quotient = value / divisor;
+        let remainder = value % divisor;
+        if remainder == 0 {
+            return quotient;
+        }
+
+        let away = if value < 0 { quotient - 1 } else { quotient + 1 };
+        let twice = remainder.abs() * 2;
+        match self {
+            Rounding::Down => quotient,
+            Rounding::Up => away,
+            Rounding::HalfUp if twice >= divisor => away,
+            Rounding::HalfEven if twice > divisor || (twice == divisor && quotient % 2 != 0) => away,
+            Rounding::HalfUp | Rounding::HalfEven => quotient,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Money {
+    minor_units: i64,
+    currency: String,
+}
+
+impl Money {
+    pub fn zero(currency: &str) -> Self {
+        Self::from_minor_units(0, currency)
+    }
+
+    pub fn from_minor_units(minor_units: i64, currency: &str) -> Self {
+        Money {
+            minor_units,
+            currency: currency.to_string(),
+        }
+    }
+
+    /// Convert a decimal amount into minor units of `currency`.
+    ///
+    /// The value is read back through its shortest decimal representation, so
+    /// `149.99` becomes exactly 14999 cents rather than 14998.999...
+    pub fn from_decimal(value: f64, currency: &str, rounding: Rounding) -> Option<Self> {
+        if !value.is_finite() {
+            return None;
+        }
+        let minor_units = scale_decimal(&value.to_string(), currency_exponent(currency), rounding)?;
+        Some(Self::from_minor_units(i64::try_from(minor_units).ok()?, currency))
+    }
+
+    pub fn minor_units(&self) -> i64 {
+        self.minor_units
+    }
+
+    pub fn currency(&self) -> &str {
+        &self.currency
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.minor_units == 0
+    }
+
+    /// Multiply a unit price by a line quantity.
+    pub fn times(&self, quantity: i64) -> Self {
+        Self::from_minor_units(self.minor_units.saturating_mul(quantity), &self.currency)
+    }
+
+    /// Add two amounts, or `None` when the currencies differ.
+    pub fn checked_add(&self, other: &Money) -> Option<Self> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(Self::from_minor_units(
+            self.minor_units.saturating_add(other.minor_units),
+            &self.currency,
+        ))
+    }
+
+    /// The given percentage of this amount, e.g. `percentage(15.0, ..)` of 10.00 is 1.50.
+    pub fn percentage(&self, percent: f64, rounding: Rounding) -> Self {
+        // Percentages are carried with four decimal places before multiplying.
+        const PERCENT_SCALE: u32 = 4;
+        let scaled = if percent.is_finite() {
+            scale_decimal(&percent.to_string(), PERCENT_SCALE, rounding).unwrap_or(0)
+        } else {
+            0
+        };
+        let divisor = 100 * 10_i128.pow(PERCENT_SCALE);
+        let minor_units = rounding.divide(self.minor_units as i128 * scaled, divisor);
+        Self::from_minor_units(minor_units as i64, &self.currency)
+    }
+
+    /// Convert into `currency` using a decimal exchange `rate` (target units per source unit).
+    pub fn convert(&self, rate: f64, currency: &str, rounding: Rounding) -> Option<Self> {
+        // Rates are carried with eight decimal places, which covers Shopify's presentment rates.
+        const RATE_SCALE: u32 = 8;
+        if !rate.is_finite() || rate < 0.0 {
+            return None;
+        }
+        let scaled_rate = scale_decimal(&rate.to_string(), RATE_SCALE, rounding)?;
+        let from_exponent = currency_exponent(&self.currency);
+        let to_exponent = currency_exponent(currency);
+        let numerator = (self.minor_units as i128)
+            .checked_mul(scaled_rate)?
+            .checked_mul(10_i128.pow(to_exponent))?;
+        let divisor = 10_i128.pow(RATE_SCALE + from_exponent);
+        let minor_units = i64::try_from(rounding.divide(numerator, divisor)).ok()?;
+        Some(Self::from_minor_units(minor_units, currency))
+    }
+
+    /// Split a non-negative amount in proportion to `weights` so the parts add up exactly.
+    /// Leftover minor units go to the largest remainders, the earlier part first on a tie.
+    /// When every weight is zero the amount is split evenly.
+    pub fn allocate(&self, weights: &[i64]) -> Vec<Money> {
+        let amount = self.minor_units.max(0) as i128;
+        let mut weights: Vec<i128> = weights.iter().map(|weight| (*weight).max(0) as i128).collect();
+        if weights.iter().all(|weight| *weight == 0) {
+            weights.iter_mut().for_each(|weight| *weight = 1);
+        }
+        let total: i128 = weights.iter().sum();
+        if total == 0 {
+            return vec![];
+        }
+        let mut parts: Vec<i128> = weights.iter().map(|weight| amount * weight / total).collect();
+        let mut leftover = amount - parts.iter().sum::<i128>();
+        let mut by_remainder: Vec<usize> = (0..parts.len()).collect();
+        by_remainder.sort_by_key(|index| std::cmp::Reverse(amount * weights[*index] % total));
+        for index in by_remainder {
+            if leftover == 0 {
+                break;
+            }
+            parts[index] += 1;
+            leftover -= 1;
+        }
+        parts
+            .into_iter()
+            .map(|part| Self::from_minor_units(part as i64, &self.currency))
+            .collect()
+    }
+
+    /// The amount in major units, for the `Decimal` fields of the function output.
+    pub fn to_decimal(&self) -> Decimal {
+        let factor = 10_i64.pow(currency_exponent(&self.currency));
+        Decimal(self.minor_units as f64 / factor as f64)
+    }
+}
+
+impl PartialOrd for Money {
+    /// Amounts in different currencies are not comparable.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.currency != other.currency {
+            return None;
+        }
+        Some(self.minor_units.cmp(&other.minor_units))
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let exponent = currency_exponent(&self.currency) as usize;
+        let sign = if self.minor_units < 0 { "-" } else { "" };
+        let digits = format!("{:0>width$}", self.minor_units.unsigned_abs(), width = exponent + 1);
+        let (major, minor) = digits.split_at(digits.len() - exponent);
+        if exponent == 0 {
+            write!(f, "{}{} {}", sign, major, self.currency)
+        } else {
+            write!(f, "{}{}.{} {}", sign, major, minor, self.currency)
+        }
+    }
+}
+
+/// Convert an amount declared in `shop_currency` into the cart `currency` with the
+/// presentment `rate` (cart units per shop unit), or `None` when it does not fit.
+/// Round minimums and thresholds `Up` and maximums and amounts given away `Down`,
+/// so a converted value is never looser than declared.
+pub fn to_presentment(amount: f64, shop_currency: &str, rate: f64, currency: &str, rounding: Rounding) -> Option<Money> {
+    Money::from_decimal(amount, shop_currency, Rounding::HalfUp)?.convert(rate, currency, rounding)
+}
+
+/// Check that a configured currency looks like an ISO 4217 code, e.g. `EUR`.
+pub fn validate_currency(code: &str) -> Result<(), String> {
+    if code.len() == 3 && code.chars().all(|c| c.is_ascii_uppercase()) {
+        Ok(())
+    } else {
+        Err(format!("{} is not an ISO 4217 currency code", code))
+    }
+}
+
+/// Number of minor-unit digits for an ISO 4217 currency code.
+pub fn currency_exponent(currency: &str) -> u32 {
+    match currency {
+        "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX"
+        | "VND" | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+        "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+        _ => 2,
+    }
+}
+
+// Parse a plain decimal string ("-12.345", "1e-7") into an integer scaled by 10^scale.
+fn scale_decimal(text: &str, scale: u32, rounding: Rounding) -> Option<i128> {
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(index) => (&text[..index], text[index + 1..].parse::<i32>().ok()?),
+        None => (text, 0),
+    };
+    let (negative, mantissa) = match mantissa.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, mantissa.strip_prefix('+').unwrap_or(mantissa)),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+
+    let mut digits: i128 = 0;
+    for c in whole.chars().chain(fraction.chars()) {
+        let digit = c.to_digit(10)? as i128;
+        digits = digits.checked_mul(10)?.checked_add(digit)?;
+    }
+
+    // `digits` currently carries `fraction.len()` decimals; shift it to `scale`.
+    let shift = scale as i32 + exponent - fraction.len() as i32;
+    let scaled = if shift >= 0 {
+        digits.checked_mul(10_i128.checked_pow(shift as u32)?)?
+    } else {
+        let divisor = 10_i128.checked_pow(shift.unsigned_abs())?;
+        let signed = if negative { -digits } else { digits };
+        return Some(rounding.divide(signed, divisor));
+    };
+    Some(if negative { -scaled } else { scaled })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eur(value: f64) -> Money {
+        Money::from_decimal(value, "EUR", Rounding::HalfUp).unwrap()
+    }
+
+    #[test]
+    fn test_from_decimal_is_exact_for_cent_amounts() {
+        assert_eq!(eur(149.99).minor_units(), 14999);
+        assert_eq!(eur(0.1).minor_units(), 10);
+        assert_eq!(eur(358.0).minor_units(), 35800);
+        assert_eq!(Money::from_decimal(1500.0, "JPY", Rounding::HalfUp).unwrap().minor_units(), 1500);
+        assert_eq!(Money::from_decimal(1.2345, "KWD", Rounding::HalfUp).unwrap().minor_units(), 1235);
+    }
+
+    #[test]
+    fn test_sum_reaches_threshold_exactly() {
+        // 0.1 + 0.2 + ... in f64 never lands on the threshold exactly.
+        let mut total = Money::zero("EUR");
+        for _ in 0..1500 {
+            total = total.checked_add(&eur(0.1)).unwrap();
+        }
+        assert_eq!(total, eur(150.0));
+        assert!(total >= eur(150.0));
+
+        // In f64 this sum is 149.99999999999997.
+        let seven_items = eur(17.65).times(7).checked_add(&eur(26.45)).unwrap();
+        assert!(seven_items >= eur(150.0));
+    }
+
+    #[test]
+    fn test_values_just_below_threshold_do_not_match() {
+        assert!(eur(149.99) < eur(150.0));
+        let below = Money::from_decimal(149.99999, "EUR", Rounding::Down).unwrap();
+        assert!(below < eur(150.0));
+        let rounded = Money::from_decimal(149.99999, "EUR", Rounding::HalfUp).unwrap();
+        assert!(rounded >= eur(150.0));
+    }
+
+    #[test]
+    fn test_rounding_modes() {
+        let half = |rounding| Money::from_decimal(0.125, "EUR", rounding).unwrap().minor_units();
+        assert_eq!(half(Rounding::HalfUp), 13);
+        assert_eq!(half(Rounding::HalfEven), 12);
+        assert_eq!(half(Rounding::Down), 12);
+        assert_eq!(half(Rounding::Up), 13);
+
+        let negative = |rounding| Money::from_decimal(-0.125, "EUR", rounding).unwrap().minor_units();
+        assert_eq!(negative(Rounding::HalfUp), -13);
+        assert_eq!(negative(Rounding::HalfEven), -12);
+        assert_eq!(negative(Rounding::Down), -12);
+        assert_eq!(negative(Rounding::Up), -13);
+    }
+
+    #[test]
+    fn test_percentage() {
+        assert_eq!(eur(10.0).percentage(15.0, Rounding::HalfUp), eur(1.5));
+        assert_eq!(eur(0.05).percentage(50.0, Rounding::HalfUp).minor_units(), 3);
+        assert_eq!(eur(0.05).percentage(50.0, Rounding::HalfEven).minor_units(), 2);
+        assert_eq!(eur(19.99).percentage(12.5, Rounding::Down).minor_units(), 249);
+    }
+
+    #[test]
+    fn test_convert_between_currencies() {
+        let usd = eur(150.0).convert(1.0834, "USD", Rounding::HalfUp).unwrap();
+        assert_eq!(usd, Money::from_minor_units(16251, "USD"));
+        let jpy = eur(150.0).convert(161.25, "JPY", Rounding::HalfUp).unwrap();
+        assert_eq!(jpy, Money::from_minor_units(24188, "JPY"));
+        let back = Money::from_minor_units(24188, "JPY").convert(0.0062, "EUR", Rounding::Down).unwrap();
+        assert_eq!(back, Money::from_minor_units(14996, "EUR"));
+        assert_eq!(eur(150.0).convert(1.0, "EUR", Rounding::HalfUp), Some(eur(150.0)));
+        assert_eq!(eur(150.0).convert(f64::NAN, "USD", Rounding::HalfUp), None);
+    }
+
+    #[test]
+    fn test_to_presentment() {
+        let usd = |rounding| to_presentment(150.0, "EUR", 1.0834, "USD", rounding);
+        assert_eq!(usd(Rounding::Up), Some(Money::from_minor_units(16251, "USD")));
+        assert_eq!(usd(Rounding::Down), Some(Money::from_minor_units(16251, "USD")));
+        let odd = |rounding| to_presentment(0.05, "EUR", 1.5, "USD", rounding).unwrap().minor_units();
+        assert_eq!(odd(Rounding::Up), 8);
+        assert_eq!(odd(Rounding::Down), 7);
+        assert_eq!(to_presentment(1e17, "EUR", 1.0, "EUR", Rounding::Up), None);
+        assert_eq!(to_presentment(150.0, "EUR", f64::NAN, "USD", Rounding::Up), None);
+    }
+
+    #[test]
+    fn test_validate_currency() {
+        assert_eq!(validate_currency("EUR"), Ok(()));
+        assert_eq!(validate_currency("eur"), Err("eur is not an ISO 4217 currency code".to_string()));
+        assert!(validate_currency("EURO").is_err());
+        assert!(validate_currency("").is_err());
+    }
+
+    #[test]
+    fn test_currencies_do_not_mix() {
+        let usd = Money::from_decimal(150.0, "USD", Rounding::HalfUp).unwrap();
+        assert_eq!(eur(1.0).checked_add(&usd), None);
+        assert_eq!(eur(150.0).partial_cmp(&usd), None);
+    }
+
+    #[test]
+    fn test_display_and_to_decimal() {
+        assert_eq!(eur(149.99).to_string(), "149.99 EUR");
+        assert_eq!(eur(-0.05).to_string(), "-0.05 EUR");
+        assert_eq!(Money::from_minor_units(1500, "JPY").to_string(), "1500 JPY");
+        assert_eq!(eur(149.99).to_decimal(), Decimal(149.99));
+    }
+
+    #[test]
+    fn test_rejects_non_finite_values() {
+        assert_eq!(Money::from_decimal(f64::NAN, "EUR", Rounding::HalfUp), None);
+        assert_eq!(Money::from_decimal(f64::INFINITY, "EUR", Rounding::HalfUp), None);
+    }
+
+    #[test]
+    fn test_allocate_adds_up_exactly() {
+        let parts = eur(50.0).allocate(&[1000, 1000, 1000]);
+        assert_eq!(parts, vec![eur(16.67), eur(16.67), eur(16.66)]);
+
+        let parts = eur(10.0).allocate(&[1, 2, 3, 0]);
+        assert_eq!(parts, vec![eur(1.67), eur(3.33), eur(5.0), eur(0.0)]);
+
+        assert_eq!(eur(0.05).allocate(&[0, 0]), vec![eur(0.03), eur(0.02)]);
+        assert_eq!(eur(1.0).allocate(&[]), vec![]);
+    }
+}
@@ -0,0 +1,312 @@
+use serde::{Deserialize, Serialize};
+
+/*
+ --------------------------------SCHEDULES----------------------------------
+            Bands and rules can carry an activation window that is evaluated
+            against `shop.localTime`. The function input only exposes the shop's
+            local date, so the current hour is derived from a set of aliased
+            `timeAfter` fields (hour01 .. hour23) in the input query.
+            Windows therefore have hour resolution: a start time with minutes
+            opens at the following full hour and an end time with minutes
+            closes at the preceding one, so a window never runs longer than set.
+---------------------------------------------------------------------------
+*/
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+/// Hours of the day in which a schedule is active, `start <= hour < end`.
+/// A range with `start > end` runs past midnight, e.g. 22 to 2.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HourRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// Optional activation window. An empty schedule is always active.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Schedule {
+    /// Local date or date-time ("2024-11-29" or "2024-11-29T09:00:00") the window opens.
+    #[serde(default)]
+    pub starts_at: Option<String>,
+    /// Local date or date-time the window closes. A bare date closes at the start of that day.
+    #[serde(default)]
+    pub ends_at: Option<String>,
+    #[serde(default)]
+    pub days_of_week: Vec<Weekday>,
+    #[serde(default)]
+    pub hours: Option<HourRange>,
+}
+
+/// The shop's current local date and hour.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct ShopTime {
+    date: (i32, u32, u32),
+    hour: u32,
+}
+
+impl ShopTime {
+    /// Build from `shop.localTime.date` and the number of `timeAfter` hour fields that are true.
+    pub fn new(date: &str, hours_passed: u32) -> Option<Self> {
+        Some(ShopTime {
+            date: parse_date(date)?,
+            hour: hours_passed.min(23),
+        })
+    }
+
+    /// Build from `shop.localTime.date` and the aliased `timeAfter` flags, hour01 first.
+    pub fn from_hour_flags(date: &str, hours_after: &[bool]) -> Option<Self> {
+        ShopTime::new(date, hours_after.iter().filter(|after| **after).count() as u32)
+    }
+
+    pub fn weekday(&self) -> Weekday {
+        // Days since 1970-01-01, which was a Thursday.
+        let (year, month, day) = self.date;
+        match days_from_civil(year, month, day).rem_euclid(7) {
+            0 => Weekday::Thursday,
+            1 => Weekday::Friday,
+            2 => Weekday::Saturday,
+            3 => Weekday::Sunday,
+            4 => Weekday::Monday,
+            5 => Weekday::Tuesday,
+            _ => Weekday::Wednesday,
+        }
+    }
+}
+
+/// A day picked with a `DatePicker` in the admin, stored as the `toISOString()` of
+/// the picker's local midnight, e.g. "2024-06-02T22:00:00.000Z" for June 3rd in UTC+2.
+/// Midnight anywhere between UTC-11 and UTC+11 lies within 12 hours of 00:00 UTC,
+/// so the value is rounded to the nearest UTC midnight. A bare date is read as is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PickedDate {
+    days: i64,
+}
+
+impl PickedDate {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (date, time) = match value.split_once('T') {
+            Some((date, time)) => (date, Some(time)),
+            None => (value, None),
+        };
+        let (year, month, day) = parse_date(date)?;
+        let hour: u32 = match time {
+            Some(time) => time.split(':').next()?.parse().ok()?,
+            None => 0,
+        };
+        if hour > 23 {
+            return None;
+        }
+        Some(PickedDate {
+            days: days_from_civil(year, month, day) + i64::from(hour >= 12),
+        })
+    }
+}
+
+impl ShopTime {
+    /// Whether the shop's local date is the picked day.
+    pub fn is_on(&self, picked: PickedDate) -> bool {
+        let (year, month, day) = self.date;
+        days_from_civil(year, month, day) == picked.days
+    }
+}
+
+impl Schedule {
+    /// Check every bound of the schedule, describing the first one that is malformed.
+    pub fn validate(&self) -> Result<(), String> {
+        for bound in [&self.starts_at, &self.ends_at].into_iter().flatten() {
+            if parse_date_time(bound).is_none() {
+                return Err(format!("{} is not a YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS value", bound));
+            }
+        }
+        if let Some(hours) = self.hours {
+            if hours.start > 24 || hours.end > 24 {
+                return Err(format!("hours {}-{} must be between 0 and 24", hours.start, hours.end));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_active(&self, now: &ShopTime) -> bool {
+        if let Some(start) = self.starts_at.as_deref().and_then(parse_date_time) {
+            // Round a start with minutes up to the next full hour.
+            let (date, hour, minutes) = start;
+            let opens = ShopTime { date, hour: hour + u32::from(minutes > 0) };
+            if *now < opens {
+                return false;
+            }
+        }
+        if let Some(end) = self.ends_at.as_deref().and_then(parse_date_time) {
+            let (date, hour, _) = end;
+            if *now >= (ShopTime { date, hour }) {
+                return false;
+            }
+        }
+        if !self.days_of_week.is_empty() && !self.days_of_week.contains(&now.weekday()) {
+            return false;
+        }
+        match self.hours {
+            Some(HourRange { start, end }) if start <= end => start <= now.hour && now.hour < end,
+            Some(HourRange { start, end }) => now.hour >= start || now.hour < end,
+            None => true,
+        }
+    }
+}
+
+// "YYYY-MM-DD"
+fn parse_date(value: &str) -> Option<(i32, u32, u32)> {
+    let mut parts = value.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some((year, month, day))
+}
+
+// "YYYY-MM-DD" or "YYYY-MM-DDTHH:MM[:SS]", returning the date, hour and whether minutes/seconds are set.
+fn parse_date_time(value: &str) -> Option<((i32, u32, u32), u32, u32)> {
+    let (date, time) = match value.split_once('T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+    let date = parse_date(date)?;
+    let (hour, minutes) = match time {
+        Some(time) => {
+            let mut parts = time.split(':');
+            let hour: u32 = parts.next()?.parse().ok()?;
+            let minute: u32 = parts.next()?.parse().ok()?;
+            let second: u32 = parts.next().map_or(Some(0), |s| s.parse().ok())?;
+            if hour > 23 || minute > 59 || second > 59 {
+                return None;
+            }
+            (hour, minute * 60 + second)
+        }
+        None => (0, 0),
+    };
+    Some((date, hour, minutes))
+}
+
+// Howard Hinnant's days_from_civil.
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year } as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(date: &str, hour: u32) -> ShopTime {
+        ShopTime::new(date, hour).unwrap()
+    }
+
+    fn window(starts_at: Option<&str>, ends_at: Option<&str>) -> Schedule {
+        Schedule {
+            starts_at: starts_at.map(str::to_string),
+            ends_at: ends_at.map(str::to_string),
+            ..Schedule::default()
+        }
+    }
+
+    #[test]
+    fn test_weekday() {
+        assert_eq!(at("1970-01-01", 0).weekday(), Weekday::Thursday);
+        assert_eq!(at("2024-11-29", 0).weekday(), Weekday::Friday);
+        assert_eq!(at("2024-02-29", 0).weekday(), Weekday::Thursday);
+        assert_eq!(at("2025-01-05", 0).weekday(), Weekday::Sunday);
+    }
+
+    #[test]
+    fn test_from_hour_flags() {
+        let mut flags = [false; 23];
+        flags[..9].iter_mut().for_each(|after| *after = true);
+        assert_eq!(ShopTime::from_hour_flags("2024-11-29", &flags), Some(at("2024-11-29", 9)));
+        assert_eq!(ShopTime::from_hour_flags("not a date", &flags), None);
+    }
+
+    #[test]
+    fn test_empty_schedule_is_always_active() {
+        assert!(Schedule::default().is_active(&at("2024-06-01", 12)));
+    }
+
+    #[test]
+    fn test_date_window() {
+        let sale = window(Some("2024-11-29"), Some("2024-12-03"));
+        assert!(!sale.is_active(&at("2024-11-28", 23)));
+        assert!(sale.is_active(&at("2024-11-29", 0)));
+        assert!(sale.is_active(&at("2024-12-02", 23)));
+        assert!(!sale.is_active(&at("2024-12-03", 0)));
+    }
+
+    #[test]
+    fn test_date_time_window_has_hour_resolution() {
+        let flash = window(Some("2024-11-29T09:30:00"), Some("2024-11-29T18:45"));
+        assert!(!flash.is_active(&at("2024-11-29", 9)));
+        assert!(flash.is_active(&at("2024-11-29", 10)));
+        assert!(flash.is_active(&at("2024-11-29", 17)));
+        assert!(!flash.is_active(&at("2024-11-29", 18)));
+    }
+
+    #[test]
+    fn test_days_of_week_and_hours() {
+        let weekend_evenings = Schedule {
+            days_of_week: vec![Weekday::Saturday, Weekday::Sunday],
+            hours: Some(HourRange { start: 18, end: 22 }),
+            ..Schedule::default()
+        };
+        assert!(weekend_evenings.is_active(&at("2024-11-30", 18)));
+        assert!(!weekend_evenings.is_active(&at("2024-11-30", 22)));
+        assert!(!weekend_evenings.is_active(&at("2024-11-29", 19)));
+
+        let overnight = Schedule {
+            hours: Some(HourRange { start: 22, end: 2 }),
+            ..Schedule::default()
+        };
+        assert!(overnight.is_active(&at("2024-11-30", 23)));
+        assert!(overnight.is_active(&at("2024-11-30", 1)));
+        assert!(!overnight.is_active(&at("2024-11-30", 2)));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(window(Some("2024-11-29T09:00:00"), Some("2024-12-01")).validate().is_ok());
+        assert!(window(Some("29/11/2024"), None).validate().is_err());
+        assert!(window(None, Some("2024-13-01")).validate().is_err());
+        let hours = Schedule {
+            hours: Some(HourRange { start: 8, end: 25 }),
+            ..Schedule::default()
+        };
+        assert!(hours.validate().is_err());
+    }
+
+    #[test]
+    fn test_picked_date_rounds_to_the_nearest_midnight() {
+        let picked = |value: &str| PickedDate::parse(value).unwrap();
+        // June 3rd picked in UTC+2, UTC-5 and UTC
+        assert!(at("2024-06-03", 10).is_on(picked("2024-06-02T22:00:00.000Z")));
+        assert!(at("2024-06-03", 0).is_on(picked("2024-06-03T05:00:00.000Z")));
+        assert!(at("2024-06-03", 23).is_on(picked("2024-06-03")));
+        assert!(!at("2024-06-02", 23).is_on(picked("2024-06-02T22:00:00.000Z")));
+        // Month and year ends roll over
+        assert!(at("2025-01-01", 0).is_on(picked("2024-12-31T23:00:00.000Z")));
+        assert_eq!(PickedDate::parse("2024-06-03T25:00:00.000Z"), None);
+        assert_eq!(PickedDate::parse("June 3rd"), None);
+    }
+}
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+/// Configurable `DiscountApplicationStrategy`. Each function maps it onto its
+/// generated output enum; `ALL` is available from API version 2024-04 on.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplicationStrategy {
+    /// Shopify applies the first discount in the list.
+    #[serde(alias = "FIRST")]
+    First,
+    /// Shopify applies the discount with the highest value.
+    #[serde(alias = "MAXIMUM")]
+    Maximum,
+    /// Shopify applies every discount.
+    #[serde(alias = "ALL")]
+    All,
+}
+
+impl ApplicationStrategy {
+    /// Order emitted discounts so the strategy picks the intended one: the biggest
+    /// saving first for `First` and `Maximum`, emission order for `All`.
+    /// The sort is stable, so discounts with the same saving keep their order.
+    pub fn order<T>(&self, discounts: &mut [T], saving: impl Fn(&T) -> i64) {
+        match self {
+            ApplicationStrategy::First | ApplicationStrategy::Maximum => {
+                discounts.sort_by_key(|discount| Reverse(saving(discount)))
+            }
+            ApplicationStrategy::All => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!(serde_json::from_str::<ApplicationStrategy>(r#""first""#).unwrap(), ApplicationStrategy::First);
+        assert_eq!(serde_json::from_str::<ApplicationStrategy>(r#""MAXIMUM""#).unwrap(), ApplicationStrategy::Maximum);
+        assert_eq!(serde_json::from_str::<ApplicationStrategy>(r#""all""#).unwrap(), ApplicationStrategy::All);
+        assert!(serde_json::from_str::<ApplicationStrategy>(r#""best""#).is_err());
+    }
+
+    #[test]
+    fn test_order() {
+        let savings = [("a", 500), ("b", 1200), ("c", 500)];
+
+        let mut first = savings;
+        ApplicationStrategy::First.order(&mut first, |(_, saving)| *saving);
+        assert_eq!(first, [("b", 1200), ("a", 500), ("c", 500)]);
+
+        let mut all = savings;
+        ApplicationStrategy::All.order(&mut all, |(_, saving)| *saving);
+        assert_eq!(all, savings);
+    }
+}
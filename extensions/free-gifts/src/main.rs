@@ -0,0 +1,22 @@
+//! Free gift tiers: gives away the gift products of every cart value tier the
+//! cart reaches, as managed in app.tiersmanagement.jsx.
+
+pub mod run;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    use discount_common::cli::{self, Usage};
+
+    let usage = Usage {
+        name: "free-gifts",
+        metafield: "$app:free_gifts.config",
+        explains: "each tier and gift line was chosen or skipped",
+    };
+    cli::main(&usage, run::evaluate, run::validate_config);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    eprintln!("Please invoke a named export.");
+    std::process::exit(1);
+}
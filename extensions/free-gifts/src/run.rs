@@ -0,0 +1,431 @@
+use shopify_function::prelude::*;
+use shopify_function::Result;
+use serde::Deserialize;
+use discount_common::cart::{self, CartLine, ThresholdInclusion};
+use discount_common::collections::CollectionMembership;
+use discount_common::config::{self, ConfigError};
+use discount_common::explain::Explanation;
+use discount_common::money::{self, Money, Rounding};
+use self::run::{input, output};
+
+/*
+ ---------------------------------FREE GIFT TIERS---------------------------------
+            Gives away the gifts managed in app.tiersmanagement.jsx. The admin keeps
+            them in the `gifts` app-installation metafields and mirrors them to the
+            shop's `$app:gifts` metafields, which is what this function reads:
+            tiers = [{ value, label, image }]
+                    `value` is the cart value, in the shop currency, that reaches the tier.
+            gifts = [{ tier, productId, image, value, title }]
+                    `tier` is the `value` of the tier the product is given away with.
+            Every reached tier gives one unit of each of its gifts at 100% off, taken
+            from the cart lines of that product in cart order. Units beyond that are
+            paid for, and a gift that is not in the cart is not added.
+            Lines of gift products never count toward the cart value, so a gift can
+            not unlock itself.
+
+            Read from the optional discount node metafield `$app:free_gifts.config`:
+            collectionIds = collections left out of the cart value, like the excluded
+                            collections of product-discount. Passed to the input
+                            query as `$collectionIds`.
+            shopCurrency = currency the tier values are declared in, when its number of
+                            decimals differs from the buyer's currency.
+            thresholdInclusion = optional lines left out of the cart value:
+                            excludeGiftCards, excludeSubscriptions, excludeFreeLines.
+---------------------------------------------------------------------------------
+*/
+#[derive(Deserialize, Default, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Configuration {
+    #[serde(default)]
+    collection_ids: Vec<String>,
+    #[serde(default)]
+    shop_currency: Option<String>,
+    #[serde(default)]
+    threshold_inclusion: ThresholdInclusion,
+}
+
+impl Configuration {
+    fn from_str(value: &str) -> std::result::Result<Self, ConfigError> {
+        let config: Configuration = config::parse(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> std::result::Result<(), ConfigError> {
+        if let Some(index) = self.collection_ids.iter().position(|id| id.is_empty()) {
+            return Err(ConfigError::invalid(format!("collectionIds[{}]", index), "collection id must not be empty"));
+        }
+        if let Some(currency) = &self.shop_currency {
+            money::validate_currency(currency).map_err(|reason| ConfigError::invalid("shopCurrency", reason))?;
+        }
+        Ok(())
+    }
+}
+
+// An entry of the `tiers` metafield.
+#[derive(Deserialize, PartialEq, Debug)]
+struct Tier {
+    // The text of the admin's tier value field, e.g. "150".
+    value: String,
+    label: String,
+}
+
+// An entry of the `gifts` metafield.
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Gift {
+    tier: String,
+    product_id: String,
+    #[serde(default)]
+    title: String,
+}
+
+impl Tier {
+    // The tier value in the cart currency.
+    fn threshold_in(&self, field: &str, currency: &str, shop_currency: &str, rate: f64) -> std::result::Result<Money, ConfigError> {
+        let invalid = || ConfigError::invalid(format!("{}.value", field), format!("{} is not a non-negative number", self.value));
+        let value: f64 = self.value.trim().parse().map_err(|_| invalid())?;
+        if !value.is_finite() || value < 0.0 {
+            return Err(invalid());
+        }
+        money::to_presentment(value, shop_currency, rate, currency, Rounding::Up).ok_or_else(invalid)
+    }
+}
+
+impl CartLine for input::InputCartLines {
+    type Membership = input::InputCartLinesMerchandiseOnProductVariantProductInCollections;
+
+    fn quantity(&self) -> i64 {
+        self.quantity
+    }
+
+    fn amount_per_quantity(&self) -> f64 {
+        self.cost.amount_per_quantity.amount.0
+    }
+
+    fn currency_code(&self) -> &str {
+        &self.cost.amount_per_quantity.currency_code
+    }
+
+    fn in_collections(&self) -> Option<&[Self::Membership]> {
+        match &self.merchandise {
+            input::InputCartLinesMerchandise::ProductVariant(variant) => Some(&variant.product.in_collections),
+            _ => None,
+        }
+    }
+
+    fn is_gift_card(&self) -> bool {
+        match &self.merchandise {
+            input::InputCartLinesMerchandise::ProductVariant(variant) => variant.product.is_gift_card,
+            input::InputCartLinesMerchandise::CustomProduct(custom) => custom.is_gift_card,
+        }
+    }
+
+    fn is_subscription(&self) -> bool {
+        self.selling_plan_allocation.is_some()
+    }
+}
+
+impl CollectionMembership for input::InputCartLinesMerchandiseOnProductVariantProductInCollections {
+    fn collection_id(&self) -> &str {
+        &self.collection_id
+    }
+
+    fn is_member(&self) -> bool {
+        self.is_member
+    }
+}
+
+// The product of a cart line, `None` for custom line items.
+fn product_id(line: &input::InputCartLines) -> Option<&str> {
+    match &line.merchandise {
+        input::InputCartLinesMerchandise::ProductVariant(variant) => Some(&variant.product.id),
+        _ => None,
+    }
+}
+
+/// Check a `$app:free_gifts.config` metafield value, as the function would read it.
+pub(crate) fn validate_config(value: &str) -> std::result::Result<(), String> {
+    Configuration::from_str(value).map(|_| ()).map_err(|err| err.to_string())
+}
+
+#[shopify_function_target(query_path = "src/run.graphql", schema_path = "schema.graphql")]
+fn run(input: input::ResponseData) -> Result<output::FunctionRunResult> {
+    evaluate(input, &mut Explanation::default())
+}
+
+/// The body of `run`. Each decision is recorded in `explanation` for the `explain` command.
+pub(crate) fn evaluate(input: input::ResponseData, explanation: &mut Explanation) -> Result<output::FunctionRunResult> {
+    let no_discount = output::FunctionRunResult {
+        discounts: vec![],
+        discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+    };
+
+    // 1. Parse the optional configuration and the tiers and gifts.
+    let config = match input.discount_node.metafield {
+        Some(input::InputDiscountNodeMetafield { value }) => match Configuration::from_str(&value) {
+            Ok(config) => config,
+            Err(err) => {
+                err.log("no_discount");
+                explanation.note(|| format!("Configuration rejected, no discount: {}", err));
+                return Ok(no_discount);
+            }
+        },
+        None => Configuration::default(),
+    };
+    let shop = &input.shop;
+    let tiers: Vec<(String, Tier)> =
+        config::parse_entries(shop.tiers.as_ref().map(|metafield| metafield.value.as_str()), "tiers", explanation);
+    let gifts: Vec<(String, Gift)> =
+        config::parse_entries(shop.gifts.as_ref().map(|metafield| metafield.value.as_str()), "gifts", explanation);
+    let is_gift = |line: &input::InputCartLines| {
+        product_id(line).is_some_and(|id| gifts.iter().any(|(_, gift)| gift.product_id == id))
+    };
+
+    // 2. Calculate the cart value without the excluded lines and the gift products.
+    let lines = &input.cart.lines;
+    let currency = cart::currency(lines);
+    let total = cart::total(lines, |line| {
+        let reason = cart::threshold_exclusion(line, &config.collection_ids, &config.threshold_inclusion)
+            .or_else(|| is_gift(line).then_some("a gift product"));
+        match reason {
+            Some(reason) => {
+                explanation.note(|| format!("Line {} is {} and does not count toward the cart value.", line.id, reason));
+                false
+            }
+            None => true,
+        }
+    });
+    explanation.note(|| format!("Cart value: {}.", total));
+
+    // 3. Find the reached tiers, lowest first.
+    let shop_currency = config.shop_currency.as_deref().unwrap_or(&currency);
+    let mut reached: Vec<(&Tier, Money)> = vec![];
+    for (field, tier) in tiers.iter() {
+        let threshold = match tier.threshold_in(field, &currency, shop_currency, input.presentment_currency_rate.0) {
+            Ok(threshold) => threshold,
+            Err(err) => {
+                err.log("skip_entry");
+                explanation.note(|| format!("Entry skipped: {}", err));
+                continue;
+            }
+        };
+        if total >= threshold {
+            explanation.note(|| format!("Tier {} needs {}: reached.", tier.label, threshold));
+            reached.push((tier, threshold));
+        } else {
+            explanation.note(|| format!("Tier {} needs {}: not reached.", tier.label, threshold));
+        }
+    }
+    reached.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    // 4. Each reached tier takes one unit of each of its gifts from the units still
+    //    unclaimed, so no line is given away for more units than the tiers allow.
+    let mut unclaimed: Vec<i64> = lines.iter().map(|line| line.quantity).collect();
+    let mut discounts = vec![];
+    for (tier, _) in reached {
+        let mut targets: Vec<(usize, i64)> = vec![];
+        for (_, gift) in gifts.iter().filter(|(_, gift)| gift.tier == tier.value) {
+            let position = lines
+                .iter()
+                .enumerate()
+                .position(|(index, line)| unclaimed[index] > 0 && product_id(line) == Some(gift.product_id.as_str()));
+            let index = match position {
+                Some(index) => index,
+                None => {
+                    explanation.note(|| format!("Gift {} of tier {} is not in the cart.", gift.title, tier.label));
+                    continue;
+                }
+            };
+            unclaimed[index] -= 1;
+            explanation.note(|| format!("Tier {} gives one unit of {} on line {}.", tier.label, gift.title, lines[index].id));
+            match targets.iter_mut().find(|(target, _)| *target == index) {
+                Some((_, units)) => *units += 1,
+                None => targets.push((index, 1)),
+            }
+        }
+        if targets.is_empty() {
+            continue;
+        }
+        discounts.push(output::Discount {
+            message: Some(format!("Free gift: {}", tier.label)),
+            targets: targets
+                .into_iter()
+                .map(|(index, units)| {
+                    output::Target::CartLine(output::CartLineTarget {
+                        id: lines[index].id.clone(),
+                        quantity: Some(units),
+                    })
+                })
+                .collect(),
+            value: output::Value::Percentage(output::Percentage {
+                value: Decimal(100.0),
+            }),
+        });
+    }
+
+    // 5. Every reached tier's gifts apply together.
+    let discount_application_strategy = if discounts.len() > 1 {
+        output::DiscountApplicationStrategy::ALL
+    } else {
+        output::DiscountApplicationStrategy::FIRST
+    };
+    Ok(output::FunctionRunResult {
+        discounts,
+        discount_application_strategy,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shopify_function::{run_function_with_input, Result};
+    use discount_common::cart::test_support;
+    use crate::run::run::output;
+
+    const TIERS: &str = r#"[
+        {"value": "100", "label": "Silver", "image": ""},
+        {"value": "200", "label": "Gold", "image": ""}
+    ]"#;
+    const GIFTS: &str = r#"[
+        {"tier": "100", "productId": "gid://shopify/Product/10", "image": "", "value": "", "title": "Tote bag"},
+        {"tier": "200", "productId": "gid://shopify/Product/20", "image": "", "value": "", "title": "Mug"},
+        {"tier": "200", "productId": "gid://shopify/Product/10", "image": "", "value": "", "title": "Tote bag"}
+    ]"#;
+
+    // A line of `quantity` units of `product` at `amount` USD each.
+    fn line(id: u32, product: u32, quantity: i64, amount: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": format!("gid://shopify/CartLine/{}", id),
+            "quantity": quantity,
+            "sellingPlanAllocation": null,
+            "cost": {
+                "amountPerQuantity": test_support::money(amount, "USD")
+            },
+            "merchandise": {
+                "__typename": "ProductVariant",
+                "id": format!("gid://shopify/ProductVariant/{}", id),
+                "product": {
+                    "id": format!("gid://shopify/Product/{}", product),
+                    "isGiftCard": false,
+                    "inCollections": test_support::in_collection("gid://shopify/Collection/9999", product == 99)
+                }
+            }
+        })
+    }
+
+    fn input_with(config: Option<&str>, tiers: &str, gifts: &str, lines: Vec<serde_json::Value>) -> String {
+        let fields = serde_json::json!({
+            "shop": {
+                "tiers": test_support::metafield(Some(tiers)),
+                "gifts": test_support::metafield(Some(gifts))
+            },
+            "cart": { "lines": lines }
+        });
+        test_support::input("discountNode", config, fields)
+    }
+
+    fn gift(label: &str, targets: &[(u32, i64)]) -> output::Discount {
+        output::Discount {
+            message: Some(format!("Free gift: {}", label)),
+            targets: targets
+                .iter()
+                .map(|(id, units)| {
+                    output::Target::CartLine(output::CartLineTarget {
+                        id: format!("gid://shopify/CartLine/{}", id),
+                        quantity: Some(*units),
+                    })
+                })
+                .collect(),
+            value: output::Value::Percentage(output::Percentage {
+                value: Decimal(100.0),
+            }),
+        }
+    }
+
+    fn result(discounts: Vec<output::Discount>, strategy: output::DiscountApplicationStrategy) -> output::FunctionRunResult {
+        output::FunctionRunResult {
+            discounts,
+            discount_application_strategy: strategy,
+        }
+    }
+
+    #[test]
+    fn test_reached_tier_gives_one_gift_unit() -> Result<()> {
+        // The three totes count for nothing, and only one of them is free
+        let lines = vec![line(1, 1, 1, "120.00"), line(2, 10, 3, "15.00")];
+        let output = run_function_with_input(run, &input_with(None, TIERS, GIFTS, lines))?;
+        assert_eq!(output, result(vec![gift("Silver", &[(2, 1)])], output::DiscountApplicationStrategy::FIRST));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreached_tier_gives_nothing() -> Result<()> {
+        let lines = vec![line(1, 1, 1, "99.99"), line(2, 10, 1, "15.00")];
+        let output = run_function_with_input(run, &input_with(None, TIERS, GIFTS, lines))?;
+        assert_eq!(output, result(vec![], output::DiscountApplicationStrategy::FIRST));
+        Ok(())
+    }
+
+    #[test]
+    fn test_every_reached_tier_applies() -> Result<()> {
+        // Silver and Gold each give a tote: two units across the tote lines, and the mug
+        let lines = vec![line(1, 1, 2, "100.00"), line(2, 10, 1, "15.00"), line(3, 20, 2, "8.00"), line(4, 10, 1, "15.00")];
+        let output = run_function_with_input(run, &input_with(None, TIERS, GIFTS, lines))?;
+        let expected = result(
+            vec![gift("Silver", &[(2, 1)]), gift("Gold", &[(3, 1), (4, 1)])],
+            output::DiscountApplicationStrategy::ALL,
+        );
+        assert_eq!(output, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gifts_are_not_added_beyond_the_cart() -> Result<()> {
+        // Gold's tote has no unit left once Silver claimed the only one
+        let lines = vec![line(1, 1, 1, "250.00"), line(2, 10, 1, "15.00")];
+        let output = run_function_with_input(run, &input_with(None, TIERS, GIFTS, lines))?;
+        assert_eq!(output, result(vec![gift("Silver", &[(2, 1)])], output::DiscountApplicationStrategy::FIRST));
+        Ok(())
+    }
+
+    #[test]
+    fn test_excluded_collections_do_not_count() -> Result<()> {
+        let config = r#"{"collectionIds": ["gid://shopify/Collection/9999"]}"#;
+        let lines = vec![line(1, 1, 1, "60.00"), line(2, 99, 1, "60.00"), line(3, 10, 1, "15.00")];
+        let output = run_function_with_input(run, &input_with(Some(config), TIERS, GIFTS, lines.clone()))?;
+        assert_eq!(output, result(vec![], output::DiscountApplicationStrategy::FIRST));
+        let output = run_function_with_input(run, &input_with(None, TIERS, GIFTS, lines))?;
+        assert_eq!(output, result(vec![gift("Silver", &[(3, 1)])], output::DiscountApplicationStrategy::FIRST));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_tiers_are_skipped() -> Result<()> {
+        let tiers = r#"[{"value": "lots", "label": "Silver"}, {"value": "200", "label": "Gold"}]"#;
+        let lines = vec![line(1, 1, 1, "250.00"), line(2, 20, 1, "8.00")];
+        let output = run_function_with_input(run, &input_with(None, tiers, GIFTS, lines.clone()))?;
+        assert_eq!(output, result(vec![gift("Gold", &[(2, 1)])], output::DiscountApplicationStrategy::FIRST));
+        let output = run_function_with_input(run, &input_with(Some("not json"), TIERS, GIFTS, lines))?;
+        assert_eq!(output, result(vec![], output::DiscountApplicationStrategy::FIRST));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert_eq!(validate_config(r#"{"collectionIds": ["gid://shopify/Collection/1"]}"#), Ok(()));
+        assert!(validate_config(r#"{"collectionIds": [""]}"#).unwrap_err().contains("collectionIds[0]"));
+        assert!(validate_config(r#"{"shopCurrency": "usd"}"#).unwrap_err().contains("shopCurrency"));
+    }
+
+    #[test]
+    fn test_explain_free_gifts() {
+        let mut explanation = Explanation::enabled();
+        let lines = vec![line(1, 1, 1, "120.00"), line(2, 10, 1, "15.00")];
+        let input = serde_json::from_str(&input_with(None, TIERS, GIFTS, lines)).unwrap();
+        evaluate(input, &mut explanation).unwrap();
+        let notes = explanation.notes();
+        assert!(notes.contains(&"Line gid://shopify/CartLine/2 is a gift product and does not count toward the cart value.".to_string()));
+        assert!(notes.contains(&"Tier Silver gives one unit of Tote bag on line gid://shopify/CartLine/2.".to_string()));
+    }
+}
@@ -1,7 +1,22 @@
-use std::process;
+//! Cart value bands: discounts the products of a band's collections once the cart
+//! reaches the band's threshold, at the rate of each product's discount metafield.
+
 pub mod run;
 
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    use discount_common::cli::{self, Usage};
+
+    let usage = Usage {
+        name: "product-discount",
+        metafield: "$app:cart_value_bands",
+        explains: "each band and line was chosen or skipped",
+    };
+    cli::main(&usage, run::evaluate, run::validate_config);
+}
+
+#[cfg(target_arch = "wasm32")]
 fn main() {
     eprintln!("Please invoke a named export.");
-    process::exit(1);
+    std::process::exit(1);
 }
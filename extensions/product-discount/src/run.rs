@@ -1,58 +1,887 @@
 use shopify_function::prelude::*;
 use shopify_function::Result;
 use serde::{Deserialize, Serialize};
-use serde::ser::Serializer; 
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use discount_common::cart::{self, CartLine, ThresholdInclusion};
+use discount_common::codes::DiscountCode;
+use discount_common::collections::{self, CollectionMatch, CollectionMembership};
+use discount_common::config::{self, ConfigError};
+use discount_common::explain::Explanation;
+use discount_common::money::{self, Money, Rounding};
+use discount_common::schedule::{Schedule, ShopTime};
+use discount_common::strategy::ApplicationStrategy;
+use self::run::{input, output};
 
-#[derive(Serialize, Deserialize, Default, PartialEq)]
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug)]
 #[serde(rename_all(deserialize = "camelCase"))]
 struct Configuration {
+    // Collections left out of the band totals. Passed to the input query as
+    // `$collectionIds`, so every band collection is listed here as well; a band
+    // whose collections are not is skipped.
     collection_ids: Vec<String>,
+    #[serde(default)]
     mapping: Vec<CollectionMapping>,
+    // Currency the plain `threshold` values are declared in. Only needed when the
+    // shop currency has a different number of decimals than the buyer's currency.
+    #[serde(default)]
+    shop_currency: Option<String>,
+    #[serde(default)]
+    allocation: AllocationMode,
+    // Every tag used by a band. Passed to the input query as `$customerTags`.
+    #[serde(default)]
+    customer_tags: Vec<String>,
+    // Rate used by the `uniform` allocation when the band has no `discount` of its own.
+    // Defaults to the highest metafield rate of the matched lines.
+    #[serde(default)]
+    uniform_percentage: Option<f64>,
+    // How Shopify combines the emitted discounts: `first`, `maximum` or `all`.
+    // Defaults to `all` for the `all_eligible_lines` allocation and `first` otherwise.
+    #[serde(default)]
+    application_strategy: Option<ApplicationStrategy>,
+    // Quantity breaks, evaluated next to the cart-value bands.
+    #[serde(default)]
+    quantity_breaks: Vec<QuantityBreak>,
+    // Every collection used by a `collection` quantity break. Passed to the input
+    // query as `$quantityBreakCollectionIds`, separately from the excluded `collectionIds`.
+    #[serde(default)]
+    quantity_break_collection_ids: Vec<String>,
+    // Query the variant weights, needed by `weight` bands. Passed to the input query
+    // as `$includeVariantWeight`.
+    #[serde(default)]
+    include_variant_weight: bool,
+    // Gift cards, subscriptions and free lines left out of every band total. Custom
+    // line items never count here, as they are in no collection.
+    #[serde(default)]
+    threshold_inclusion: ThresholdInclusion,
 }
 
-#[derive(Serialize, Deserialize, Default, PartialEq)]
+// Units of a variant, a product or a collection in the cart unlock increasing discounts
+// on those units, e.g. 10% from 3 units and 15% from 6.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all(deserialize = "camelCase"))]
+struct QuantityBreak {
+    scope: QuantityScope,
+    // The collection whose units are counted together, for the `collection` scope only.
+    #[serde(default)]
+    collection: Option<String>,
+    // Lowest `minimumQuantity` first. The highest break reached applies.
+    breaks: Vec<Breakpoint>,
+}
+
+// How the quantities of the cart lines are summed.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum QuantityScope {
+    // Each variant on its own, across the lines it is on.
+    Variant,
+    // All variants of a product together.
+    Product,
+    // All products in `collection` together.
+    Collection,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all(deserialize = "camelCase"))]
+struct Breakpoint {
+    minimum_quantity: i64,
+    discount: DiscountValue,
+}
+
+// How the matched band's discount is spread over the lines in its collection.
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum AllocationMode {
+    // Only the line with the highest metafield discount is discounted.
+    #[default]
+    BestSingleLine,
+    // Every line is discounted by its own metafield discount.
+    AllEligibleLines,
+    // Every line in the collection gets the same discount.
+    Uniform,
+}
+
+// Per-band choice of the discounted lines.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Targeting {
+    // The line with the highest discount, like the `best_single_line` allocation.
+    HighestRate,
+    // A single unit of the line with the lowest unit price.
+    CheapestUnit,
+    // A single unit of the line with the highest unit price.
+    MostExpensiveUnit,
+    // Every line with its own discount, like the `all_eligible_lines` allocation.
+    All,
+}
+
+// What a band's `threshold` is compared against. Every kind only counts the lines
+// outside the excluded `collectionIds`.
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ThresholdKind {
+    // Cart value in the shop currency, converted like the rest of the amounts.
+    #[default]
+    Value,
+    // Units in the cart, e.g. 5 items from the rest of the store.
+    ItemCount,
+    // Different products in the cart, however many units of each.
+    DistinctProducts,
+    // Total weight in kilograms, from each variant's `weight` and `weightUnit`.
+    Weight,
+}
+
+// A band threshold in the unit of its kind. Thresholds of different kinds do not compare.
+#[derive(PartialEq, Debug)]
+enum Threshold {
+    Value(Money),
+    ItemCount(i64),
+    DistinctProducts(i64),
+    // In milligrams, so that sums of converted weights compare exactly.
+    Weight(i64),
+}
+
+impl PartialOrd for Threshold {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Threshold::Value(a), Threshold::Value(b)) => a.partial_cmp(b),
+            (Threshold::ItemCount(a), Threshold::ItemCount(b)) => a.partial_cmp(b),
+            (Threshold::DistinctProducts(a), Threshold::DistinctProducts(b)) => a.partial_cmp(b),
+            (Threshold::Weight(a), Threshold::Weight(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Threshold::Value(amount) => write!(f, "{}", amount),
+            Threshold::ItemCount(count) => write!(f, "{} items", count),
+            Threshold::DistinctProducts(count) => write!(f, "{} distinct products", count),
+            Threshold::Weight(milligrams) => write!(f, "{} kg", *milligrams as f64 / MILLIGRAMS_PER_KILOGRAM),
+        }
+    }
+}
+
+// What the lines outside the excluded collections add up to.
+struct ThresholdTotals<'a> {
+    value: Money,
+    item_count: i64,
+    products: BTreeSet<&'a str>,
+    weight_milligrams: i64,
+}
+
+impl ThresholdTotals<'_> {
+    fn reaches(&self, threshold: &Threshold) -> bool {
+        match threshold {
+            Threshold::Value(amount) => self.value >= *amount,
+            Threshold::ItemCount(count) => self.item_count >= *count,
+            Threshold::DistinctProducts(count) => self.products.len() as i64 >= *count,
+            Threshold::Weight(milligrams) => self.weight_milligrams >= *milligrams,
+        }
+    }
+}
+
+const MILLIGRAMS_PER_KILOGRAM: f64 = 1_000_000.0;
+
+// Weight of one unit of the variant in milligrams. Variants without a weight weigh nothing.
+fn unit_weight_milligrams(weight: Option<f64>, unit: &input::WeightUnit) -> Option<i64> {
+    let kilograms_per_unit = match unit {
+        input::WeightUnit::GRAMS => 0.001,
+        input::WeightUnit::KILOGRAMS => 1.0,
+        input::WeightUnit::OUNCES => 0.028_349_523_125,
+        input::WeightUnit::POUNDS => 0.453_592_37,
+        input::WeightUnit::Other(_) => return None,
+    };
+    let weight = weight.unwrap_or(0.0);
+    if !weight.is_finite() || weight < 0.0 {
+        return None;
+    }
+    Some((weight * kilograms_per_unit * MILLIGRAMS_PER_KILOGRAM).round() as i64)
+}
+
+// How the `value` of a discount is read.
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum DiscountKind {
+    // Percentage off the targeted lines.
+    #[default]
+    Percentage,
+    // Fixed amount off each targeted unit (`appliesToEachItem: true`).
+    FixedAmountPerUnit,
+    // Fixed amount spread once across all targets (`appliesToEachItem: false`).
+    FixedAmount,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+struct DiscountValue {
+    #[serde(default)]
+    kind: DiscountKind,
+    // Percentage, or an amount in the shop currency for the fixed kinds.
+    value: f64,
+}
+
+impl DiscountValue {
+    fn validate(&self, field: String) -> std::result::Result<(), DiscountError> {
+        let valid = match self.kind {
+            DiscountKind::Percentage => (0.0..=100.0).contains(&self.value),
+            DiscountKind::FixedAmountPerUnit | DiscountKind::FixedAmount => {
+                self.value.is_finite() && self.value >= 0.0
+            }
+        };
+        if valid {
+            return Ok(());
+        }
+        let reason = match self.kind {
+            DiscountKind::Percentage => format!("discount {} must be between 0 and 100", self.value),
+            _ => format!("amount {} must be a non-negative number", self.value),
+        };
+        Err(DiscountError::InvalidValue { field, reason })
+    }
+
+    // Amount of the fixed kinds in the cart currency. Rounding down never gives away more than declared.
+    fn amount_in(&self, currency: &CartCurrency) -> Option<Money> {
+        currency.shop_amount(self.value, Rounding::Down)
+    }
+
+    // Map the discount onto the matching Shopify output value.
+    fn to_output(self, currency: &CartCurrency, targets: Vec<output::Target>) -> Option<output::Discount> {
+        let (message, value) = match self.kind {
+            DiscountKind::Percentage => (
+                format!("{}% off", self.value),
+                output::Value::Percentage(output::Percentage {
+                    value: Decimal(self.value),
+                }),
+            ),
+            DiscountKind::FixedAmountPerUnit => {
+                let amount = self.amount_in(currency)?;
+                (
+                    format!("{} off each item", amount),
+                    output::Value::FixedAmount(output::FixedAmount {
+                        amount: amount.to_decimal(),
+                        applies_to_each_item: Some(true),
+                    }),
+                )
+            }
+            DiscountKind::FixedAmount => {
+                let amount = self.amount_in(currency)?;
+                (
+                    format!("{} off", amount),
+                    output::Value::FixedAmount(output::FixedAmount {
+                        amount: amount.to_decimal(),
+                        applies_to_each_item: Some(false),
+                    }),
+                )
+            }
+        };
+        Some(output::Discount {
+            message: Some(message),
+            targets,
+            value,
+        })
+    }
+
+    // Money this discount takes off a line, used to compare lines with different kinds.
+    fn reduction(&self, unit_price: &Money, quantity: i64, currency: &CartCurrency) -> Option<Money> {
+        let line_total = unit_price.times(quantity);
+        let reduction = match self.kind {
+            DiscountKind::Percentage => line_total.percentage(self.value, Rounding::HalfUp),
+            DiscountKind::FixedAmountPerUnit => {
+                let amount = self.amount_in(currency)?;
+                let per_unit = if amount < *unit_price { amount } else { unit_price.clone() };
+                per_unit.times(quantity)
+            }
+            DiscountKind::FixedAmount => {
+                let amount = self.amount_in(currency)?;
+                if amount < line_total { amount } else { line_total }
+            }
+        };
+        Some(reduction)
+    }
+}
+
+// The cart's presentment currency and how to reach it from the shop currency.
+struct CartCurrency {
+    code: String,
+    shop_currency: Option<String>,
+    presentment_rate: f64,
+}
+
+impl CartCurrency {
+    fn shop_amount(&self, value: f64, rounding: Rounding) -> Option<Money> {
+        let shop_currency = self.shop_currency.as_deref().unwrap_or(&self.code);
+        money::to_presentment(value, shop_currency, self.presentment_rate, &self.code, rounding)
+    }
+}
+
+// A cart line in the matched band's collection.
+struct EligibleLine {
+    variant_id: String,
+    quantity: i64,
+    unit_price: Money,
+    discount: Option<DiscountValue>,
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug)]
 #[serde(rename_all(deserialize = "camelCase"))]
 struct CollectionMapping {
     collection: String,
+    // Further collections of the band, combined with `collection` by `collectionMatch`:
+    // `match_any` (default) targets products in any of them, `match_all` only
+    // products in every one of them.
+    #[serde(default)]
+    collections: Vec<String>,
+    #[serde(default)]
+    collection_match: CollectionMatch,
+    // Which lines of the band's collections get the discount. Without it the
+    // top-level `allocation` decides.
+    #[serde(default)]
+    targeting: Option<Targeting>,
+    // `value` (default), `item_count`, `distinct_products` or `weight`.
+    #[serde(default)]
+    threshold_kind: ThresholdKind,
+    // Threshold in the shop currency, converted with the cart's presentment rate.
+    // A whole number of items or products for the count kinds, kilograms for `weight`.
     threshold: f64,
+    // Optional per-currency thresholds, e.g. {"CHF": 140}. These win over the converted value.
+    // Only for the `value` kind.
+    #[serde(default)]
+    thresholds: BTreeMap<String, f64>,
+    // Discount granted by the band itself. Used by the `uniform` allocation and
+    // for lines in the collection whose product metafield has no entry for it.
+    #[serde(default)]
+    discount: Option<DiscountValue>,
+    // Optional `startsAt`/`endsAt`, `daysOfWeek` and `hours` window for the band.
+    #[serde(flatten)]
+    schedule: Schedule,
+    // The buyer needs at least one of these tags. Guests never match a tagged band.
+    #[serde(default)]
+    required_customer_tags: Vec<String>,
+    // The band is skipped when the buyer has any of these tags.
+    #[serde(default)]
+    excluded_customer_tags: Vec<String>,
+    // Most the band may take off an order, in the shop currency like `threshold`.
+    #[serde(default)]
+    max_discount_amount: Option<f64>,
+    // Codes that would unlock the band at their own percentage. Entered codes are not
+    // part of this API version's input, so a band with codes is rejected.
+    #[serde(default)]
+    discount_codes: Vec<DiscountCode>,
+}
+
+impl CollectionMapping {
+    // Select the threshold for the cart currency. Rounding up means a converted
+    // band never unlocks for less than the declared shop-currency amount.
+    fn threshold_in(&self, currency: &CartCurrency) -> Option<Threshold> {
+        match self.threshold_kind {
+            ThresholdKind::Value => {}
+            ThresholdKind::ItemCount => return Some(Threshold::ItemCount(self.threshold as i64)),
+            ThresholdKind::DistinctProducts => return Some(Threshold::DistinctProducts(self.threshold as i64)),
+            ThresholdKind::Weight => {
+                return Some(Threshold::Weight((self.threshold * MILLIGRAMS_PER_KILOGRAM).round() as i64))
+            }
+        }
+        if let Some(amount) = self.thresholds.get(&currency.code) {
+            return Money::from_decimal(*amount, &currency.code, Rounding::HalfUp).map(Threshold::Value);
+        }
+        currency.shop_amount(self.threshold, Rounding::Up).map(Threshold::Value)
+    }
+
+    // The cap in the cart currency. Rounding down keeps the cap from growing in conversion.
+    fn max_discount_in(&self, currency: &CartCurrency) -> Option<Money> {
+        currency.shop_amount(self.max_discount_amount?, Rounding::Down)
+    }
+
+    // The band's collections, `collection` first.
+    fn collection_ids(&self) -> Vec<String> {
+        let mut ids = vec![self.collection.clone()];
+        ids.extend(self.collections.iter().cloned());
+        ids
+    }
+
+    fn is_eligible(&self, customer_tags: &[&str]) -> bool {
+        let has_tag = |tag: &String| customer_tags.contains(&tag.as_str());
+        (self.required_customer_tags.is_empty() || self.required_customer_tags.iter().any(has_tag))
+            && !self.excluded_customer_tags.iter().any(has_tag)
+    }
+}
+
+impl QuantityBreak {
+    fn validate(&self, index: usize, collection_ids: &[String]) -> std::result::Result<(), DiscountError> {
+        match (self.scope, &self.collection) {
+            (QuantityScope::Collection, Some(collection)) if collection_ids.contains(collection) => {}
+            (QuantityScope::Collection, _) => {
+                return Err(DiscountError::InvalidValue {
+                    field: format!("quantityBreaks[{}].collection", index),
+                    reason: "a collection listed in quantityBreakCollectionIds is required".to_string(),
+                });
+            }
+            (_, Some(_)) => {
+                return Err(DiscountError::InvalidValue {
+                    field: format!("quantityBreaks[{}].collection", index),
+                    reason: "only the collection scope takes a collection".to_string(),
+                });
+            }
+            (_, None) => {}
+        }
+        if self.breaks.is_empty() {
+            return Err(DiscountError::InvalidValue {
+                field: format!("quantityBreaks[{}].breaks", index),
+                reason: "at least one break is required".to_string(),
+            });
+        }
+        let mut previous = 0;
+        for (position, breakpoint) in self.breaks.iter().enumerate() {
+            if breakpoint.minimum_quantity <= previous {
+                return Err(DiscountError::InvalidValue {
+                    field: format!("quantityBreaks[{}].breaks[{}].minimumQuantity", index, position),
+                    reason: format!("{} must be positive and above the previous break", breakpoint.minimum_quantity),
+                });
+            }
+            previous = breakpoint.minimum_quantity;
+            breakpoint
+                .discount
+                .validate(format!("quantityBreaks[{}].breaks[{}].discount", index, position))?;
+        }
+        Ok(())
+    }
+
+    // The highest break the quantity reaches.
+    fn reached(&self, quantity: i64) -> Option<&Breakpoint> {
+        self.breaks.iter().rev().find(|breakpoint| quantity >= breakpoint.minimum_quantity)
+    }
 }
 
 impl Configuration {
-    fn from_str(value: &str) -> Self {
-        serde_json::from_str(value).expect("Unable to parse configuration value from metafield")
+    fn from_str(value: &str) -> std::result::Result<Self, DiscountError> {
+        let config: Configuration = config::parse(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Why a band cannot be checked, if it cannot. Band membership is read from
+    // `inCollections(ids: $collectionIds)`, so a band collection missing there is
+    // never reported. Only that band is skipped.
+    fn band_error(&self, index: usize, mapping: &CollectionMapping) -> Option<DiscountError> {
+        let id = mapping.collection_ids().into_iter().find(|id| !self.collection_ids.contains(id))?;
+        let key = if id == mapping.collection { "collection" } else { "collections" };
+        Some(DiscountError::InvalidValue {
+            field: format!("mapping[{}].{}", index, key),
+            reason: format!("{} is missing from collectionIds, so its membership is never queried", id),
+        })
+    }
+
+    fn band_errors(&self) -> Vec<DiscountError> {
+        self.mapping
+            .iter()
+            .enumerate()
+            .filter_map(|(index, mapping)| self.band_error(index, mapping))
+            .collect()
+    }
+
+    // Reject values that parse but cannot describe a usable band.
+    fn validate(&self) -> std::result::Result<(), DiscountError> {
+        if let Some(percentage) = self.uniform_percentage {
+            if !(0.0..=100.0).contains(&percentage) {
+                return Err(DiscountError::InvalidValue {
+                    field: "uniformPercentage".to_string(),
+                    reason: format!("percentage {} must be between 0 and 100", percentage),
+                });
+            }
+        }
+        if let Some(currency) = &self.shop_currency {
+            money::validate_currency(currency).map_err(|reason| DiscountError::InvalidValue {
+                field: "shopCurrency".to_string(),
+                reason,
+            })?;
+        }
+        for (index, rule) in self.quantity_breaks.iter().enumerate() {
+            rule.validate(index, &self.quantity_break_collection_ids)?;
+        }
+        for (index, mapping) in self.mapping.iter().enumerate() {
+            if mapping.collection.is_empty() || mapping.collections.iter().any(|id| id.is_empty()) {
+                return Err(DiscountError::InvalidValue {
+                    field: format!("mapping[{}].collection", index),
+                    reason: "collection id must not be empty".to_string(),
+                });
+            }
+            if !mapping.threshold.is_finite() || mapping.threshold < 0.0 {
+                return Err(DiscountError::InvalidValue {
+                    field: format!("mapping[{}].threshold", index),
+                    reason: format!("threshold {} must be a non-negative number", mapping.threshold),
+                });
+            }
+            if mapping.threshold_kind == ThresholdKind::Weight && !self.include_variant_weight {
+                return Err(DiscountError::InvalidValue {
+                    field: format!("mapping[{}].thresholdKind", index),
+                    reason: "weight bands need includeVariantWeight".to_string(),
+                });
+            }
+            let is_count = matches!(mapping.threshold_kind, ThresholdKind::ItemCount | ThresholdKind::DistinctProducts);
+            if is_count && mapping.threshold.fract() != 0.0 {
+                return Err(DiscountError::InvalidValue {
+                    field: format!("mapping[{}].threshold", index),
+                    reason: format!("threshold {} must be a whole number for {:?}", mapping.threshold, mapping.threshold_kind),
+                });
+            }
+            if mapping.threshold_kind != ThresholdKind::Value && !mapping.thresholds.is_empty() {
+                return Err(DiscountError::InvalidValue {
+                    field: format!("mapping[{}].thresholds", index),
+                    reason: "per-currency thresholds only apply to the value kind".to_string(),
+                });
+            }
+            for (currency, threshold) in mapping.thresholds.iter() {
+                money::validate_currency(currency).map_err(|reason| DiscountError::InvalidValue {
+                    field: format!("mapping[{}].thresholds", index),
+                    reason,
+                })?;
+                if !threshold.is_finite() || *threshold < 0.0 {
+                    return Err(DiscountError::InvalidValue {
+                        field: format!("mapping[{}].thresholds.{}", index, currency),
+                        reason: format!("threshold {} must be a non-negative number", threshold),
+                    });
+                }
+            }
+            if let Some(discount) = &mapping.discount {
+                discount.validate(format!("mapping[{}].discount", index))?;
+            }
+            if let Some(cap) = mapping.max_discount_amount {
+                if !cap.is_finite() || cap < 0.0 {
+                    return Err(DiscountError::InvalidValue {
+                        field: format!("mapping[{}].maxDiscountAmount", index),
+                        reason: format!("cap {} must be a non-negative number", cap),
+                    });
+                }
+            }
+            if !mapping.discount_codes.is_empty() {
+                return Err(DiscountError::InvalidValue {
+                    field: format!("mapping[{}].discountCodes", index),
+                    reason: "entered codes are not part of this API version's input, so the band would never apply".to_string(),
+                });
+            }
+            mapping.schedule.validate().map_err(|reason| DiscountError::InvalidValue {
+                field: format!("mapping[{}].schedule", index),
+                reason,
+            })?;
+            // Tags missing from the query variable are never reported on the customer.
+            let band_tags = mapping.required_customer_tags.iter().chain(mapping.excluded_customer_tags.iter());
+            for tag in band_tags {
+                if !self.customer_tags.contains(tag) {
+                    return Err(DiscountError::InvalidValue {
+                        field: format!("mapping[{}].customerTags", index),
+                        reason: format!("{} is not listed in the top-level customerTags", tag),
+                    });
+                }
+            }
+        }
+        Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Default, PartialEq)]
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug)]
 struct DiscountData {
     #[serde(rename = "collectionDiscounts")] // Correctly name the field to match the input JSON
     collection_discounts: Vec<CollectionDiscount>,
 }
 
-#[derive(Serialize, Deserialize, Default, PartialEq)]
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug)]
 struct CollectionDiscount {
     #[serde(rename = "collection_id")] // Rename to match the input JSON
     collection_id: String,
     discount: f64,
+    #[serde(default)]
+    kind: DiscountKind,
+}
+
+impl DiscountData {
+    fn discount_for(&self, collection_id: &str) -> Option<DiscountValue> {
+        self.collection_discounts
+            .iter()
+            .find(|entry| entry.collection_id == collection_id)
+            .map(|entry| DiscountValue {
+                kind: entry.kind,
+                value: entry.discount,
+            })
+    }
+
+    fn from_str(value: &str, variant_id: &str) -> std::result::Result<Self, DiscountError> {
+        let data: DiscountData = serde_json::from_str(value).map_err(|err| {
+            DiscountError::ProductMetafieldParse {
+                variant_id: variant_id.to_string(),
+                reason: err.to_string(),
+            }
+        })?;
+        for (index, entry) in data.collection_discounts.iter().enumerate() {
+            let discount = DiscountValue {
+                kind: entry.kind,
+                value: entry.discount,
+            };
+            discount.validate(format!("{} collectionDiscounts[{}].discount", variant_id, index))?;
+        }
+        Ok(data)
+    }
+}
+
+/// Errors raised while reading the metafields that drive this function.
+///
+/// None of these abort the function: a bad discount configuration falls back
+/// to the no-discount result and a bad product metafield only skips that line.
+#[derive(Debug, PartialEq)]
+enum DiscountError {
+    /// The `$app:cart_value_bands` metafield is not valid configuration JSON.
+    ConfigParse(String),
+    /// A product's `product_discount_data` metafield is not valid JSON.
+    ProductMetafieldParse { variant_id: String, reason: String },
+    /// A value parsed but is outside the range the function accepts.
+    InvalidValue { field: String, reason: String },
+}
+
+impl DiscountError {
+    fn kind(&self) -> &'static str {
+        match self {
+            DiscountError::ConfigParse(_) => "config_parse",
+            DiscountError::ProductMetafieldParse { .. } => "product_metafield_parse",
+            DiscountError::InvalidValue { .. } => "invalid_value",
+        }
+    }
+
+    fn log(&self, fallback: &str) {
+        config::log_warning(self.kind(), &self.to_string(), fallback);
+    }
+}
+
+impl From<ConfigError> for DiscountError {
+    fn from(err: ConfigError) -> Self {
+        match err {
+            ConfigError::Parse(reason) => DiscountError::ConfigParse(reason),
+            ConfigError::InvalidValue { field, reason } => DiscountError::InvalidValue { field, reason },
+        }
+    }
+}
+
+impl fmt::Display for DiscountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscountError::ConfigParse(reason) => {
+                write!(f, "unable to parse configuration value from metafield: {}", reason)
+            }
+            DiscountError::ProductMetafieldParse { variant_id, reason } => {
+                write!(f, "invalid discount metafield format on {}: {}", variant_id, reason)
+            }
+            DiscountError::InvalidValue { field, reason } => {
+                write!(f, "invalid value for {}: {}", field, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DiscountError {}
+
+impl CartLine for input::InputCartLines {
+    type Membership = input::InputCartLinesMerchandiseOnProductVariantProductInCollections;
+
+    fn quantity(&self) -> i64 {
+        self.quantity
+    }
+
+    fn amount_per_quantity(&self) -> f64 {
+        self.cost.amount_per_quantity.amount.0
+    }
+
+    fn currency_code(&self) -> &str {
+        &self.cost.amount_per_quantity.currency_code
+    }
+
+    fn in_collections(&self) -> Option<&[Self::Membership]> {
+        match &self.merchandise {
+            input::InputCartLinesMerchandise::ProductVariant(variant) => Some(&variant.product.in_collections),
+            _ => None,
+        }
+    }
+
+    fn is_gift_card(&self) -> bool {
+        match &self.merchandise {
+            input::InputCartLinesMerchandise::ProductVariant(variant) => variant.product.is_gift_card,
+            input::InputCartLinesMerchandise::CustomProduct(custom) => custom.is_gift_card,
+        }
+    }
+
+    fn is_subscription(&self) -> bool {
+        self.selling_plan_allocation.is_some()
+    }
+}
+
+impl CollectionMembership for input::InputCartLinesMerchandiseOnProductVariantProductInCollections {
+    fn collection_id(&self) -> &str {
+        &self.collection_id
+    }
+
+    fn is_member(&self) -> bool {
+        self.is_member
+    }
+}
+
+impl CollectionMembership for input::InputCartLinesMerchandiseOnProductVariantProductQuantityBreakCollections {
+    fn collection_id(&self) -> &str {
+        &self.collection_id
+    }
+
+    fn is_member(&self) -> bool {
+        self.is_member
+    }
+}
+
+// A variant's units within a quantity-break group.
+struct GroupedVariant<'a> {
+    id: &'a str,
+    units: i64,
+    unit_price: Money,
+}
+
+// One discount per variant, product or collection whose units reach a break, with the
+// money it saves. Each variant is targeted once with its units summed over its lines.
+fn quantity_break_discounts(
+    config: &Configuration,
+    lines: &[input::InputCartLines],
+    currency: &CartCurrency,
+    explanation: &mut Explanation,
+) -> Vec<(output::Discount, i64)> {
+    let mut discounts = vec![];
+    for (index, rule) in config.quantity_breaks.iter().enumerate() {
+        // Groups keyed by variant, product or collection id, in cart order
+        let mut groups: Vec<(&str, Vec<GroupedVariant>)> = vec![];
+        for line in lines {
+            let variant = match &line.merchandise {
+                input::InputCartLinesMerchandise::ProductVariant(variant) => variant,
+                _ => continue,
+            };
+            let key = match (rule.scope, rule.collection.as_deref()) {
+                (QuantityScope::Variant, _) => variant.id.as_str(),
+                (QuantityScope::Product, _) => variant.product.id.as_str(),
+                (QuantityScope::Collection, Some(collection))
+                    if collections::is_member_of(&variant.product.quantity_break_collections, collection) =>
+                {
+                    collection
+                }
+                (QuantityScope::Collection, _) => continue,
+            };
+            let unit_price = match cart::unit_price(line) {
+                Some(price) => price,
+                None => continue,
+            };
+            let group = match groups.iter().position(|(group_key, _)| *group_key == key) {
+                Some(position) => &mut groups[position].1,
+                None => {
+                    groups.push((key, vec![]));
+                    &mut groups.last_mut().unwrap().1
+                }
+            };
+            match group.iter_mut().find(|grouped| grouped.id == variant.id) {
+                Some(grouped) => grouped.units += line.quantity,
+                None => group.push(GroupedVariant {
+                    id: &variant.id,
+                    units: line.quantity,
+                    unit_price,
+                }),
+            }
+        }
+
+        for (key, variants) in groups {
+            let quantity: i64 = variants.iter().map(|grouped| grouped.units).sum();
+            let breakpoint = match rule.reached(quantity) {
+                Some(breakpoint) => breakpoint,
+                None => {
+                    explanation.note(|| format!("Quantity break {}: {} has {} units, below every break.", index, key, quantity));
+                    continue;
+                }
+            };
+            explanation.note(|| {
+                format!("Quantity break {}: {} has {} units, reaching the {}+ break.", index, key, quantity, breakpoint.minimum_quantity)
+            });
+            let discount = breakpoint.discount;
+            let saving = match discount.kind {
+                // One amount across the group, at most what the group costs
+                DiscountKind::FixedAmount => {
+                    let value = variants.iter().fold(Money::zero(&currency.code), |total, grouped| {
+                        total.checked_add(&grouped.unit_price.times(grouped.units)).unwrap_or(total)
+                    });
+                    discount.amount_in(currency).map(|amount| if amount < value { amount } else { value })
+                }
+                _ => variants.iter().try_fold(Money::zero(&currency.code), |total, grouped| {
+                    total.checked_add(&discount.reduction(&grouped.unit_price, grouped.units, currency)?)
+                }),
+            };
+            let targets = variants
+                .iter()
+                .map(|grouped| {
+                    output::Target::ProductVariant(output::ProductVariantTarget {
+                        id: grouped.id.to_string(),
+                        quantity: Some(grouped.units),
+                    })
+                })
+                .collect();
+            if let Some(output) = discount.to_output(currency, targets) {
+                discounts.push((output, saving.map_or(0, |saving| saving.minor_units())));
+            }
+        }
+    }
+    discounts
+}
+
+// Add the quantity-break discounts to the band result. When both emit discounts they are
+// applied with ALL unless a strategy is configured, and the band discounts come first.
+fn with_quantity_breaks(
+    mut result: output::FunctionRunResult,
+    mut quantity_discounts: Vec<(output::Discount, i64)>,
+    configured: Option<ApplicationStrategy>,
+) -> output::FunctionRunResult {
+    if quantity_discounts.is_empty() {
+        return result;
+    }
+    let strategy = if result.discounts.is_empty() {
+        configured.unwrap_or(if quantity_discounts.len() > 1 {
+            ApplicationStrategy::All
+        } else {
+            ApplicationStrategy::First
+        })
+    } else if result.discount_application_strategy == output::DiscountApplicationStrategy::ALL {
+        ApplicationStrategy::All
+    } else {
+        configured.unwrap_or(ApplicationStrategy::All)
+    };
+    strategy.order(&mut quantity_discounts, |(_, saving)| *saving);
+    result.discounts.extend(quantity_discounts.into_iter().map(|(discount, _)| discount));
+    result.discount_application_strategy = strategy_output(strategy);
+    result
 }
 
-// Custom serialization for Decimal
-fn serialize_decimal<S>(decimal: &Decimal, serializer: S) -> std::result::Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    serializer.serialize_f64(decimal.0) // Ensure this serializes as a float
+fn strategy_output(strategy: ApplicationStrategy) -> output::DiscountApplicationStrategy {
+    match strategy {
+        ApplicationStrategy::First => output::DiscountApplicationStrategy::FIRST,
+        ApplicationStrategy::Maximum => output::DiscountApplicationStrategy::MAXIMUM,
+        ApplicationStrategy::All => output::DiscountApplicationStrategy::ALL,
+    }
 }
 
-#[derive(Serialize, Deserialize, PartialEq)]
-struct Percentage {
-    #[serde(serialize_with = "serialize_decimal")]
-    value: Decimal,
+/// Check a `$app:cart_value_bands` metafield value, as the function would read it.
+pub(crate) fn validate_config(value: &str) -> std::result::Result<(), String> {
+    let config = Configuration::from_str(value).map_err(|err| err.to_string())?;
+    // The function skips these bands and applies the others, so they are only warnings.
+    for err in config.band_errors() {
+        err.log("skip_entry");
+    }
+    Ok(())
 }
 
 #[shopify_function_target(query_path = "src/run.graphql", schema_path = "schema.graphql")]
 fn run(input: input::ResponseData) -> Result<output::FunctionRunResult> {
+    evaluate(input, &mut Explanation::default())
+}
+
+/// The body of `run`. Each decision is recorded in `explanation` for the `explain` command.
+pub(crate) fn evaluate(input: input::ResponseData, explanation: &mut Explanation) -> Result<output::FunctionRunResult> {
     let mut discounts: Vec<output::Discount> = vec![];
     let no_discount = output::FunctionRunResult {
         discounts: vec![],
@@ -61,70 +890,164 @@ fn run(input: input::ResponseData) -> Result<output::FunctionRunResult> {
 
     // Parse the configuration from discountNode metafield
     let config = match input.discount_node.metafield {
-        Some(input::InputDiscountNodeMetafield { value }) => Configuration::from_str(&value),
-        None => return Ok(no_discount),
+        Some(input::InputDiscountNodeMetafield { value }) => match Configuration::from_str(&value) {
+            Ok(config) => config,
+            Err(err) => {
+                err.log("no_discount");
+                explanation.note(|| format!("Configuration rejected, no discount: {}", err));
+                return Ok(no_discount);
+            }
+        },
+        None => {
+            explanation.note(|| "No configuration metafield, no discount.".to_string());
+            return Ok(no_discount);
+        }
     };
 
-    // 1. Calculate total cart value excluding products in the defined collections
-    let mut total_cart_value_excluding_collections = Decimal(0.0);
+    // 1. Calculate the cart totals excluding products in the defined collections
+    let currency = CartCurrency {
+        code: cart::currency(&input.cart.lines),
+        shop_currency: config.shop_currency.clone(),
+        presentment_rate: input.presentment_currency_rate.0,
+    };
+    let mut totals = ThresholdTotals {
+        value: Money::zero(&currency.code),
+        item_count: 0,
+        products: BTreeSet::new(),
+        weight_milligrams: 0,
+    };
 
     for line in input.cart.lines.iter() {
-        // Directly match on InputCartLinesMerchandise
+        // Only product variants outside the excluded collections count toward the total
+        if let Some(reason) = cart::threshold_exclusion(line, &config.collection_ids, &config.threshold_inclusion) {
+            explanation.note(|| format!("Line {} is {} and does not count toward the total.", line.id, reason));
+            continue;
+        }
         let variant = match &line.merchandise {
             input::InputCartLinesMerchandise::ProductVariant(variant) => variant,
             _ => continue,
         };
 
-        // Access the product directly
-        let product = &variant.product;
+        totals.item_count += line.quantity;
+        totals.products.insert(&variant.product.id);
+        if config.include_variant_weight {
+            match unit_weight_milligrams(variant.weight, &variant.weight_unit) {
+                Some(weight) => {
+                    totals.weight_milligrams = totals.weight_milligrams.saturating_add(weight.saturating_mul(line.quantity))
+                }
+                None => explanation.note(|| format!("Line {} has an unreadable weight and adds nothing to the weight.", line.id)),
+            }
+        }
+        let line_value = match cart::line_value(line) {
+            Some(value) => value,
+            None => continue,
+        };
+        explanation.note(|| format!("Line {} adds {} to the total.", line.id, line_value));
+        if let Some(total) = totals.value.checked_add(&line_value) {
+            totals.value = total;
+        }
+    }
+    explanation.note(|| format!("Cart value excluding collections: {}.", totals.value));
+    explanation.note(|| {
+        format!(
+            "Items excluding collections: {} units of {} products.",
+            totals.item_count,
+            totals.products.len()
+        )
+    });
+    if config.include_variant_weight {
+        explanation.note(|| format!("Weight excluding collections: {}.", Threshold::Weight(totals.weight_milligrams)));
+    }
+    let quantity_discounts = quantity_break_discounts(&config, &input.cart.lines, &currency, explanation);
 
-        let is_in_excluded_collection = product
-            .in_collections
+    // 2. Find the corresponding threshold for the cart value among the bands active right now
+    let local_time = &input.shop.local_time;
+    let hours_after = [
+        local_time.hour01, local_time.hour02, local_time.hour03, local_time.hour04,
+        local_time.hour05, local_time.hour06, local_time.hour07, local_time.hour08,
+        local_time.hour09, local_time.hour10, local_time.hour11, local_time.hour12,
+        local_time.hour13, local_time.hour14, local_time.hour15, local_time.hour16,
+        local_time.hour17, local_time.hour18, local_time.hour19, local_time.hour20,
+        local_time.hour21, local_time.hour22, local_time.hour23,
+    ];
+    let now = ShopTime::from_hour_flags(&local_time.date, &hours_after);
+    let customer_tags: Vec<&str> = match input.cart.buyer_identity.as_ref().and_then(|buyer| buyer.customer.as_ref()) {
+        Some(customer) => customer
+            .has_tags
             .iter()
-            .any(|c| c.is_member && config.collection_ids.contains(&c.collection_id));
-
-        if is_in_excluded_collection {
-            continue; // Skip excluded products
-        } else {
-            // Access the price correctly from the line's cost
-            let price: f64 = line.cost.amount_per_quantity.amount.0; // Accessing inner f64 value
+            .filter(|response| response.has_tag)
+            .map(|response| response.tag.as_str())
+            .collect(),
+        None => vec![],
+    };
+    let mut matching_threshold: Option<(&CollectionMapping, Threshold)> = None;
 
-            // Use Decimal's inner value for multiplication
-            let line_value = Decimal(price * (line.quantity as f64)); // Multiply price by quantity
-            total_cart_value_excluding_collections = Decimal(total_cart_value_excluding_collections.0 + line_value.0);
+    for (index, mapping) in config.mapping.iter().enumerate() {
+        if let Some(err) = config.band_error(index, mapping) {
+            err.log("skip_entry");
+            explanation.note(|| format!("Band for {} skipped: {}", mapping.collection, err));
+            continue;
+        }
+        // Without a readable shop date only unscheduled bands can apply
+        let is_active = match &now {
+            Some(now) => mapping.schedule.is_active(now),
+            None => mapping.schedule == Schedule::default(),
+        };
+        if !is_active {
+            explanation.note(|| format!("Band for {} is outside its schedule.", mapping.collection));
+            continue;
+        }
+        if !mapping.is_eligible(&customer_tags) {
+            explanation.note(|| format!("Band for {} does not apply to this customer's tags.", mapping.collection));
+            continue;
         }
-    }
 
-    // 2. Find the corresponding threshold for the cart value
-    let mut matching_threshold: Option<&CollectionMapping> = None;
+        let threshold = match mapping.threshold_in(&currency) {
+            Some(threshold) => threshold,
+            None => continue,
+        };
+        let reached = totals.reaches(&threshold);
+        explanation.note(|| {
+            format!(
+                "Band for {} needs {}: {}.",
+                mapping.collection,
+                threshold,
+                if reached { "reached" } else { "not reached" }
+            )
+        });
 
-    for mapping in config.mapping.iter() {
-        // Update only if the cart value is greater than or equal to the threshold
-        // and the threshold is higher than the current matching threshold
-        if total_cart_value_excluding_collections.0 >= mapping.threshold {
-            match matching_threshold {
-                Some(threshold) if mapping.threshold > threshold.threshold => {
-                    matching_threshold = Some(mapping);
-                }
-                None => {
-                    matching_threshold = Some(mapping);
+        // Update only if the cart reaches the threshold and the threshold is higher
+        // than the current matching threshold. Bands reserved for tagged customers
+        // always win over general bands. Between thresholds of different kinds the
+        // band listed first is kept.
+        if reached {
+            let is_tagged = !mapping.required_customer_tags.is_empty();
+            match &matching_threshold {
+                Some((current_mapping, current)) => {
+                    let current_is_tagged = !current_mapping.required_customer_tags.is_empty();
+                    if (is_tagged, &threshold) > (current_is_tagged, current) {
+                        matching_threshold = Some((mapping, threshold));
+                    }
                 }
-                _ => {}
+                None => matching_threshold = Some((mapping, threshold)),
             }
         }
     }
 
     // If no threshold matched, return no discounts
     let matching_threshold = match matching_threshold {
-        Some(threshold) => threshold,
-        None => return Ok(no_discount),
+        Some((mapping, _)) => mapping,
+        None => {
+            explanation.note(|| "No band matched.".to_string());
+            return Ok(with_quantity_breaks(no_discount, quantity_discounts, config.application_strategy));
+        }
     };
+    explanation.note(|| format!("Chose the band for {}.", matching_threshold.collection));
 
-    // 3. Apply the highest discount to eligible products
-    let mut highest_discount = 0.0;
-    let mut best_discount: Option<output::Discount> = None;
+    // 3. Collect the products in the matched collection along with their metafield discount
+    let band_collections = matching_threshold.collection_ids();
+    let mut eligible_lines: Vec<EligibleLine> = vec![];
 
-    // 1. Iterate over the cart lines and find products in the excluded collection
     for line in input.cart.lines.iter() {
         let variant = match &line.merchandise {
             input::InputCartLinesMerchandise::ProductVariant(variant) => variant,
@@ -133,76 +1056,330 @@ fn run(input: input::ResponseData) -> Result<output::FunctionRunResult> {
 
         let product = &variant.product;
 
-        // Here, we ignore the is_member logic
-        let is_in_excluded_collection = product.in_collections.iter().any(|collection| {
-            collection.collection_id == matching_threshold.collection
-        });
+        // `inCollections` lists every queried collection, so only `isMember` tells
+        // whether the product is actually in the band's collections
+        if !matching_threshold.collection_match.matches(&product.in_collections, &band_collections) {
+            explanation.note(|| format!("Line {} is not in the band's collections.", line.id));
+            continue;
+        }
 
-        if !is_in_excluded_collection {
-            continue; // Skip this product if it is not in the excluded collection
-        }
-
-        // 3. Get the metafield and parse it
-        if let Some(metafield) = &product.metafield {
-            let discount_data: DiscountData = serde_json::from_str(&metafield.value)
-                .expect("Invalid discount metafield format");
-
-            // 4. Find the discount for the matching collection
-            if let Some(discount_entry) = discount_data.collection_discounts.iter().find(|entry| {
-                entry.collection_id == matching_threshold.collection
-            }) {
-                // 5. Track the highest discount
-                if discount_entry.discount > highest_discount {
-                    highest_discount = discount_entry.discount;
-
-                    // Store the best discount to apply
-                    best_discount = Some(output::Discount {
-                        message: Some(format!("{}% off", discount_entry.discount)),
-                        targets: vec![output::Target::ProductVariant(output::ProductVariantTarget {
-                            id: variant.id.clone(),
-                            quantity: Some(line.quantity as i64),
-                        })],
-                        value: output::Value::Percentage(output::Percentage {
-                            value: Decimal(discount_entry.discount),
-                        }),
-                    });
+        // Get the metafield and find the discount for the first band collection the product is in
+        let discount = match &product.metafield {
+            Some(metafield) => match DiscountData::from_str(&metafield.value, &variant.id) {
+                Ok(data) => band_collections
+                    .iter()
+                    .filter(|id| collections::is_member_of(&product.in_collections, id))
+                    .find_map(|id| data.discount_for(id)),
+                Err(err) => {
+                    err.log("skip_line");
+                    explanation.note(|| format!("Line {} skipped: {}", line.id, err));
+                    continue;
                 }
-            }
-        }
-    }
+            },
+            None => None,
+        };
+        explanation.note(|| match discount {
+            Some(discount) => format!("Line {} ({}) is in the collection with a {:?} discount of {}.", line.id, variant.id, discount.kind, discount.value),
+            None => format!("Line {} ({}) is in the collection without a metafield discount.", line.id, variant.id),
+        });
 
-    // 6. Apply the highest discount, if any
-    if let Some(discount) = best_discount {
-        discounts.push(discount);
-    }
+        let unit_price = match cart::unit_price(line) {
+            Some(price) => price,
+            None => continue,
+        };
 
-    Ok(output::FunctionRunResult {
-        discounts,
-        discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
-    })
-}
+        eligible_lines.push(EligibleLine {
+            variant_id: variant.id.clone(),
+            quantity: line.quantity,
+            unit_price,
+            discount: discount.or(matching_threshold.discount),
+        });
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use shopify_function::{run_function_with_input, Result};
+    let target = |line: &EligibleLine| {
+        output::Target::ProductVariant(output::ProductVariantTarget {
+            id: line.variant_id.clone(),
+            quantity: Some(line.quantity),
+        })
+    };
+    let mut strategy = config.application_strategy.unwrap_or(ApplicationStrategy::First);
+    // What each line gets off, counting only the discounts Shopify will apply
+    let mut given_away: Vec<(&EligibleLine, Money)> = vec![];
 
-    #[test]
-    fn test_empty_cart_no_discounts() -> Result<()> {
-        let result = run_function_with_input(
-            run,
-            r#"
-                {
-                    "discountNode": {
-                        "metafield": {
-                            "value": "{\"collection_ids\":[], \"mapping\":[]}"
-                        }
+    // 4. Allocate the discount according to the band's targeting, or the configured mode
+    match (matching_threshold.targeting, config.allocation) {
+        (Some(Targeting::CheapestUnit), _) | (Some(Targeting::MostExpensiveUnit), _) => {
+            // One unit of the line with the lowest or highest unit price; the first line wins a tie
+            let cheapest = matching_threshold.targeting == Some(Targeting::CheapestUnit);
+            let mut selected: Option<(&EligibleLine, DiscountValue)> = None;
+            for line in eligible_lines.iter() {
+                let discount = match line.discount {
+                    Some(discount) if discount.value > 0.0 => discount,
+                    _ => continue,
+                };
+                let better = match &selected {
+                    Some((current, _)) if cheapest => line.unit_price < current.unit_price,
+                    Some((current, _)) => line.unit_price > current.unit_price,
+                    None => true,
+                };
+                if better {
+                    selected = Some((line, discount));
+                }
+            }
+            if let Some((line, discount)) = selected {
+                explanation.note(|| format!("Selected one unit of {} at {}.", line.variant_id, line.unit_price));
+                let unit = output::Target::ProductVariant(output::ProductVariantTarget {
+                    id: line.variant_id.clone(),
+                    quantity: Some(1),
+                });
+                discounts.extend(discount.to_output(&currency, vec![unit]));
+                given_away.extend(discount.reduction(&line.unit_price, 1, &currency).map(|reduction| (line, reduction)));
+            }
+        }
+        (Some(Targeting::HighestRate), _) | (None, AllocationMode::BestSingleLine) => {
+            // Percentages alone are compared by rate, as they always have been. Once a
+            // fixed amount is involved, lines are compared by the money they save.
+            let by_rate = eligible_lines
+                .iter()
+                .filter_map(|line| line.discount)
+                .all(|discount| discount.kind == DiscountKind::Percentage);
+            let mut best: Option<(&EligibleLine, DiscountValue, i64)> = None;
+            for line in eligible_lines.iter() {
+                let discount = match line.discount {
+                    Some(discount) => discount,
+                    None => continue,
+                };
+                let score = if by_rate {
+                    // Compare rates in hundredths of a percent
+                    (discount.value * 100.0).round() as i64
+                } else {
+                    match discount.reduction(&line.unit_price, line.quantity, &currency) {
+                        Some(reduction) => reduction.minor_units(),
+                        None => continue,
+                    }
+                };
+                // Track the highest discount; the first line wins a tie
+                if score > best.map_or(0, |(_, _, highest)| highest) {
+                    best = Some((line, discount, score));
+                }
+            }
+            if let Some((line, discount, _)) = best {
+                explanation.note(|| format!("Best single line: {} with the highest discount.", line.variant_id));
+                discounts.extend(discount.to_output(&currency, vec![target(line)]));
+                given_away.extend(discount.reduction(&line.unit_price, line.quantity, &currency).map(|reduction| (line, reduction)));
+            }
+        }
+        (Some(Targeting::All), _) | (None, AllocationMode::AllEligibleLines) => {
+            // Each line carries its own discount, so by default all of them are applied
+            strategy = config.application_strategy.unwrap_or(ApplicationStrategy::All);
+            let mut line_discounts = vec![];
+            for line in eligible_lines.iter() {
+                match line.discount {
+                    Some(discount) if discount.value > 0.0 => {
+                        let reduction = discount
+                            .reduction(&line.unit_price, line.quantity, &currency)
+                            .unwrap_or_else(|| Money::zero(&currency.code));
+                        line_discounts.extend(discount.to_output(&currency, vec![target(line)]).map(|output| (output, line, reduction)));
+                    }
+                    _ => {}
+                }
+            }
+            // Put the line the strategy should pick first
+            strategy.order(&mut line_discounts, |(_, _, reduction)| reduction.minor_units());
+            let applied = if strategy == ApplicationStrategy::All { line_discounts.len() } else { 1 };
+            for (index, (output, line, reduction)) in line_discounts.into_iter().enumerate() {
+                if index < applied {
+                    given_away.push((line, reduction));
+                }
+                discounts.push(output);
+            }
+            explanation.note(|| format!("All eligible lines: {} discounts, applied with {:?}.", discounts.len(), strategy));
+        }
+        (None, AllocationMode::Uniform) => {
+            let discount = matching_threshold.discount.unwrap_or_else(|| DiscountValue {
+                kind: DiscountKind::Percentage,
+                value: config.uniform_percentage.unwrap_or_else(|| {
+                    eligible_lines
+                        .iter()
+                        .filter_map(|line| line.discount)
+                        .filter(|discount| discount.kind == DiscountKind::Percentage)
+                        .fold(0.0, |highest, discount| f64::max(highest, discount.value))
+                }),
+            });
+            let targets: Vec<output::Target> = eligible_lines.iter().map(target).collect();
+            if discount.value > 0.0 && !targets.is_empty() {
+                explanation.note(|| format!("Uniform {:?} discount of {} on {} lines.", discount.kind, discount.value, targets.len()));
+                discounts.extend(discount.to_output(&currency, targets));
+                if discount.kind == DiscountKind::FixedAmount {
+                    // One amount across all targets, shared out by line value
+                    let values: Vec<Money> = eligible_lines.iter().map(|line| line.unit_price.times(line.quantity)).collect();
+                    let total = values.iter().fold(Money::zero(&currency.code), |total, value| total.checked_add(value).unwrap_or(total));
+                    if let Some(amount) = discount.amount_in(&currency) {
+                        let amount = if amount < total { amount } else { total };
+                        let weights: Vec<i64> = values.iter().map(Money::minor_units).collect();
+                        given_away.extend(eligible_lines.iter().zip(amount.allocate(&weights)));
+                    }
+                } else {
+                    for line in eligible_lines.iter() {
+                        given_away.extend(discount.reduction(&line.unit_price, line.quantity, &currency).map(|reduction| (line, reduction)));
+                    }
+                }
+            }
+        }
+    }
+
+    // 5. Cap what the band gives away. Above the cap the discounts are replaced by fixed
+    // amounts per line, shared out in proportion to what each line would have saved
+    if let Some(cap) = matching_threshold.max_discount_in(&currency) {
+        let total = given_away
+            .iter()
+            .fold(Money::zero(&currency.code), |total, (_, reduction)| total.checked_add(reduction).unwrap_or(total));
+        if total > cap {
+            explanation.note(|| format!("Discount of {} capped at {}.", total, cap));
+            let weights: Vec<i64> = given_away.iter().map(|(_, reduction)| reduction.minor_units()).collect();
+            discounts = given_away
+                .iter()
+                .zip(cap.allocate(&weights))
+                .filter(|(_, portion)| !portion.is_zero())
+                .map(|((line, _), portion)| output::Discount {
+                    message: Some(format!("Discount capped at {}", cap)),
+                    targets: vec![target(line)],
+                    value: output::Value::FixedAmount(output::FixedAmount {
+                        amount: portion.to_decimal(),
+                        applies_to_each_item: Some(false),
+                    }),
+                })
+                .collect();
+            // The portions only add up to the cap when every one of them is applied
+            strategy = ApplicationStrategy::All;
+        }
+    }
+
+    let result = output::FunctionRunResult {
+        discounts,
+        discount_application_strategy: strategy_output(strategy),
+    };
+    Ok(with_quantity_breaks(result, quantity_discounts, config.application_strategy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shopify_function::{run_function_with_input, Result};
+    use crate::run::run::output;
+
+    fn no_discount() -> output::FunctionRunResult {
+        output::FunctionRunResult {
+            discounts: vec![],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        }
+    }
+
+    // Builds a cart line in the band collection 1234 carrying the given product metafield value.
+    fn line(id: u32, amount: &str, metafield: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": format!("gid://shopify/CartLine/{}", id),
+            "quantity": 1,
+            "cost": {
+                "amountPerQuantity": {
+                    "amount": amount,
+                    "currencyCode": "EUR"
+                }
+            },
+            "merchandise": {
+                "__typename": "ProductVariant",
+                "weightUnit": "KILOGRAMS",
+                "id": format!("gid://shopify/ProductVariant/{}", id),
+                "product": {
+                    "isGiftCard": false,
+                    "id": format!("gid://shopify/Product/{}", id),
+                    "inCollections": [
+                        {
+                            "isMember": true,
+                            "collectionId": "gid://shopify/Collection/1234"
+                        }
+                    ],
+                    "quantityBreakCollections": [],
+                    "metafield": {
+                        "value": metafield
+                    }
+                }
+            }
+        })
+    }
+
+    // Moves a line out of the band collection, so it counts toward the band totals.
+    fn outside(mut cart_line: serde_json::Value) -> serde_json::Value {
+        cart_line["merchandise"]["product"]["inCollections"] =
+            serde_json::json!([{ "isMember": false, "collectionId": "gid://shopify/Collection/1234" }]);
+        cart_line["merchandise"]["product"]["metafield"] = serde_json::Value::Null;
+        cart_line
+    }
+
+    fn outside_line(id: u32, amount: &str) -> serde_json::Value {
+        outside(line(id, amount, ""))
+    }
+
+    fn input_with(config: &str, lines: Vec<serde_json::Value>) -> String {
+        serde_json::json!({
+            "discountNode": {
+                "metafield": {
+                    "value": config
+                }
+            },
+            "presentmentCurrencyRate": "1.0",
+            "shop": {
+                "localTime": local_time("2024-06-03", 12)
+            },
+            "cart": {
+                "lines": lines
+            }
+        })
+        .to_string()
+    }
+
+    // `shop.localTime` as queried: the date plus one `timeAfter` flag per full hour.
+    fn local_time(date: &str, hour: u32) -> serde_json::Value {
+        let mut local_time = serde_json::json!({ "date": date });
+        for h in 1..24 {
+            local_time[format!("hour{:02}", h)] = (hour >= h).into();
+        }
+        local_time
+    }
+
+    // Adds a Monday noon `shop.localTime` to a hand-written input.
+    fn with_shop_time(input: &str) -> String {
+        let mut input: serde_json::Value = serde_json::from_str(input).unwrap();
+        input["shop"] = serde_json::json!({ "localTime": local_time("2024-06-03", 12) });
+        input.to_string()
+    }
+
+    const VALID_CONFIG: &str = r#"{"collectionIds":["gid://shopify/Collection/1234"], "mapping":[{"collection":"gid://shopify/Collection/1234", "threshold": 100}]}"#;
+
+    fn discount_of(percentage: u32) -> String {
+        format!(
+            r#"{{"collectionDiscounts": [{{"collection_id": "gid://shopify/Collection/1234", "discount": {}}}]}}"#,
+            percentage
+        )
+    }
+
+    #[test]
+    fn test_empty_cart_no_discounts() -> Result<()> {
+        let result = run_function_with_input(
+            run,
+            &with_shop_time(r#"
+                {
+                    "discountNode": {
+                        "metafield": {
+                            "value": "{\"collectionIds\":[], \"mapping\":[]}"
+                        }
                     },
+                    "presentmentCurrencyRate": "1.0",
                     "cart": {
                         "lines": []
                     }
                 }
-            "#,
+            "#),
         )?;
 
         let expected = output::FunctionRunResult {
@@ -218,37 +1395,75 @@ mod tests {
     fn test_cart_with_valid_products_applies_discount() -> Result<()> {
         let result = run_function_with_input(
             run,
-            r#"
+            &with_shop_time(r#"
                 {
                     "discountNode": {
                         "metafield": {
-                            "value": "{\"collection_ids\":[], \"mapping\":[{\"collection\":\"gid://shopify/Collection/1234\", \"threshold\": 300}]}"
+                            "value": "{\"collectionIds\":[\"gid://shopify/Collection/1234\"], \"mapping\":[{\"collection\":\"gid://shopify/Collection/1234\", \"threshold\": 300}]}"
                         }
                     },
+                    "presentmentCurrencyRate": "1.0",
                     "cart": {
                         "lines": [
                             {
+                                "id": "gid://shopify/CartLine/0",
                                 "quantity": 1,
                                 "cost": {
                                     "amountPerQuantity": {
-                                        "amount": 358.00
+                                        "amount": "358.00",
+                                        "currencyCode": "EUR"
                                     }
                                 },
                                 "merchandise": {
                                     "__typename": "ProductVariant",
+                                    "weightUnit": "KILOGRAMS",
                                     "id": "gid://shopify/ProductVariant/9876",
                                     "product": {
-                                        "inCollections": [],
+                                        "isGiftCard": false,
+                                        "id": "gid://shopify/Product/9876",
+                                        "inCollections": [
+                                            {
+                                                "isMember": true,
+                                                "collectionId": "gid://shopify/Collection/1234"
+                                            }
+                                        ],
+                                        "quantityBreakCollections": [],
                                         "metafield": {
                                             "value": "{\"collectionDiscounts\": [{\"collection_id\": \"gid://shopify/Collection/1234\", \"discount\": 10}]}"
                                         }
                                     }
                                 }
+                            },
+                            {
+                                "id": "gid://shopify/CartLine/1",
+                                "quantity": 1,
+                                "cost": {
+                                    "amountPerQuantity": {
+                                        "amount": "300.00",
+                                        "currencyCode": "EUR"
+                                    }
+                                },
+                                "merchandise": {
+                                    "__typename": "ProductVariant",
+                                    "weightUnit": "KILOGRAMS",
+                                    "id": "gid://shopify/ProductVariant/5432",
+                                    "product": {
+                                        "isGiftCard": false,
+                                        "id": "gid://shopify/Product/5432",
+                                        "inCollections": [
+                                            {
+                                                "isMember": false,
+                                                "collectionId": "gid://shopify/Collection/1234"
+                                            }
+                                        ],
+                                        "quantityBreakCollections": []
+                                    }
+                                }
                             }
                         ]
                     }
                 }
-            "#,
+            "#),
         )?;
 
         let expected = output::FunctionRunResult {
@@ -273,32 +1488,43 @@ mod tests {
     fn test_cart_with_excluded_products_no_discount() -> Result<()> {
         let result = run_function_with_input(
             run,
-            r#"
+            &with_shop_time(r#"
                 {
                     "discountNode": {
                         "metafield": {
-                            "value": "{\"collection_ids\":[\"gid://shopify/Collection/987\"], \"mapping\":[{\"collection\":\"gid://shopify/Collection/1234\", \"threshold\": 300}]}"
+                            "value": "{\"collectionIds\":[\"gid://shopify/Collection/987\", \"gid://shopify/Collection/1234\"], \"mapping\":[{\"collection\":\"gid://shopify/Collection/1234\", \"threshold\": 300}]}"
                         }
                     },
+                    "presentmentCurrencyRate": "1.0",
                     "cart": {
                         "lines": [
                             {
+                                "id": "gid://shopify/CartLine/0",
                                 "quantity": 1,
                                 "cost": {
                                     "amountPerQuantity": {
-                                        "amount": 358.00
+                                        "amount": "358.00",
+                                        "currencyCode": "EUR"
                                     }
                                 },
                                 "merchandise": {
                                     "__typename": "ProductVariant",
+                                    "weightUnit": "KILOGRAMS",
                                     "id": "gid://shopify/ProductVariant/9876",
                                     "product": {
+                                        "isGiftCard": false,
+                                        "id": "gid://shopify/Product/9876",
                                         "inCollections": [
+                                            {
+                                                "isMember": false,
+                                                "collectionId": "gid://shopify/Collection/1234"
+                                            },
                                             {
                                                 "isMember": true,
                                                 "collectionId": "gid://shopify/Collection/987"
                                             }
                                         ],
+                                        "quantityBreakCollections": [],
                                         "metafield": {
                                             "value": "{\"collectionDiscounts\": [{\"collection_id\": \"gid://shopify/Collection/1234\", \"discount\": 10}]}"
                                         }
@@ -308,7 +1534,7 @@ mod tests {
                         ]
                     }
                 }
-            "#,
+            "#),
         )?;
 
         let expected = output::FunctionRunResult {
@@ -319,4 +1545,1146 @@ mod tests {
         assert_eq!(result, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_malformed_config_returns_no_discount() -> Result<()> {
+        let input = input_with("{not json", vec![line(1, "200.0", &discount_of(10))]);
+        let result = run_function_with_input(run, &input)?;
+
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_threshold_returns_no_discount() -> Result<()> {
+        let config = r#"{"collectionIds":["gid://shopify/Collection/1234"], "mapping":[{"collection":"gid://shopify/Collection/1234", "threshold": -5}]}"#;
+        let input = input_with(config, vec![line(1, "200.0", &discount_of(10)), outside_line(2, "200.0")]);
+        let result = run_function_with_input(run, &input)?;
+
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_product_metafield_skips_only_that_line() -> Result<()> {
+        let input = input_with(
+            VALID_CONFIG,
+            vec![
+                line(1, "200.0", "{\"collectionDiscounts\": 12"),
+                line(2, "200.0", &discount_of(10)),
+                outside_line(3, "100.0"),
+            ],
+        );
+        let result = run_function_with_input(run, &input)?;
+
+        let expected = output::FunctionRunResult {
+            discounts: vec![output::Discount {
+                message: Some("10% off".to_string()),
+                targets: vec![output::Target::ProductVariant(output::ProductVariantTarget {
+                    id: "gid://shopify/ProductVariant/2".to_string(),
+                    quantity: Some(1),
+                })],
+                value: output::Value::Percentage(output::Percentage {
+                    value: Decimal(10.0),
+                }),
+            }],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_range_product_discount_skips_only_that_line() -> Result<()> {
+        let input = input_with(
+            VALID_CONFIG,
+            vec![line(1, "200.0", &discount_of(150)), line(2, "200.0", &discount_of(10)), outside_line(3, "100.0")],
+        );
+        let result = run_function_with_input(run, &input)?;
+
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(
+            result.discounts[0].targets,
+            vec![output::Target::ProductVariant(output::ProductVariantTarget {
+                id: "gid://shopify/ProductVariant/2".to_string(),
+                quantity: Some(1),
+            })]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cart_total_on_band_boundary_applies_discount() -> Result<()> {
+        // 7 x 17.65 + 26.45 sums to 149.99999999999997 in f64 but is exactly 150.00.
+        let mut first = outside_line(1, "17.65");
+        first["quantity"] = 7.into();
+        let config = r#"{"collectionIds":["gid://shopify/Collection/1234"], "mapping":[{"collection":"gid://shopify/Collection/1234", "threshold": 150}]}"#;
+        let input = input_with(config, vec![first, outside_line(2, "26.45"), line(3, "10.0", &discount_of(10))]);
+        let result = run_function_with_input(run, &input)?;
+
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].message, Some("10% off".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cart_total_just_below_band_boundary_no_discount() -> Result<()> {
+        let config = r#"{"collectionIds":["gid://shopify/Collection/1234"], "mapping":[{"collection":"gid://shopify/Collection/1234", "threshold": 150}]}"#;
+        let input = input_with(config, vec![outside_line(1, "149.99"), line(2, "10.0", &discount_of(10))]);
+        let result = run_function_with_input(run, &input)?;
+
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    fn config_with_allocation(allocation: &str) -> String {
+        format!(
+            r#"{{"collectionIds":["gid://shopify/Collection/1234"], "mapping":[{{"collection":"gid://shopify/Collection/1234", "threshold": 100}}], {}}}"#,
+            allocation
+        )
+    }
+
+    fn variant_target(id: u32) -> output::Target {
+        output::Target::ProductVariant(output::ProductVariantTarget {
+            id: format!("gid://shopify/ProductVariant/{}", id),
+            quantity: Some(1),
+        })
+    }
+
+    fn mixed_rate_lines() -> Vec<serde_json::Value> {
+        let mut no_metafield = line(3, "50.0", "");
+        no_metafield["merchandise"]["product"]["metafield"] = serde_json::Value::Null;
+        vec![
+            line(1, "100.0", &discount_of(10)),
+            line(2, "100.0", &discount_of(20)),
+            no_metafield,
+            outside_line(4, "100.0"),
+        ]
+    }
+
+    #[test]
+    fn test_best_single_line_allocation_discounts_highest_rate_only() -> Result<()> {
+        let config = config_with_allocation(r#""allocation": "best_single_line""#);
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+
+        let expected = output::FunctionRunResult {
+            discounts: vec![output::Discount {
+                message: Some("20% off".to_string()),
+                targets: vec![variant_target(2)],
+                value: output::Value::Percentage(output::Percentage {
+                    value: Decimal(20.0),
+                }),
+            }],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_all_eligible_lines_allocation_uses_each_line_rate() -> Result<()> {
+        let config = config_with_allocation(r#""allocation": "all_eligible_lines""#);
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+
+        let expected = output::FunctionRunResult {
+            discounts: vec![
+                output::Discount {
+                    message: Some("10% off".to_string()),
+                    targets: vec![variant_target(1)],
+                    value: output::Value::Percentage(output::Percentage {
+                        value: Decimal(10.0),
+                    }),
+                },
+                output::Discount {
+                    message: Some("20% off".to_string()),
+                    targets: vec![variant_target(2)],
+                    value: output::Value::Percentage(output::Percentage {
+                        value: Decimal(20.0),
+                    }),
+                },
+            ],
+            discount_application_strategy: output::DiscountApplicationStrategy::ALL,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    fn strategy_of(result: &output::FunctionRunResult) -> (Vec<Option<String>>, &output::DiscountApplicationStrategy) {
+        let messages = result.discounts.iter().map(|discount| discount.message.clone()).collect();
+        (messages, &result.discount_application_strategy)
+    }
+
+    #[test]
+    fn test_first_strategy_puts_biggest_saving_first() -> Result<()> {
+        let config = config_with_allocation(r#""allocation": "all_eligible_lines", "applicationStrategy": "first""#);
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+
+        assert_eq!(
+            strategy_of(&result),
+            (
+                vec![Some("20% off".to_string()), Some("10% off".to_string())],
+                &output::DiscountApplicationStrategy::FIRST
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_maximum_strategy_compares_savings_across_kinds() -> Result<()> {
+        // 30% of 40.00 saves 12.00, 15.00 off saves 15.00
+        let config = config_with_allocation(r#""allocation": "all_eligible_lines", "applicationStrategy": "MAXIMUM""#);
+        let lines = vec![
+            line(1, "40.0", &discount_of(30)),
+            line(2, "100.0", &fixed_discount_of("fixed_amount", 15)),
+            outside_line(3, "100.0"),
+        ];
+        let result = run_function_with_input(run, &input_with(&config, lines))?;
+
+        assert_eq!(
+            strategy_of(&result),
+            (
+                vec![Some("15.00 EUR off".to_string()), Some("30% off".to_string())],
+                &output::DiscountApplicationStrategy::MAXIMUM
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_all_strategy_keeps_line_order() -> Result<()> {
+        let config = config_with_allocation(r#""allocation": "all_eligible_lines", "applicationStrategy": "all""#);
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+
+        assert_eq!(
+            strategy_of(&result),
+            (
+                vec![Some("10% off".to_string()), Some("20% off".to_string())],
+                &output::DiscountApplicationStrategy::ALL
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_configured_strategy_overrides_allocation_default() -> Result<()> {
+        let config = config_with_allocation(r#""allocation": "best_single_line", "applicationStrategy": "all""#);
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+        assert_eq!(
+            strategy_of(&result),
+            (vec![Some("20% off".to_string())], &output::DiscountApplicationStrategy::ALL)
+        );
+
+        let config = config_with_allocation(r#""applicationStrategy": "best""#);
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_uniform_allocation_with_configured_percentage() -> Result<()> {
+        let config = config_with_allocation(r#""allocation": "uniform", "uniformPercentage": 15"#);
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+
+        let expected = output::FunctionRunResult {
+            discounts: vec![output::Discount {
+                message: Some("15% off".to_string()),
+                targets: vec![variant_target(1), variant_target(2), variant_target(3)],
+                value: output::Value::Percentage(output::Percentage {
+                    value: Decimal(15.0),
+                }),
+            }],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_uniform_allocation_defaults_to_highest_rate() -> Result<()> {
+        let config = config_with_allocation(r#""allocation": "uniform""#);
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].message, Some("20% off".to_string()));
+        assert_eq!(result.discounts[0].targets.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_allocation_mode_returns_no_discount() -> Result<()> {
+        let config = config_with_allocation(r#""allocation": "everything""#);
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    fn fixed_discount_of(kind: &str, amount: u32) -> String {
+        format!(
+            r#"{{"collectionDiscounts": [{{"collection_id": "gid://shopify/Collection/1234", "discount": {}, "kind": "{}"}}]}}"#,
+            amount, kind
+        )
+    }
+
+    #[test]
+    fn test_fixed_amount_per_unit_product_discount() -> Result<()> {
+        let input = input_with(
+            VALID_CONFIG,
+            vec![line(1, "120.0", &fixed_discount_of("fixed_amount_per_unit", 10)), outside_line(2, "120.0")],
+        );
+        let result = run_function_with_input(run, &input)?;
+
+        let expected = output::FunctionRunResult {
+            discounts: vec![output::Discount {
+                message: Some("10.00 EUR off each item".to_string()),
+                targets: vec![variant_target(1)],
+                value: output::Value::FixedAmount(output::FixedAmount {
+                    amount: Decimal(10.0),
+                    applies_to_each_item: Some(true),
+                }),
+            }],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_band_fixed_amount_spread_across_collection() -> Result<()> {
+        let config = config_with_allocation(r#""allocation": "uniform", "uniformPercentage": 5"#).replace(
+            r#""threshold": 100}"#,
+            r#""threshold": 100, "discount": {"kind": "fixed_amount", "value": 25}}"#,
+        );
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+
+        let expected = output::FunctionRunResult {
+            discounts: vec![output::Discount {
+                message: Some("25.00 EUR off".to_string()),
+                targets: vec![variant_target(1), variant_target(2), variant_target(3)],
+                value: output::Value::FixedAmount(output::FixedAmount {
+                    amount: Decimal(25.0),
+                    applies_to_each_item: Some(false),
+                }),
+            }],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_band_discount_covers_lines_without_metafield_entry() -> Result<()> {
+        let config = config_with_allocation(r#""allocation": "all_eligible_lines""#).replace(
+            r#""threshold": 100}"#,
+            r#""threshold": 100, "discount": {"kind": "fixed_amount_per_unit", "value": 5}}"#,
+        );
+        let result = run_function_with_input(run, &input_with(&config, mixed_rate_lines()))?;
+
+        assert_eq!(result.discounts.len(), 3);
+        assert_eq!(result.discounts[2].targets, vec![variant_target(3)]);
+        assert_eq!(
+            result.discounts[2].value,
+            output::Value::FixedAmount(output::FixedAmount {
+                amount: Decimal(5.0),
+                applies_to_each_item: Some(true),
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_amount_is_converted_to_cart_currency() -> Result<()> {
+        // €10 at 1.0834 USD/EUR is $10.834, rounded down to $10.83.
+        let mut lines = vec![line(1, "200.0", &fixed_discount_of("fixed_amount_per_unit", 10)), outside_line(2, "200.0")];
+        for cart_line in lines.iter_mut() {
+            cart_line["cost"]["amountPerQuantity"]["currencyCode"] = "USD".into();
+        }
+        let config = r#"{"collectionIds":["gid://shopify/Collection/1234"], "shopCurrency":"EUR", "mapping":[{"collection":"gid://shopify/Collection/1234", "threshold": 100}]}"#;
+        let mut input: serde_json::Value = serde_json::from_str(&input_with(config, lines)).unwrap();
+        input["presentmentCurrencyRate"] = "1.0834".into();
+        let result = run_function_with_input(run, &input.to_string())?;
+
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].message, Some("10.83 USD off each item".to_string()));
+        assert_eq!(
+            result.discounts[0].value,
+            output::Value::FixedAmount(output::FixedAmount {
+                amount: Decimal(10.83),
+                applies_to_each_item: Some(true),
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_best_single_line_compares_mixed_kinds_by_reduction() -> Result<()> {
+        // 20% of 50.00 saves 10.00, 15.00 off each unit saves 15.00.
+        let input = input_with(
+            VALID_CONFIG,
+            vec![
+                line(1, "50.0", &discount_of(20)),
+                line(2, "100.0", &fixed_discount_of("fixed_amount_per_unit", 15)),
+                outside_line(3, "100.0"),
+            ],
+        );
+        let result = run_function_with_input(run, &input)?;
+
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].targets, vec![variant_target(2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_negative_fixed_amount_skips_line() -> Result<()> {
+        let negative = r#"{"collectionDiscounts": [{"collection_id": "gid://shopify/Collection/1234", "discount": -5, "kind": "fixed_amount"}]}"#;
+        let input = input_with(VALID_CONFIG, vec![line(1, "200.0", negative), outside_line(2, "200.0")]);
+        let result = run_function_with_input(run, &input)?;
+
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    // Builds an input for a line of `amount` in `currency` outside the collection of a €150 band.
+    fn foreign_cart(amount: &str, currency: &str, rate: &str, thresholds: &str) -> String {
+        let mut lines = vec![outside_line(1, amount), line(2, "10.0", &discount_of(10))];
+        for cart_line in lines.iter_mut() {
+            cart_line["cost"]["amountPerQuantity"]["currencyCode"] = currency.into();
+        }
+        let config = format!(
+            r#"{{"collectionIds":["gid://shopify/Collection/1234"], "shopCurrency":"EUR", "mapping":[{{"collection":"gid://shopify/Collection/1234", "threshold": 150, "thresholds": {}}}]}}"#,
+            thresholds
+        );
+        let mut input: serde_json::Value = serde_json::from_str(&input_with(&config, lines)).unwrap();
+        input["presentmentCurrencyRate"] = rate.into();
+        input.to_string()
+    }
+
+    #[test]
+    fn test_shop_currency_threshold_is_converted_with_presentment_rate() -> Result<()> {
+        // €150 at 1.0834 USD/EUR is $162.51.
+        let below = run_function_with_input(run, &foreign_cart("162.50", "USD", "1.0834", "{}"))?;
+        assert_eq!(below, no_discount());
+
+        let above = run_function_with_input(run, &foreign_cart("162.51", "USD", "1.0834", "{}"))?;
+        assert_eq!(above.discounts.len(), 1);
+
+        // €150 at 0.8561 GBP/EUR is £128.415, which rounds up to £128.42.
+        let below = run_function_with_input(run, &foreign_cart("128.41", "GBP", "0.8561", "{}"))?;
+        assert_eq!(below, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_currency_threshold_wins_over_conversion() -> Result<()> {
+        // The converted threshold would be CHF 141.30, but the band declares CHF 140.
+        let result = run_function_with_input(run, &foreign_cart("140.00", "CHF", "0.942", r#"{"CHF": 140}"#))?;
+        assert_eq!(result.discounts.len(), 1);
+
+        let result = run_function_with_input(run, &foreign_cart("139.99", "CHF", "0.942", r#"{"CHF": 140}"#))?;
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_eur_cart_uses_declared_threshold() -> Result<()> {
+        let result = run_function_with_input(run, &foreign_cart("150.00", "EUR", "1.0", r#"{"USD": 170}"#))?;
+        assert_eq!(result.discounts.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shop_currency_must_be_a_currency_code() {
+        let config = r#"{"collectionIds":["gid://shopify/Collection/1234"], "shopCurrency":"euro", "mapping":[{"collection":"gid://shopify/Collection/1234", "threshold": 150}]}"#;
+        assert_eq!(
+            validate_config(config),
+            Err("invalid value for shopCurrency: euro is not an ISO 4217 currency code".to_string())
+        );
+    }
+
+    fn scheduled_input(schedule: &str, date: &str, hour: u32) -> String {
+        let config = format!(
+            r#"{{"collectionIds":["gid://shopify/Collection/1234"], "mapping":[{{"collection":"gid://shopify/Collection/1234", "threshold": 100, {}}}]}}"#,
+            schedule
+        );
+        let mut input: serde_json::Value =
+            serde_json::from_str(&input_with(&config, vec![line(1, "200.0", &discount_of(10)), outside_line(2, "200.0")])).unwrap();
+        input["shop"]["localTime"] = local_time(date, hour);
+        input.to_string()
+    }
+
+    #[test]
+    fn test_band_outside_its_date_window_is_skipped() -> Result<()> {
+        let window = r#""startsAt": "2024-11-29T09:00:00", "endsAt": "2024-12-03""#;
+
+        let before = run_function_with_input(run, &scheduled_input(window, "2024-11-29", 8))?;
+        assert_eq!(before, no_discount());
+
+        let during = run_function_with_input(run, &scheduled_input(window, "2024-11-29", 9))?;
+        assert_eq!(during.discounts.len(), 1);
+
+        let after = run_function_with_input(run, &scheduled_input(window, "2024-12-03", 0))?;
+        assert_eq!(after, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_band_limited_to_weekdays_and_hours() -> Result<()> {
+        let window = r#""daysOfWeek": ["saturday", "sunday"], "hours": {"start": 10, "end": 18}"#;
+
+        // 2024-11-30 is a Saturday, 2024-12-02 a Monday.
+        let saturday = run_function_with_input(run, &scheduled_input(window, "2024-11-30", 10))?;
+        assert_eq!(saturday.discounts.len(), 1);
+
+        let saturday_evening = run_function_with_input(run, &scheduled_input(window, "2024-11-30", 18))?;
+        assert_eq!(saturday_evening, no_discount());
+
+        let monday = run_function_with_input(run, &scheduled_input(window, "2024-12-02", 12))?;
+        assert_eq!(monday, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_schedule_returns_no_discount() -> Result<()> {
+        let result = run_function_with_input(run, &scheduled_input(r#""startsAt": "tomorrow""#, "2024-11-30", 12))?;
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    // General band: 10% above 100. VIP band: 25% from 0. Employees are excluded from the general band.
+    const TAGGED_CONFIG: &str = r#"{
+        "collectionIds": ["gid://shopify/Collection/1234"],
+        "customerTags": ["VIP", "EMPLOYEE"],
+        "mapping": [
+            {"collection": "gid://shopify/Collection/1234", "threshold": 100,
+             "discount": {"value": 10}, "excludedCustomerTags": ["EMPLOYEE"]},
+            {"collection": "gid://shopify/Collection/1234", "threshold": 0,
+             "discount": {"value": 25}, "requiredCustomerTags": ["VIP"]}
+        ]
+    }"#;
+
+    fn tagged_input(amount: &str, buyer_identity: serde_json::Value) -> String {
+        let mut no_metafield = line(2, "10.0", "");
+        no_metafield["merchandise"]["product"]["metafield"] = serde_json::Value::Null;
+        let mut input: serde_json::Value =
+            serde_json::from_str(&input_with(TAGGED_CONFIG, vec![outside_line(1, amount), no_metafield])).unwrap();
+        input["cart"]["buyerIdentity"] = buyer_identity;
+        input.to_string()
+    }
+
+    fn customer_with(tags: &[(&str, bool)]) -> serde_json::Value {
+        let has_tags: Vec<serde_json::Value> = tags
+            .iter()
+            .map(|(tag, has_tag)| serde_json::json!({ "tag": tag, "hasTag": has_tag }))
+            .collect();
+        serde_json::json!({ "customer": { "hasTags": has_tags } })
+    }
+
+    fn discount_message(result: &output::FunctionRunResult) -> Option<String> {
+        result.discounts.first().and_then(|discount| discount.message.clone())
+    }
+
+    #[test]
+    fn test_guest_checkout_only_gets_general_bands() -> Result<()> {
+        // No buyer identity at all.
+        let result = run_function_with_input(run, &tagged_input("150.0", serde_json::Value::Null))?;
+        assert_eq!(discount_message(&result), Some("10% off".to_string()));
+
+        // A buyer identity without a customer.
+        let guest = serde_json::json!({ "customer": null });
+        let result = run_function_with_input(run, &tagged_input("150.0", guest.clone()))?;
+        assert_eq!(discount_message(&result), Some("10% off".to_string()));
+
+        let result = run_function_with_input(run, &tagged_input("50.0", guest))?;
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tagged_customer_gets_own_rate_and_bypasses_threshold() -> Result<()> {
+        let vip = customer_with(&[("VIP", true), ("EMPLOYEE", false)]);
+
+        let result = run_function_with_input(run, &tagged_input("50.0", vip.clone()))?;
+        assert_eq!(discount_message(&result), Some("25% off".to_string()));
+
+        let result = run_function_with_input(run, &tagged_input("150.0", vip))?;
+        assert_eq!(discount_message(&result), Some("25% off".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_excluded_customer_tag_skips_band() -> Result<()> {
+        let employee = customer_with(&[("VIP", false), ("EMPLOYEE", true)]);
+        let result = run_function_with_input(run, &tagged_input("150.0", employee))?;
+        assert_eq!(result, no_discount());
+
+        let regular = customer_with(&[("VIP", false), ("EMPLOYEE", false)]);
+        let result = run_function_with_input(run, &tagged_input("150.0", regular))?;
+        assert_eq!(discount_message(&result), Some("10% off".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_band_tags_must_be_queried() {
+        let config = r#"{"collectionIds":["gid://shopify/Collection/1234"], "customerTags": ["VIP"], "mapping":[{"collection":"gid://shopify/Collection/1234", "threshold": 0, "requiredCustomerTags": ["WHOLESALE"]}]}"#;
+        assert_eq!(
+            Configuration::from_str(config),
+            Err(DiscountError::InvalidValue {
+                field: "mapping[0].customerTags".to_string(),
+                reason: "WHOLESALE is not listed in the top-level customerTags".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_configuration_errors() {
+        assert!(matches!(
+            Configuration::from_str("[]"),
+            Err(DiscountError::ConfigParse(_))
+        ));
+        assert_eq!(
+            Configuration::from_str(r#"{"collectionIds":[], "mapping":[{"collection":"", "threshold": 10}]}"#),
+            Err(DiscountError::InvalidValue {
+                field: "mapping[0].collection".to_string(),
+                reason: "collection id must not be empty".to_string(),
+            })
+        );
+        assert!(matches!(
+            Configuration::from_str(r#"{"collectionIds":["gid://shopify/Collection/1234"], "mapping":[{"collection":"gid://shopify/Collection/1234", "threshold": 10, "thresholds": {"usd": 12}}]}"#),
+            Err(DiscountError::InvalidValue { .. })
+        ));
+        assert!(Configuration::from_str(VALID_CONFIG).is_ok());
+    }
+
+    #[test]
+    fn test_band_with_unlisted_collection_is_skipped_alone() -> Result<()> {
+        let config = r#"{"collectionIds":["gid://shopify/Collection/1234"], "mapping":[
+            {"collection":"gid://shopify/Collection/1234", "collections": ["gid://shopify/Collection/5678"], "threshold": 100, "discount": {"kind": "percentage", "value": 30}},
+            {"collection":"gid://shopify/Collection/1234", "threshold": 50}]}"#;
+        let parsed = Configuration::from_str(config).unwrap();
+        assert_eq!(
+            parsed.band_errors(),
+            vec![DiscountError::InvalidValue {
+                field: "mapping[0].collections".to_string(),
+                reason: "gid://shopify/Collection/5678 is missing from collectionIds, so its membership is never queried".to_string(),
+            }]
+        );
+        assert_eq!(validate_config(config), Ok(()));
+
+        let lines = vec![line(1, "10.0", &discount_of(10)), outside_line(2, "150.0")];
+        let mut explanation = Explanation::enabled();
+        let result = evaluate(serde_json::from_str(&input_with(config, lines)).unwrap(), &mut explanation).unwrap();
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].message, Some("10% off".to_string()));
+        assert!(explanation.notes().iter().any(|note| note.starts_with("Band for gid://shopify/Collection/1234 skipped: invalid value for mapping[0].collections")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_product_metafield_errors() {
+        assert!(matches!(
+            DiscountData::from_str("oops", "gid://shopify/ProductVariant/1"),
+            Err(DiscountError::ProductMetafieldParse { .. })
+        ));
+        assert!(matches!(
+            DiscountData::from_str(&discount_of(101), "gid://shopify/ProductVariant/1"),
+            Err(DiscountError::InvalidValue { .. })
+        ));
+        assert!(DiscountData::from_str(&discount_of(25), "gid://shopify/ProductVariant/1").is_ok());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert!(validate_config(VALID_CONFIG).is_ok());
+        assert_eq!(
+            validate_config(r#"{"collectionIds":[], "mapping":[{"collection":"", "threshold": 10}]}"#),
+            Err("invalid value for mapping[0].collection: collection id must not be empty".to_string())
+        );
+    }
+
+    #[test]
+    fn test_explain_records_chosen_band() {
+        let input = input_with(VALID_CONFIG, vec![outside_line(1, "150.00"), line(2, "10.0", &discount_of(10))]);
+        let mut explanation = Explanation::enabled();
+        let result = evaluate(serde_json::from_str(&input).unwrap(), &mut explanation).unwrap();
+        assert_eq!(result.discounts.len(), 1);
+        let notes = explanation.notes();
+        assert!(notes.iter().any(|note| note.starts_with("Cart value excluding collections: 150.00 EUR")));
+        assert!(notes.iter().any(|note| note.starts_with("Chose the band for gid://shopify/Collection/1234")));
+    }
+
+    fn with_memberships(mut cart_line: serde_json::Value, memberships: &[(&str, bool)]) -> serde_json::Value {
+        cart_line["merchandise"]["product"]["inCollections"] = memberships
+            .iter()
+            .map(|(id, is_member)| serde_json::json!({ "collectionId": id, "isMember": is_member }))
+            .collect();
+        cart_line
+    }
+
+    #[test]
+    fn test_non_member_product_is_not_targeted() -> Result<()> {
+        // The collection is queried for every product, but only line 2 is in it
+        let lines = vec![
+            with_memberships(line(1, "100.0", &discount_of(30)), &[("gid://shopify/Collection/1234", false)]),
+            line(2, "100.0", &discount_of(10)),
+        ];
+        let result = run_function_with_input(run, &input_with(VALID_CONFIG, lines))?;
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].targets, vec![variant_target(2)]);
+        assert_eq!(result.discounts[0].message, Some("10% off".to_string()));
+
+        let lines = vec![with_memberships(line(1, "150.0", &discount_of(30)), &[("gid://shopify/Collection/1234", false)])];
+        let result = run_function_with_input(run, &input_with(VALID_CONFIG, lines))?;
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    const MULTI_COLLECTION_DISCOUNTS: &str = r#"{"collectionDiscounts": [
+        {"collection_id": "gid://shopify/Collection/1234", "discount": 10},
+        {"collection_id": "gid://shopify/Collection/5678", "discount": 20}
+    ]}"#;
+
+    fn multi_collection_config(collection_match: &str) -> String {
+        format!(
+            r#"{{"collectionIds":["gid://shopify/Collection/1234", "gid://shopify/Collection/5678"], "allocation": "all_eligible_lines", "mapping":[{{"collection":"gid://shopify/Collection/1234", "collections": ["gid://shopify/Collection/5678"], "collectionMatch": "{}", "threshold": 100}}]}}"#,
+            collection_match
+        )
+    }
+
+    fn multi_collection_lines() -> Vec<serde_json::Value> {
+        vec![
+            with_memberships(
+                line(1, "100.0", MULTI_COLLECTION_DISCOUNTS),
+                &[("gid://shopify/Collection/1234", true), ("gid://shopify/Collection/5678", true)],
+            ),
+            with_memberships(
+                line(2, "100.0", MULTI_COLLECTION_DISCOUNTS),
+                &[("gid://shopify/Collection/1234", false), ("gid://shopify/Collection/5678", true)],
+            ),
+            with_memberships(
+                line(3, "100.0", MULTI_COLLECTION_DISCOUNTS),
+                &[("gid://shopify/Collection/1234", false), ("gid://shopify/Collection/5678", false)],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_match_any_targets_products_in_any_band_collection() -> Result<()> {
+        let result = run_function_with_input(run, &input_with(&multi_collection_config("match_any"), multi_collection_lines()))?;
+        let targeted: Vec<(Vec<output::Target>, Option<String>)> = result
+            .discounts
+            .into_iter()
+            .map(|discount| (discount.targets, discount.message))
+            .collect();
+        // Line 2 is only in the second collection, so it gets that collection's rate
+        assert_eq!(
+            targeted,
+            vec![
+                (vec![variant_target(1)], Some("10% off".to_string())),
+                (vec![variant_target(2)], Some("20% off".to_string())),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_all_requires_every_band_collection() -> Result<()> {
+        let result = run_function_with_input(run, &input_with(&multi_collection_config("match_all"), multi_collection_lines()))?;
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].targets, vec![variant_target(1)]);
+        Ok(())
+    }
+
+    fn capped_config(allocation: &str, cap: &str) -> String {
+        format!(
+            r#"{{"collectionIds":["gid://shopify/Collection/1234"], "allocation": "{}", "uniformPercentage": 20, "mapping":[{{"collection":"gid://shopify/Collection/1234", "threshold": 100, "maxDiscountAmount": {}}}]}}"#,
+            allocation, cap
+        )
+    }
+
+    fn capped_portion(id: u32, amount: f64) -> output::Discount {
+        output::Discount {
+            message: Some("Discount capped at 50.00 EUR".to_string()),
+            targets: vec![variant_target(id)],
+            value: output::Value::FixedAmount(output::FixedAmount {
+                amount: Decimal(amount),
+                applies_to_each_item: Some(false),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_cap_spreads_fixed_amounts_that_total_the_cap() -> Result<()> {
+        // 20% of 300.00 would be 60.00; the cap splits 50.00 over three equal lines
+        let lines = vec![
+            line(1, "100.0", &discount_of(20)),
+            line(2, "100.0", &discount_of(20)),
+            line(3, "100.0", &discount_of(20)),
+            outside_line(4, "100.0"),
+        ];
+        let result = run_function_with_input(run, &input_with(&capped_config("uniform", "50"), lines))?;
+        let expected = output::FunctionRunResult {
+            discounts: vec![capped_portion(1, 16.67), capped_portion(2, 16.67), capped_portion(3, 16.66)],
+            discount_application_strategy: output::DiscountApplicationStrategy::ALL,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cap_weights_portions_by_saving() -> Result<()> {
+        // 10% of 200.00 and 30% of 100.00 save 20.00 and 30.00, 50.00 in total, capped to 40.00
+        let lines = vec![line(1, "200.0", &discount_of(10)), line(2, "100.0", &discount_of(30)), outside_line(3, "100.0")];
+        let result = run_function_with_input(run, &input_with(&capped_config("all_eligible_lines", "40"), lines))?;
+        let amounts: Vec<(Vec<output::Target>, output::Value)> =
+            result.discounts.into_iter().map(|discount| (discount.targets, discount.value)).collect();
+        let fixed = |amount: f64| {
+            output::Value::FixedAmount(output::FixedAmount {
+                amount: Decimal(amount),
+                applies_to_each_item: Some(false),
+            })
+        };
+        assert_eq!(amounts, vec![(vec![variant_target(1)], fixed(16.0)), (vec![variant_target(2)], fixed(24.0))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cap_leaves_smaller_discounts_alone() -> Result<()> {
+        let lines = vec![line(1, "200.0", &discount_of(30)), outside_line(2, "100.0")];
+        let result = run_function_with_input(run, &input_with(&capped_config("best_single_line", "60"), lines.clone()))?;
+        assert_eq!(result.discounts[0].message, Some("30% off".to_string()));
+        assert_eq!(result.discount_application_strategy, output::DiscountApplicationStrategy::FIRST);
+
+        let result = run_function_with_input(run, &input_with(&capped_config("best_single_line", "50"), lines))?;
+        assert_eq!(result.discounts, vec![capped_portion(1, 50.0)]);
+
+        assert!(matches!(
+            Configuration::from_str(&capped_config("uniform", "-5")),
+            Err(DiscountError::InvalidValue { .. })
+        ));
+        Ok(())
+    }
+
+    const QUANTITY_BREAKS: &str = r#"[{"minimumQuantity": 3, "discount": {"value": 10}}, {"minimumQuantity": 6, "discount": {"value": 15}}]"#;
+
+    fn quantity_break_config(scope: &str) -> String {
+        format!(
+            r#"{{"collectionIds":[], "quantityBreakCollectionIds": ["gid://shopify/Collection/9"], "quantityBreaks":[{{"scope": "{}", {} "breaks": {}}}]}}"#,
+            scope,
+            if scope == "collection" { r#""collection": "gid://shopify/Collection/9","# } else { "" },
+            QUANTITY_BREAKS
+        )
+    }
+
+    // A line of `quantity` units of a variant of a product, optionally in the quantity-break collection.
+    fn units_line(line_id: u32, variant: u32, product: u32, quantity: i64, in_break_collection: bool) -> serde_json::Value {
+        let mut cart_line = line(line_id, "10.0", "");
+        cart_line["quantity"] = quantity.into();
+        cart_line["merchandise"]["id"] = format!("gid://shopify/ProductVariant/{}", variant).into();
+        cart_line["merchandise"]["product"]["id"] = format!("gid://shopify/Product/{}", product).into();
+        cart_line["merchandise"]["product"]["metafield"] = serde_json::Value::Null;
+        cart_line["merchandise"]["product"]["inCollections"] = serde_json::json!([]);
+        cart_line["merchandise"]["product"]["quantityBreakCollections"] =
+            serde_json::json!([{ "collectionId": "gid://shopify/Collection/9", "isMember": in_break_collection }]);
+        cart_line
+    }
+
+    fn variant_units(id: u32, quantity: i64) -> output::Target {
+        output::Target::ProductVariant(output::ProductVariantTarget {
+            id: format!("gid://shopify/ProductVariant/{}", id),
+            quantity: Some(quantity),
+        })
+    }
+
+    fn break_discount(percentage: f64, targets: Vec<output::Target>) -> output::Discount {
+        output::Discount {
+            message: Some(format!("{}% off", percentage)),
+            targets,
+            value: output::Value::Percentage(output::Percentage {
+                value: Decimal(percentage),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_quantity_breaks_per_variant() -> Result<()> {
+        // Variant 1 is split over two lines and reaches the first break with 4 units
+        let lines = vec![
+            units_line(1, 1, 1, 2, false),
+            units_line(2, 2, 1, 7, false),
+            units_line(3, 1, 1, 2, false),
+            units_line(4, 3, 2, 2, false),
+        ];
+        let result = run_function_with_input(run, &input_with(&quantity_break_config("variant"), lines))?;
+        let expected = output::FunctionRunResult {
+            discounts: vec![
+                break_discount(10.0, vec![variant_units(1, 4)]),
+                break_discount(15.0, vec![variant_units(2, 7)]),
+            ],
+            discount_application_strategy: output::DiscountApplicationStrategy::ALL,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantity_breaks_per_product() -> Result<()> {
+        let lines = vec![units_line(1, 1, 1, 2, false), units_line(2, 2, 1, 1, false), units_line(3, 3, 2, 2, false)];
+        let result = run_function_with_input(run, &input_with(&quantity_break_config("product"), lines))?;
+        let expected = output::FunctionRunResult {
+            discounts: vec![break_discount(10.0, vec![variant_units(1, 2), variant_units(2, 1)])],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantity_breaks_per_collection_count_members_only() -> Result<()> {
+        let lines = vec![units_line(1, 1, 1, 4, true), units_line(2, 2, 2, 5, false), units_line(3, 3, 3, 2, true)];
+        let result = run_function_with_input(run, &input_with(&quantity_break_config("collection"), lines))?;
+        assert_eq!(result.discounts, vec![break_discount(15.0, vec![variant_units(1, 4), variant_units(3, 2)])]);
+
+        let lines = vec![units_line(1, 1, 1, 2, true), units_line(2, 2, 2, 5, false)];
+        let result = run_function_with_input(run, &input_with(&quantity_break_config("collection"), lines))?;
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantity_breaks_apply_next_to_band() -> Result<()> {
+        let config = VALID_CONFIG.replacen(
+            '{',
+            &format!(r#"{{"quantityBreaks": [{{"scope": "variant", "breaks": {}}}],"#, QUANTITY_BREAKS),
+            1,
+        );
+        let bulk = outside(units_line(2, 2, 2, 3, false));
+        let lines = vec![line(1, "150.0", &discount_of(20)), bulk, outside_line(3, "100.0")];
+        let result = run_function_with_input(run, &input_with(&config, lines))?;
+        let messages: Vec<Option<String>> = result.discounts.iter().map(|discount| discount.message.clone()).collect();
+        assert_eq!(messages, vec![Some("20% off".to_string()), Some("10% off".to_string())]);
+        assert_eq!(result.discount_application_strategy, output::DiscountApplicationStrategy::ALL);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantity_break_validation() {
+        let descending = r#"{"collectionIds":[], "quantityBreaks":[{"scope": "variant", "breaks": [{"minimumQuantity": 6, "discount": {"value": 15}}, {"minimumQuantity": 3, "discount": {"value": 10}}]}]}"#;
+        assert_eq!(
+            Configuration::from_str(descending),
+            Err(DiscountError::InvalidValue {
+                field: "quantityBreaks[0].breaks[1].minimumQuantity".to_string(),
+                reason: "3 must be positive and above the previous break".to_string(),
+            })
+        );
+        let unlisted = quantity_break_config("collection").replace(r#""quantityBreakCollectionIds": ["gid://shopify/Collection/9"], "#, "");
+        assert!(matches!(Configuration::from_str(&unlisted), Err(DiscountError::InvalidValue { .. })));
+        assert!(Configuration::from_str(&quantity_break_config("product")).is_ok());
+    }
+
+    fn targeting_config(targeting: &str, percentage: u32) -> String {
+        format!(
+            r#"{{"collectionIds":["gid://shopify/Collection/1234"], "mapping":[{{"collection":"gid://shopify/Collection/1234", "threshold": 100, "targeting": "{}", "discount": {{"value": {}}}}}]}}"#,
+            targeting, percentage
+        )
+    }
+
+    // Lines in the band collection without a metafield entry, so the band discount applies,
+    // followed by a 100.00 line outside it that reaches the band threshold.
+    fn priced_lines(prices: &[(&str, i64)]) -> Vec<serde_json::Value> {
+        let mut lines = band_lines(prices);
+        lines.push(outside_line(9, "100.0"));
+        lines
+    }
+
+    fn band_lines(prices: &[(&str, i64)]) -> Vec<serde_json::Value> {
+        prices
+            .iter()
+            .enumerate()
+            .map(|(index, (amount, quantity))| {
+                let mut cart_line = line(index as u32 + 1, amount, "");
+                cart_line["quantity"] = (*quantity).into();
+                cart_line["merchandise"]["product"]["metafield"] = serde_json::Value::Null;
+                cart_line
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cheapest_unit_targeting_discounts_one_unit() -> Result<()> {
+        let lines = priced_lines(&[("30.0", 2), ("12.5", 3), ("80.0", 1), ("12.5", 1)]);
+        let result = run_function_with_input(run, &input_with(&targeting_config("cheapest_unit", 50), lines))?;
+        let expected = output::FunctionRunResult {
+            discounts: vec![output::Discount {
+                message: Some("50% off".to_string()),
+                targets: vec![variant_units(2, 1)],
+                value: output::Value::Percentage(output::Percentage {
+                    value: Decimal(50.0),
+                }),
+            }],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_most_expensive_unit_targeting_discounts_one_unit() -> Result<()> {
+        let lines = priced_lines(&[("30.0", 2), ("80.0", 1), ("80.0", 4), ("12.5", 3)]);
+        let result = run_function_with_input(run, &input_with(&targeting_config("most_expensive_unit", 20), lines))?;
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].targets, vec![variant_units(2, 1)]);
+        assert_eq!(result.discounts[0].message, Some("20% off".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_all_and_highest_rate_targeting_override_allocation() -> Result<()> {
+        let lines = priced_lines(&[("30.0", 2), ("80.0", 1)]);
+        let result = run_function_with_input(run, &input_with(&targeting_config("all", 10), lines.clone()))?;
+        let targets: Vec<Vec<output::Target>> = result.discounts.into_iter().map(|discount| discount.targets).collect();
+        assert_eq!(targets, vec![vec![variant_units(1, 2)], vec![variant_units(2, 1)]]);
+        assert_eq!(result.discount_application_strategy, output::DiscountApplicationStrategy::ALL);
+
+        let config = targeting_config("highest_rate", 10).replacen('{', r#"{"allocation": "uniform","#, 1);
+        let result = run_function_with_input(run, &input_with(&config, lines))?;
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].targets, vec![variant_units(1, 2)]);
+        Ok(())
+    }
+
+    fn count_band_config(kind: &str, threshold: u32) -> String {
+        format!(
+            r#"{{"collectionIds":["gid://shopify/Collection/9999", "gid://shopify/Collection/1234"], "mapping":[{{"collection":"gid://shopify/Collection/1234", "thresholdKind": "{}", "threshold": {}, "discount": {{"value": 10}}}}]}}"#,
+            kind, threshold
+        )
+    }
+
+    // Lines outside the band collection that make up the band totals, followed by a
+    // band line that takes the discount.
+    fn counted_lines(prices: &[(&str, i64)]) -> Vec<serde_json::Value> {
+        let mut lines: Vec<serde_json::Value> = band_lines(prices).into_iter().map(outside).collect();
+        lines.extend(band_lines(&[("20.0", 1)]).into_iter().map(|mut cart_line| {
+            cart_line["id"] = "gid://shopify/CartLine/9".into();
+            cart_line["merchandise"]["id"] = "gid://shopify/ProductVariant/9".into();
+            cart_line["merchandise"]["product"]["id"] = "gid://shopify/Product/9".into();
+            cart_line
+        }));
+        lines
+    }
+
+    fn excluded(mut cart_line: serde_json::Value) -> serde_json::Value {
+        cart_line["merchandise"]["product"]["inCollections"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({ "isMember": true, "collectionId": "gid://shopify/Collection/9999" }));
+        cart_line
+    }
+
+    #[test]
+    fn test_item_count_threshold_skips_excluded_lines() -> Result<()> {
+        let config = count_band_config("item_count", 5);
+        let mut lines = counted_lines(&[("10.0", 3), ("5.0", 1), ("1.0", 4)]);
+        lines[2] = excluded(lines[2].clone());
+        let result = run_function_with_input(run, &input_with(&config, lines.clone()))?;
+        assert_eq!(result, no_discount());
+
+        lines[1]["quantity"] = 2.into();
+        let result = run_function_with_input(run, &input_with(&config, lines))?;
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].message, Some("10% off".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_distinct_products_threshold_counts_each_product_once() -> Result<()> {
+        let config = count_band_config("distinct_products", 2);
+        let mut lines = counted_lines(&[("10.0", 5), ("12.0", 1), ("1.0", 1)]);
+        lines[1]["merchandise"]["product"]["id"] = "gid://shopify/Product/1".into();
+        lines[2] = excluded(lines[2].clone());
+        let result = run_function_with_input(run, &input_with(&config, lines.clone()))?;
+        assert_eq!(result, no_discount());
+
+        lines.extend(band_lines(&[("0.5", 1)]).into_iter().map(|mut cart_line| {
+            cart_line["id"] = "gid://shopify/CartLine/7".into();
+            cart_line["merchandise"]["product"]["id"] = "gid://shopify/Product/7".into();
+            outside(cart_line)
+        }));
+        let result = run_function_with_input(run, &input_with(&config, lines))?;
+        assert_eq!(result.discounts.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_thresholds_reject_fractions_and_currency_thresholds() {
+        let fractional = count_band_config("item_count", 5).replace(r#""threshold": 5"#, r#""threshold": 2.5"#);
+        assert!(validate_config(&fractional).unwrap_err().contains("mapping[0].threshold"));
+        let per_currency = count_band_config("distinct_products", 2).replace(r#""threshold": 2"#, r#""threshold": 2, "thresholds": {"CHF": 3}"#);
+        assert!(validate_config(&per_currency).unwrap_err().contains("mapping[0].thresholds"));
+        assert!(validate_config(&count_band_config("item_count", 5)).is_ok());
+    }
+
+    fn weighted(mut cart_line: serde_json::Value, weight: f64, unit: &str) -> serde_json::Value {
+        cart_line["merchandise"]["weight"] = weight.into();
+        cart_line["merchandise"]["weightUnit"] = unit.into();
+        cart_line
+    }
+
+    #[test]
+    fn test_weight_threshold_normalizes_units_and_skips_excluded_lines() -> Result<()> {
+        let config = count_band_config("weight", 50).replacen('{', r#"{"includeVariantWeight": true,"#, 1);
+        let mut lines = counted_lines(&[("100.0", 2), ("10.0", 1), ("5.0", 1), ("1.0", 1)]);
+        lines[0] = weighted(lines[0].clone(), 20.0, "KILOGRAMS");
+        lines[1] = weighted(lines[1].clone(), 8000.0, "GRAMS");
+        lines[2] = excluded(weighted(lines[2].clone(), 10.0, "KILOGRAMS"));
+        let result = run_function_with_input(run, &input_with(&config, lines.clone()))?;
+        assert_eq!(result, no_discount());
+
+        // 5 lb is 2.27 kg, taking the cart past 50 kg
+        lines[3] = weighted(lines[3].clone(), 5.0, "POUNDS");
+        let result = run_function_with_input(run, &input_with(&config, lines))?;
+        assert_eq!(result.discounts.len(), 1);
+        assert_eq!(result.discounts[0].message, Some("10% off".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_weight_threshold_requires_variant_weight_query() {
+        let err = validate_config(&count_band_config("weight", 50)).unwrap_err();
+        assert!(err.contains("mapping[0].thresholdKind"));
+        assert_eq!(unit_weight_milligrams(Some(16.0), &input::WeightUnit::OUNCES), Some(453_592));
+        assert_eq!(unit_weight_milligrams(None, &input::WeightUnit::GRAMS), Some(0));
+    }
+
+    #[test]
+    fn test_threshold_inclusion_rules_leave_lines_out_of_band_totals() -> Result<()> {
+        let mut lines = counted_lines(&[("10.0", 2), ("25.0", 1), ("15.0", 1), ("0.0", 1)]);
+        lines[1]["merchandise"]["product"]["isGiftCard"] = true.into();
+        lines[2]["sellingPlanAllocation"] = serde_json::json!({ "sellingPlan": { "id": "gid://shopify/SellingPlan/1" } });
+        let config = count_band_config("item_count", 4);
+        assert_eq!(run_function_with_input(run, &input_with(&config, lines.clone()))?.discounts.len(), 1);
+
+        let strict = config.replacen(
+            '{',
+            r#"{"thresholdInclusion": {"excludeGiftCards": true, "excludeSubscriptions": true, "excludeFreeLines": true},"#,
+            1,
+        );
+        assert_eq!(run_function_with_input(run, &input_with(&strict, lines.clone()))?, no_discount());
+
+        // The gift card still counts when only subscriptions and free lines are left out
+        let lenient = config.replacen('{', r#"{"thresholdInclusion": {"excludeSubscriptions": true, "excludeFreeLines": true},"#, 1);
+        assert_eq!(run_function_with_input(run, &input_with(&lenient, lines.clone()))?, no_discount());
+        lines[0]["quantity"] = 3.into();
+        assert_eq!(run_function_with_input(run, &input_with(&lenient, lines))?.discounts.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_band_with_discount_codes_is_rejected() {
+        let config = r#"{"collectionIds":["gid://shopify/Collection/1234"], "mapping":[{"collection":"gid://shopify/Collection/1234", "threshold": 100,
+            "discountCodes": [{"code": "BANDS15", "percentage": 15}]}]}"#;
+        let err = validate_config(config).unwrap_err();
+        assert!(err.contains("mapping[0].discountCodes"));
+        assert!(validate_config(&config.replace(r#"{"code": "BANDS15", "percentage": 15}"#, "")).is_ok());
+    }
 }
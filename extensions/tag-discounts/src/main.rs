@@ -0,0 +1,22 @@
+//! Customer tag discounts: gives a tagged customer the highest percentage among
+//! the tag discounts managed in app.discount.jsx that apply today.
+
+pub mod run;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    use discount_common::cli::{self, Usage};
+
+    let usage = Usage {
+        name: "tag-discounts",
+        metafield: "$app:tag_discounts.config",
+        explains: "each tag discount was chosen or skipped",
+    };
+    cli::main(&usage, run::evaluate, run::validate_config);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    eprintln!("Please invoke a named export.");
+    std::process::exit(1);
+}
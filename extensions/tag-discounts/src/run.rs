@@ -0,0 +1,479 @@
+use shopify_function::prelude::*;
+use shopify_function::Result;
+use serde::Deserialize;
+use discount_common::codes::DiscountCode;
+use discount_common::collections::{self, CollectionMembership};
+use discount_common::config::{self, ConfigError};
+use discount_common::explain::Explanation;
+use discount_common::schedule::{PickedDate, ShopTime};
+use self::run::{input, output};
+
+/*
+ ---------------------------CUSTOMER TAG DISCOUNTS--------------------------------
+            Applies the tag discounts managed in app.discount.jsx. The admin keeps
+            them in the `discounts` app-installation metafields and mirrors them to
+            the shop's `$app:discounts` metafields, which is what this function reads:
+            tagDiscounts = [{ tag, discountCode, discountPercentage, isD12C }]
+                           a tagged customer gets `discountPercentage` off.
+            scheduleDiscounts = [{ tag, discountCode, discountPercentage, isD12C, date }]
+                           the same, but only on `date` in the shop's local time.
+                           A tag with scheduled days only applies on those days.
+            The percentage is saved as text and has to match the last two digits of
+            the code, as the admin requires.
+            When several discounts apply the highest percentage wins, the first one
+            listed on a tie. It is shown to the buyer under its discount code.
+            isD12C = the entry is an order discount: every product in the cart is
+                     discounted, whatever `collectionIds` says. Product discount
+                     functions cannot target the order subtotal, so the percentage
+                     goes on every product line, which takes the same amount off.
+                     Other entries are product discounts, limited by `collectionIds`.
+
+            Read from the discount node metafield `$app:tag_discounts.config`:
+            customerTags = every tag used above. Passed to the input query as
+                           `$customerTags`; other tags are never matched.
+            collectionIds = optional collections the product discounts are limited to.
+                           Without any, every product in the cart is discounted.
+                           Passed to the input query as `$collectionIds`.
+---------------------------------------------------------------------------------
+*/
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Configuration {
+    customer_tags: Vec<String>,
+    #[serde(default)]
+    collection_ids: Vec<String>,
+}
+
+impl Configuration {
+    fn from_str(value: &str) -> std::result::Result<Self, ConfigError> {
+        let config: Configuration = config::parse(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> std::result::Result<(), ConfigError> {
+        if let Some(index) = self.customer_tags.iter().position(|tag| tag.is_empty()) {
+            return Err(ConfigError::invalid(format!("customerTags[{}]", index), "tag must not be empty"));
+        }
+        if let Some(index) = self.collection_ids.iter().position(|id| id.is_empty()) {
+            return Err(ConfigError::invalid(format!("collectionIds[{}]", index), "collection id must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+// An entry of the `tagDiscounts` metafield.
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TagDiscount {
+    tag: String,
+    discount_code: String,
+    // The text of the admin's percentage field, e.g. "15".
+    discount_percentage: String,
+    #[serde(default, rename = "isD12C")]
+    is_d12c: bool,
+}
+
+// What a tag discount is applied to.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum DiscountClass {
+    // Products in the configured collections, or every product without any.
+    Product,
+    // Every product in the cart, for `isD12C` entries.
+    Order,
+}
+
+// An entry of the `scheduleDiscounts` metafield: a copy of a tag discount for one day.
+#[derive(Deserialize, PartialEq, Debug)]
+struct ScheduleDiscount {
+    #[serde(flatten)]
+    discount: TagDiscount,
+    // `toISOString()` of the day picked in the admin.
+    date: String,
+}
+
+impl TagDiscount {
+    // The code and its percentage, checked like `validateDiscountCode` in the admin.
+    fn code(&self, field: &str) -> std::result::Result<DiscountCode, ConfigError> {
+        if self.tag.is_empty() {
+            return Err(ConfigError::invalid(format!("{}.tag", field), "tag must not be empty"));
+        }
+        let percentage = self.discount_percentage.trim().parse().map_err(|_| {
+            ConfigError::invalid(
+                format!("{}.discountPercentage", field),
+                format!("{} is not a number", self.discount_percentage),
+            )
+        })?;
+        let code = DiscountCode {
+            code: self.discount_code.clone(),
+            percentage,
+        };
+        code.validate().map_err(|reason| ConfigError::invalid(format!("{}.discountCode", field), reason))?;
+        Ok(code)
+    }
+
+    fn class(&self) -> DiscountClass {
+        if self.is_d12c {
+            DiscountClass::Order
+        } else {
+            DiscountClass::Product
+        }
+    }
+}
+
+impl CollectionMembership for input::InputCartLinesMerchandiseOnProductVariantProductInCollections {
+    fn collection_id(&self) -> &str {
+        &self.collection_id
+    }
+
+    fn is_member(&self) -> bool {
+        self.is_member
+    }
+}
+
+/// Check a `$app:tag_discounts.config` metafield value, as the function would read it.
+pub(crate) fn validate_config(value: &str) -> std::result::Result<(), String> {
+    Configuration::from_str(value).map(|_| ()).map_err(|err| err.to_string())
+}
+
+#[shopify_function_target(query_path = "src/run.graphql", schema_path = "schema.graphql")]
+fn run(input: input::ResponseData) -> Result<output::FunctionRunResult> {
+    evaluate(input, &mut Explanation::default())
+}
+
+/// The body of `run`. Each decision is recorded in `explanation` for the `explain` command.
+pub(crate) fn evaluate(input: input::ResponseData, explanation: &mut Explanation) -> Result<output::FunctionRunResult> {
+    let no_discount = output::FunctionRunResult {
+        discounts: vec![],
+        discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+    };
+
+    // 1. Parse the configuration from the discount node metafield.
+    let config = match input.discount_node.metafield {
+        Some(input::InputDiscountNodeMetafield { value }) => match Configuration::from_str(&value) {
+            Ok(config) => config,
+            Err(err) => {
+                err.log("no_discount");
+                explanation.note(|| format!("Configuration rejected, no discount: {}", err));
+                return Ok(no_discount);
+            }
+        },
+        None => {
+            explanation.note(|| "No configuration metafield, no discount.".to_string());
+            return Ok(no_discount);
+        }
+    };
+
+    // 2. Only tagged customers get a discount.
+    let customer_tags: Vec<&str> = match input.cart.buyer_identity.as_ref().and_then(|buyer| buyer.customer.as_ref()) {
+        Some(customer) => customer
+            .has_tags
+            .iter()
+            .filter(|response| response.has_tag)
+            .map(|response| response.tag.as_str())
+            .collect(),
+        None => vec![],
+    };
+    if customer_tags.is_empty() {
+        explanation.note(|| "The buyer has none of the configured tags, no discount.".to_string());
+        return Ok(no_discount);
+    }
+
+    // 3. Read the tag and schedule discounts. Entries with an unknown tag never match.
+    let shop = &input.shop;
+    let tag_discounts: Vec<(String, TagDiscount)> = config::parse_entries(
+        shop.tag_discounts.as_ref().map(|metafield| metafield.value.as_str()),
+        "tagDiscounts",
+        explanation,
+    );
+    let schedule_discounts: Vec<(String, ScheduleDiscount)> = config::parse_entries(
+        shop.schedule_discounts.as_ref().map(|metafield| metafield.value.as_str()),
+        "scheduleDiscounts",
+        explanation,
+    );
+    for tag in tag_discounts
+        .iter()
+        .map(|(_, entry)| &entry.tag)
+        .chain(schedule_discounts.iter().map(|(_, entry)| &entry.discount.tag))
+    {
+        if !config.customer_tags.contains(tag) {
+            explanation.note(|| format!("Tag {} is not listed in customerTags and never matches.", tag));
+        }
+    }
+    let today = ShopTime::new(&shop.local_time.date, 0);
+
+    // 4. Collect the discounts the buyer qualifies for today.
+    let mut offers: Vec<(DiscountCode, DiscountClass)> = vec![];
+    for (field, entry) in tag_discounts.iter() {
+        if !customer_tags.contains(&entry.tag.as_str()) {
+            continue;
+        }
+        if schedule_discounts.iter().any(|(_, scheduled)| scheduled.discount.tag == entry.tag) {
+            explanation.note(|| format!("{} only applies on its scheduled days.", field));
+            continue;
+        }
+        match entry.code(field) {
+            Ok(code) => offers.push((code, entry.class())),
+            Err(err) => {
+                err.log("skip_entry");
+                explanation.note(|| format!("Entry skipped: {}", err));
+            }
+        }
+    }
+    for (field, entry) in schedule_discounts.iter() {
+        if !customer_tags.contains(&entry.discount.tag.as_str()) {
+            continue;
+        }
+        let date = match PickedDate::parse(&entry.date) {
+            Some(date) => date,
+            None => {
+                let err = ConfigError::invalid(format!("{}.date", field), format!("{} is not an ISO date", entry.date));
+                err.log("skip_entry");
+                explanation.note(|| format!("Entry skipped: {}", err));
+                continue;
+            }
+        };
+        // Without a readable shop date no scheduled discount applies
+        if !today.is_some_and(|today| today.is_on(date)) {
+            explanation.note(|| format!("{} is scheduled for another day.", field));
+            continue;
+        }
+        match entry.discount.code(field) {
+            Ok(code) => offers.push((code, entry.discount.class())),
+            Err(err) => {
+                err.log("skip_entry");
+                explanation.note(|| format!("Entry skipped: {}", err));
+            }
+        }
+    }
+
+    // 5. The highest percentage wins, the first one listed on a tie.
+    let mut best: Option<(DiscountCode, DiscountClass)> = None;
+    for (offer, class) in offers {
+        explanation.note(|| format!("The buyer qualifies for {} at {}%.", offer.code, offer.percentage));
+        match &best {
+            Some((current, _)) if current.percentage >= offer.percentage => {}
+            _ => best = Some((offer, class)),
+        }
+    }
+    let (best, class) = match best {
+        Some((best, class)) if best.percentage > 0.0 => (best, class),
+        _ => {
+            explanation.note(|| "No tag discount applies.".to_string());
+            return Ok(no_discount);
+        }
+    };
+
+    // 6. Discount every product line, or for a product discount only those in the
+    //    configured collections.
+    let targets: Vec<output::Target> = input
+        .cart
+        .lines
+        .iter()
+        .filter(|line| match &line.merchandise {
+            input::InputCartLinesMerchandise::ProductVariant(variant) => {
+                class == DiscountClass::Order
+                    || config.collection_ids.is_empty()
+                    || collections::is_member_of_any(&variant.product.in_collections, &config.collection_ids)
+            }
+            _ => false,
+        })
+        .map(|line| {
+            output::Target::CartLine(output::CartLineTarget {
+                id: line.id.clone(),
+                quantity: None,
+            })
+        })
+        .collect();
+    if targets.is_empty() {
+        explanation.note(|| "No cart line can take the discount.".to_string());
+        return Ok(no_discount);
+    }
+    explanation.note(|| match class {
+        DiscountClass::Product => format!("Applies {} at {}% to {} lines.", best.code, best.percentage, targets.len()),
+        DiscountClass::Order => format!("Applies {} at {}% to the order, {} lines.", best.code, best.percentage, targets.len()),
+    });
+
+    Ok(output::FunctionRunResult {
+        discounts: vec![output::Discount {
+            message: Some(best.code),
+            targets,
+            value: output::Value::Percentage(output::Percentage {
+                value: Decimal(best.percentage),
+            }),
+        }],
+        discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shopify_function::{run_function_with_input, Result};
+    use crate::run::run::output;
+
+    const CONFIG: &str = r#"{"customerTags": ["VIP", "STAFF"]}"#;
+    const TAG_DISCOUNTS: &str = r#"[
+        {"tag": "VIP", "discountCode": "VIP10", "discountPercentage": "10", "isD12C": false},
+        {"tag": "STAFF", "discountCode": "STAFF20", "discountPercentage": "20", "isD12C": true}
+    ]"#;
+
+    fn line(id: u32, collection: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": format!("gid://shopify/CartLine/{}", id),
+            "merchandise": {
+                "__typename": "ProductVariant",
+                "id": format!("gid://shopify/ProductVariant/{}", id),
+                "product": {
+                    "inCollections": [{ "collectionId": collection, "isMember": true }]
+                }
+            }
+        })
+    }
+
+    // A cart on Monday June 3rd 2024 for a customer with `tags` (a guest for `None`).
+    fn input_with(config: &str, tags: Option<&[&str]>, tag_discounts: &str, schedule_discounts: Option<&str>) -> String {
+        let customer = tags.map(|tags| {
+            let responses: Vec<serde_json::Value> = ["VIP", "STAFF"]
+                .iter()
+                .map(|tag| serde_json::json!({ "tag": tag, "hasTag": tags.contains(tag) }))
+                .collect();
+            serde_json::json!({ "hasTags": responses })
+        });
+        serde_json::json!({
+            "discountNode": {
+                "metafield": { "value": config }
+            },
+            "shop": {
+                "localTime": { "date": "2024-06-03" },
+                "tagDiscounts": { "value": tag_discounts },
+                "scheduleDiscounts": schedule_discounts.map(|value| serde_json::json!({ "value": value }))
+            },
+            "cart": {
+                "buyerIdentity": { "customer": customer },
+                "lines": [line(1, "gid://shopify/Collection/1"), line(2, "gid://shopify/Collection/2")]
+            }
+        })
+        .to_string()
+    }
+
+    fn discount(code: &str, percentage: f64, lines: &[u32]) -> output::FunctionRunResult {
+        output::FunctionRunResult {
+            discounts: vec![output::Discount {
+                message: Some(code.to_string()),
+                targets: lines
+                    .iter()
+                    .map(|id| {
+                        output::Target::CartLine(output::CartLineTarget {
+                            id: format!("gid://shopify/CartLine/{}", id),
+                            quantity: None,
+                        })
+                    })
+                    .collect(),
+                value: output::Value::Percentage(output::Percentage {
+                    value: Decimal(percentage),
+                }),
+            }],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        }
+    }
+
+    fn no_discount() -> output::FunctionRunResult {
+        output::FunctionRunResult {
+            discounts: vec![],
+            discount_application_strategy: output::DiscountApplicationStrategy::FIRST,
+        }
+    }
+
+    #[test]
+    fn test_tagged_customer_gets_the_tag_discount() -> Result<()> {
+        let result = run_function_with_input(run, &input_with(CONFIG, Some(&["VIP"]), TAG_DISCOUNTS, None))?;
+        assert_eq!(result, discount("VIP10", 10.0, &[1, 2]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_guests_and_untagged_customers_get_nothing() -> Result<()> {
+        assert_eq!(run_function_with_input(run, &input_with(CONFIG, None, TAG_DISCOUNTS, None))?, no_discount());
+        assert_eq!(run_function_with_input(run, &input_with(CONFIG, Some(&[]), TAG_DISCOUNTS, None))?, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_highest_percentage_wins() -> Result<()> {
+        let result = run_function_with_input(run, &input_with(CONFIG, Some(&["VIP", "STAFF"]), TAG_DISCOUNTS, None))?;
+        assert_eq!(result, discount("STAFF20", 20.0, &[1, 2]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_collection_ids_limit_the_discount() -> Result<()> {
+        let config = r#"{"customerTags": ["VIP", "STAFF"], "collectionIds": ["gid://shopify/Collection/2"]}"#;
+        let result = run_function_with_input(run, &input_with(config, Some(&["VIP"]), TAG_DISCOUNTS, None))?;
+        assert_eq!(result, discount("VIP10", 10.0, &[2]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_d12c_discounts_apply_to_the_whole_order() -> Result<()> {
+        let config = r#"{"customerTags": ["VIP", "STAFF"], "collectionIds": ["gid://shopify/Collection/2"]}"#;
+        let result = run_function_with_input(run, &input_with(config, Some(&["VIP", "STAFF"]), TAG_DISCOUNTS, None))?;
+        assert_eq!(result, discount("STAFF20", 20.0, &[1, 2]));
+
+        // A lower order discount loses to a product discount
+        let tag_discounts = TAG_DISCOUNTS.replace(r#""STAFF20", "discountPercentage": "20""#, r#""STAFF05", "discountPercentage": "5""#);
+        let result = run_function_with_input(run, &input_with(config, Some(&["VIP", "STAFF"]), &tag_discounts, None))?;
+        assert_eq!(result, discount("VIP10", 10.0, &[2]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scheduled_tags_only_apply_on_their_days() -> Result<()> {
+        // June 3rd picked in UTC+2, and June 4th picked in UTC-5
+        let today = r#"[{"tag": "STAFF", "discountCode": "STAFF20", "discountPercentage": "20", "isD12C": true, "date": "2024-06-02T22:00:00.000Z"}]"#;
+        let tomorrow = r#"[{"tag": "STAFF", "discountCode": "STAFF20", "discountPercentage": "20", "isD12C": true, "date": "2024-06-04T05:00:00.000Z"}]"#;
+        let staff = Some(&["STAFF"][..]);
+        assert_eq!(
+            run_function_with_input(run, &input_with(CONFIG, staff, TAG_DISCOUNTS, Some(today)))?,
+            discount("STAFF20", 20.0, &[1, 2])
+        );
+        assert_eq!(run_function_with_input(run, &input_with(CONFIG, staff, TAG_DISCOUNTS, Some(tomorrow)))?, no_discount());
+        // Other tags are not affected by the STAFF schedule
+        assert_eq!(
+            run_function_with_input(run, &input_with(CONFIG, Some(&["VIP", "STAFF"]), TAG_DISCOUNTS, Some(tomorrow)))?,
+            discount("VIP10", 10.0, &[1, 2])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_entries_are_skipped() -> Result<()> {
+        let tag_discounts = r#"[
+            {"tag": "STAFF", "discountCode": "STAFF25", "discountPercentage": "20"},
+            {"tag": "STAFF", "discountCode": "STAFF15", "discountPercentage": 15},
+            {"tag": "VIP", "discountCode": "VIP10", "discountPercentage": "10"}
+        ]"#;
+        let result = run_function_with_input(run, &input_with(CONFIG, Some(&["VIP", "STAFF"]), tag_discounts, None))?;
+        assert_eq!(result, discount("VIP10", 10.0, &[1, 2]));
+        let result = run_function_with_input(run, &input_with(CONFIG, Some(&["VIP"]), "not json", None))?;
+        assert_eq!(result, no_discount());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert_eq!(validate_config(CONFIG), Ok(()));
+        assert!(validate_config(r#"{"customerTags": [""]}"#).unwrap_err().contains("customerTags[0]"));
+        assert!(validate_config(r#"{"collectionIds": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_explain_tag_discounts() {
+        let mut explanation = Explanation::enabled();
+        let input = serde_json::from_str(&input_with(CONFIG, Some(&["VIP"]), TAG_DISCOUNTS, None)).unwrap();
+        evaluate(input, &mut explanation).unwrap();
+        let notes = explanation.notes();
+        assert!(notes.contains(&"The buyer qualifies for VIP10 at 10%.".to_string()));
+        assert!(notes.contains(&"Applies VIP10 at 10% to 2 lines.".to_string()));
+    }
+}